@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, ResponseError};
+use sha2::{Digest, Sha256};
+use tracing::{error, info_span, warn, Instrument};
+
+use crate::config::{ApiKeyConfig, ModelSettings, Target};
+use crate::error::AppError;
+use crate::openai::OpenAiError;
+use crate::rate_limiter::RateLimiter;
+use crate::AppState;
+
+/// Rate limiter "provider" under which per-key budgets are registered.
+pub const KEY_LIMITER: &str = "_key";
+
+/// Paths reachable without a key, for probes and scrapers.
+const PUBLIC_PATHS: &[&str] = &["/health", "/health/providers", "/ready", "/metrics"];
+
+/// An authenticated caller and what it is allowed to use.
+#[derive(Debug)]
+pub struct ApiKey {
+    pub name: String,
+    providers: Vec<String>,
+    models: Vec<Target>,
+}
+
+impl ApiKey {
+    pub fn allows(&self, target: &Target) -> bool {
+        (self.providers.is_empty() && self.models.is_empty())
+            || self.providers.contains(&target.provider)
+            || self.models.contains(target)
+    }
+
+    pub fn check(&self, target: &Target) -> Result<(), AppError> {
+        if self.allows(target) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden {
+                key: self.name.clone(),
+                target: target.to_string(),
+            })
+        }
+    }
+}
+
+/// Configured keys, indexed by the SHA-256 of the secret so plain keys
+/// never need to be kept around.
+#[derive(Default)]
+pub struct ApiKeys {
+    by_hash: HashMap<String, Arc<ApiKey>>,
+}
+
+pub fn sha256_hex(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+impl ApiKeys {
+    /// Keys without a secret can only get here when invalid configs are
+    /// allowed; they are left out, as nobody could present them.
+    pub fn new(keys: &[ApiKeyConfig]) -> Self {
+        let by_hash = keys
+            .iter()
+            .filter_map(|k| {
+                let hash = match (&k.key, &k.key_sha256) {
+                    (Some(key), _) => sha256_hex(key),
+                    (None, Some(hash)) => hash.to_lowercase(),
+                    (None, None) => {
+                        error!(key = %k.name, "ignoring API key without key or key_sha256");
+                        return None;
+                    }
+                };
+                let key = ApiKey {
+                    name: k.name.clone(),
+                    providers: k.providers.clone(),
+                    models: k.models.iter().map(|m| Target::parse(m)).collect(),
+                };
+                Some((hash, Arc::new(key)))
+            })
+            .collect();
+        Self { by_hash }
+    }
+
+    /// Authentication is off when no keys are configured.
+    pub fn is_enabled(&self) -> bool {
+        !self.by_hash.is_empty()
+    }
+
+    pub fn find(&self, secret: &str) -> Option<Arc<ApiKey>> {
+        self.by_hash.get(&sha256_hex(secret)).cloned()
+    }
+}
+
+/// Registers the rps/rpm/concurrency budget of every key that has one.
+pub fn register_budgets(rate_limiter: &RateLimiter, keys: &[ApiKeyConfig]) {
+    for key in keys {
+        if key.rps.is_some() || key.rpm.is_some() || key.concurrent.is_some() {
+            rate_limiter.register(
+                KEY_LIMITER.into(),
+                key.name.clone(),
+                ModelSettings {
+                    rps: key.rps,
+                    rpm: key.rpm,
+                    concurrent: key.concurrent,
+                    ..Default::default()
+                },
+            );
+        }
+    }
+}
+
+/// Reads the key from `Authorization: Bearer <key>` or `X-API-Key`.
+fn presented_key(req: &ServiceRequest) -> Option<&str> {
+    let headers = req.headers();
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()))
+        .map(str::trim)
+}
+
+/// Middleware rejecting requests without a valid key with 401. The matched
+/// `Arc<ApiKey>` is stored in the request extensions for the handlers, and
+/// the rest of the request is logged under its name.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let keys = match req.app_data::<web::Data<Arc<AppState>>>() {
+        Some(state) if state.api_keys.is_enabled() => &state.api_keys,
+        _ => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+    if PUBLIC_PATHS.contains(&req.path()) {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    let Some(key) = presented_key(&req).and_then(|k| keys.find(k)) else {
+        warn!(method = %req.method(), path = req.path(), "rejected unauthenticated request");
+        let response = if req.path().starts_with("/v1/") {
+            OpenAiError::from(AppError::Unauthorized).error_response()
+        } else {
+            AppError::Unauthorized.error_response()
+        };
+        return Ok(req.into_response(response));
+    };
+
+    let span = info_span!("request", api_key = %key.name);
+    req.extensions_mut().insert(key);
+    Ok(next.call(req).instrument(span).await?.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{failing_claude_executor, mock_executor, test_state_with, valid_schema};
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    fn keys() -> Vec<ApiKeyConfig> {
+        toml::from_str::<crate::config::AuthConfig>(
+            r#"
+            [[keys]]
+            name = "admin"
+            key = "admin-secret"
+
+            [[keys]]
+            name = "ci"
+            # sha256 of "ci-secret"
+            key_sha256 = "f82da6e2b2e51c046a2eaf10964ef184b69c0aee9892f10a2a7b657477d407e6"
+            models = ["gemini/_auto"]
+            concurrent = 1
+            "#,
+        )
+        .unwrap()
+        .keys
+    }
+
+    macro_rules! auth_app {
+        () => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(test_state_with(mock_executor(), |state| {
+                        register_budgets(&state.rate_limiter, &keys());
+                        state.api_keys = ApiKeys::new(&keys());
+                    })))
+                    .wrap(from_fn(authenticate))
+                    .route("/health", web::get().to(crate::health))
+                    .route("/generate", web::post().to(crate::generate)),
+            )
+            .await
+        };
+    }
+
+    fn generate(provider: &str) -> test::TestRequest {
+        test::TestRequest::post().uri("/generate").set_json(json!({
+            "provider": provider,
+            "prompt": "hello",
+            "schema": valid_schema()
+        }))
+    }
+
+    #[actix_web::test]
+    async fn test_hashes_match() {
+        let keys = ApiKeys::new(&keys());
+        assert_eq!(keys.find("ci-secret").unwrap().name, "ci");
+        assert_eq!(keys.find("admin-secret").unwrap().name, "admin");
+        assert!(keys.find("nope").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_missing_key_is_unauthorized() {
+        let app = auth_app!();
+
+        let resp = test::call_service(&app, generate("claude").to_request()).await;
+        assert_eq!(resp.status(), 401);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "missing or invalid API key");
+
+        let req = generate("claude")
+            .insert_header(("Authorization", "Bearer wrong"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_key_restricted_to_models() {
+        let app = auth_app!();
+
+        let req = generate("claude")
+            .insert_header(("X-API-Key", "ci-secret"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+        let req = generate("claude")
+            .insert_header(("Authorization", "Bearer admin-secret"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_auto_provider_skips_targets_the_key_may_not_use() {
+        let state = test_state_with(mock_executor(), |state| {
+            register_budgets(&state.rate_limiter, &keys());
+            state.api_keys = ApiKeys::new(&keys());
+            let current = Arc::make_mut(state.settings.get_mut().unwrap());
+            let config: crate::config::Config = toml::from_str(
+                r#"
+                [server]
+
+                [[routing.rules]]
+                name = "fast"
+                tags = ["fast"]
+                targets = ["claude/sonnet", "gemini"]
+
+                [[routing.rules]]
+                name = "claude-only"
+                targets = ["claude"]
+                "#,
+            )
+            .unwrap();
+            current.routing = config.routing.rules;
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(from_fn(authenticate))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let auto = |tags: Value| {
+            test::TestRequest::post()
+                .uri("/generate")
+                .insert_header(("X-API-Key", "ci-secret"))
+                .set_json(json!({
+                    "provider": "auto",
+                    "tags": tags,
+                    "prompt": "hello",
+                    "schema": valid_schema()
+                }))
+                .to_request()
+        };
+
+        let body: Value = test::call_and_read_body_json(&app, auto(json!(["fast"]))).await;
+        assert_eq!(body["served_by"], "gemini/_auto");
+
+        let resp = test::call_service(&app, auto(json!([]))).await;
+        assert_eq!(resp.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_key_budget_is_charged_once_per_request() {
+        let keys: Vec<ApiKeyConfig> = toml::from_str::<crate::config::AuthConfig>(
+            r#"
+            [[keys]]
+            name = "limited"
+            key = "limited-secret"
+            rpm = 1
+            "#,
+        )
+        .unwrap()
+        .keys;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state_with(
+                    failing_claude_executor(),
+                    |state| {
+                        register_budgets(&state.rate_limiter, &keys);
+                        state.api_keys = ApiKeys::new(&keys);
+                    },
+                )))
+                .wrap(from_fn(authenticate))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let request = || {
+            test::TestRequest::post()
+                .uri("/generate")
+                .insert_header(("X-API-Key", "limited-secret"))
+                .set_json(json!({
+                    "route": "fallback",
+                    "prompt": "hello",
+                    "schema": valid_schema()
+                }))
+                .to_request()
+        };
+
+        // Falling back from claude to gemini does not charge the key again
+        assert_eq!(test::call_service(&app, request()).await.status(), 200);
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), 429);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "key_rate_limited");
+    }
+
+    #[actix_web::test]
+    async fn test_ensemble_charges_the_key_budget_once() {
+        let keys: Vec<ApiKeyConfig> = toml::from_str::<crate::config::AuthConfig>(
+            r#"
+            [[keys]]
+            name = "limited"
+            key = "limited-secret"
+            rpm = 1
+            concurrent = 1
+            "#,
+        )
+        .unwrap()
+        .keys;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state_with(mock_executor(), |state| {
+                    register_budgets(&state.rate_limiter, &keys);
+                    state.api_keys = ApiKeys::new(&keys);
+                })))
+                .wrap(from_fn(authenticate))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let request = || {
+            test::TestRequest::post()
+                .uri("/generate")
+                .insert_header(("X-API-Key", "limited-secret"))
+                .set_json(json!({
+                    "prompt": "hello",
+                    "schema": valid_schema(),
+                    "ensemble": {"targets": ["claude/sonnet", "gemini"]}
+                }))
+                .to_request()
+        };
+
+        let body: Value = test::call_and_read_body_json(&app, request()).await;
+        let results = body["ensemble"]["results"].as_array().unwrap();
+        assert!(results.iter().all(|r| r["status"] == "ok"), "{body}");
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), 429);
+    }
+
+    #[actix_web::test]
+    async fn test_key_budget() {
+        let limiter = RateLimiter::new();
+        register_budgets(&limiter, &keys());
+
+        let _guard = limiter.try_acquire(KEY_LIMITER, "ci").unwrap();
+        assert!(limiter.try_acquire(KEY_LIMITER, "ci").is_err());
+        // Keys without a budget are not limited
+        assert!(limiter.try_acquire(KEY_LIMITER, "admin").is_ok());
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{error, info};
+
+use crate::auth::ApiKey;
+use crate::cache::CacheControl;
+use crate::ensemble::Ensemble;
+use crate::error::AppError;
+use crate::jobs::JobError;
+use crate::provider::Message;
+use crate::rate_limiter::Wait;
+use crate::schema;
+use crate::{respond, AppState, GenerateRequest, GenerateResponse};
+
+/// Upper bound for a batch's `concurrency`.
+const MAX_CONCURRENCY: usize = 64;
+
+/// Upper bound for the number of items in one batch.
+const MAX_ITEMS: usize = 10_000;
+
+/// Batches carry thousands of prompts, more than the default JSON limit.
+const MAX_BODY_BYTES: usize = 32 * 1024 * 1024;
+
+fn default_concurrency() -> usize {
+    4
+}
+
+/// Many prompts sharing one schema. `provider`, `model` and `route` are the
+/// defaults for items that do not pick their own target.
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    #[serde(default)]
+    provider: Option<String>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    route: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    ensemble: Option<Ensemble>,
+    #[serde(default)]
+    schema: Option<Value>,
+    #[serde(default)]
+    max_repair_attempts: Option<u32>,
+    #[serde(default)]
+    include_meta: bool,
+    /// How many items of this batch may run or wait for the rate limiter at
+    /// once.
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    items: Vec<BatchItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchItem {
+    /// Echoed back on the item's result line.
+    #[serde(default)]
+    id: Option<Value>,
+    /// With `provider` or `route` the item ignores the batch's target, tags
+    /// and ensemble; `model` alone only overrides the batch's model.
+    #[serde(default)]
+    provider: Option<String>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    route: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    prompt: Option<String>,
+    #[serde(default)]
+    messages: Vec<Message>,
+}
+
+impl BatchRequest {
+    fn validate(&self) -> Result<(), AppError> {
+        if self.items.is_empty() {
+            return Err(AppError::InvalidRequest(
+                "\"items\" must not be empty".into(),
+            ));
+        }
+        if self.items.len() > MAX_ITEMS {
+            return Err(AppError::InvalidRequest(format!(
+                "\"items\" must not hold more than {MAX_ITEMS} entries"
+            )));
+        }
+        if !(1..=MAX_CONCURRENCY).contains(&self.concurrency) {
+            return Err(AppError::InvalidRequest(format!(
+                "\"concurrency\" must be between 1 and {MAX_CONCURRENCY}"
+            )));
+        }
+        match &self.schema {
+            Some(schema) => schema::validate_structured_schema(schema),
+            None => Ok(()),
+        }
+    }
+
+    /// The `/generate` request each item stands for.
+    fn requests(self) -> impl Iterator<Item = (Option<Value>, GenerateRequest)> {
+        let BatchRequest {
+            provider,
+            model,
+            route,
+            tags,
+            ensemble,
+            schema,
+            max_repair_attempts,
+            include_meta,
+            items,
+            ..
+        } = self;
+        items.into_iter().map(move |item| {
+            let (provider, model, route, tags, ensemble) =
+                if item.provider.is_some() || item.route.is_some() {
+                    (item.provider, item.model, item.route, item.tags, None)
+                } else {
+                    (
+                        provider.clone(),
+                        item.model.or(model.clone()),
+                        route.clone(),
+                        tags.clone(),
+                        ensemble.clone(),
+                    )
+                };
+            let req = GenerateRequest {
+                provider,
+                model,
+                route,
+                tags,
+                ensemble,
+                prompt: item.prompt,
+                messages: item.messages,
+                schema: schema.clone(),
+                max_repair_attempts,
+                include_meta,
+            };
+            (item.id, req)
+        })
+    }
+}
+
+/// One line of the response: an item's result, or its error in the shape
+/// failed jobs use.
+#[derive(Debug, Serialize)]
+struct BatchLine {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
+    #[serde(flatten)]
+    result: LineResult,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum LineResult {
+    Ok(GenerateResponse),
+    Err { error: JobError },
+}
+
+impl BatchLine {
+    fn new(index: usize, id: Option<Value>, result: Result<GenerateResponse, AppError>) -> Self {
+        Self {
+            index,
+            id,
+            result: match result {
+                Ok(response) => LineResult::Ok(response),
+                Err(e) => LineResult::Err { error: (&e).into() },
+            },
+        }
+    }
+}
+
+async fn run_item(
+    state: Arc<AppState>,
+    caller: Option<Arc<ApiKey>>,
+    control: CacheControl,
+    index: usize,
+    id: Option<Value>,
+    req: GenerateRequest,
+) -> BatchLine {
+    let result = match req.validate() {
+        // Items queue for the rate limiter for as long as it takes; the
+        // batch's concurrency caps how many wait at once
+        Ok(()) => respond(&state, caller.as_deref(), &req, control, Wait::Unbounded).await,
+        Err(e) => Err(e),
+    };
+    BatchLine::new(index, id, result)
+}
+
+/// `POST /batch`: runs every item like a `/generate` request, at most
+/// `concurrency` at a time, and streams one NDJSON line per item as it
+/// completes. Items that fail only fail their own line.
+///
+/// Disconnecting cancels the items that have not finished yet.
+pub async fn batch(
+    state: web::Data<Arc<AppState>>,
+    caller: Option<web::ReqData<Arc<ApiKey>>>,
+    http_req: HttpRequest,
+    req: web::Json<BatchRequest>,
+) -> Result<HttpResponse, AppError> {
+    req.validate()?;
+
+    let req = req.into_inner();
+    let concurrency = req.concurrency;
+    let total = req.items.len();
+    let caller = caller.map(web::ReqData::into_inner);
+    let control = CacheControl::from_request(&http_req);
+    let state = state.get_ref().clone();
+    info!(items = total, concurrency, "batch started");
+
+    let (tx, rx) = mpsc::unbounded_channel::<Bytes>();
+    tokio::spawn(async move {
+        let mut pending = req.requests().enumerate();
+        let mut running = JoinSet::new();
+        // Index and id of every running item, to report one that panicked
+        let mut items = HashMap::new();
+        loop {
+            while running.len() < concurrency {
+                let Some((index, (id, item))) = pending.next() else {
+                    break;
+                };
+                let task = running.spawn(run_item(
+                    state.clone(),
+                    caller.clone(),
+                    control,
+                    index,
+                    id.clone(),
+                    item,
+                ));
+                items.insert(task.id(), (index, id));
+            }
+
+            let joined = tokio::select! {
+                joined = running.join_next_with_id() => joined,
+                // Aborting the items kills their child processes.
+                _ = tx.closed() => {
+                    info!(items = total, "batch cancelled by client");
+                    running.abort_all();
+                    return;
+                }
+            };
+            let line = match joined {
+                Some(Ok((task, line))) => {
+                    items.remove(&task);
+                    line
+                }
+                Some(Err(e)) => {
+                    let (index, id) = items.remove(&e.id()).expect("spawned items are tracked");
+                    error!(index, error = %e, "batch item panicked");
+                    BatchLine::new(index, id, Err(AppError::Internal("item panicked".into())))
+                }
+                None => break,
+            };
+            let mut bytes = serde_json::to_vec(&line).unwrap_or_default();
+            bytes.push(b'\n');
+            // The client went away: dropping `running` aborts the rest
+            if tx.send(Bytes::from(bytes)).is_err() {
+                info!(items = total, "batch cancelled by client");
+                return;
+            }
+        }
+        info!(items = total, "batch finished");
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(UnboundedReceiverStream::new(rx).map(Ok::<_, Infallible>)))
+}
+
+/// Raises the body limit for `/batch`.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().limit(MAX_BODY_BYTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelSettings;
+    use crate::provider::executor::{CommandOutput, MockExecutor};
+    use crate::tests::{
+        mock_executor, test_state, test_state_with, valid_schema, RaceExecutor, SlowExecutor,
+    };
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    async fn post_batch(state: Arc<AppState>, body: Value) -> (u16, Vec<Value>) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/batch", web::post().to(batch)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/batch")
+            .set_json(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let status = resp.status().as_u16();
+        let body = test::read_body(resp).await;
+        let mut lines: Vec<Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        lines.sort_by_key(|line| line["index"].as_u64());
+        (status, lines)
+    }
+
+    #[actix_web::test]
+    async fn test_batch_reports_each_item() {
+        let (status, lines) = post_batch(
+            test_state(mock_executor()),
+            json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "schema": valid_schema(),
+                "items": [
+                    {"id": "a", "prompt": "hello"},
+                    {"id": "b", "prompt": "hello", "provider": "nope"},
+                    {"id": 3, "messages": [{"role": "assistant", "content": "hi"}]},
+                    {"prompt": "hello", "model": "opus"}
+                ]
+            }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(lines.len(), 4);
+
+        assert_eq!(lines[0]["id"], "a");
+        assert_eq!(lines[0]["output"], json!({"message": "hello"}));
+        assert_eq!(lines[1]["id"], "b");
+        assert_eq!(lines[1]["error"]["code"], "provider_not_found");
+        assert_eq!(lines[2]["id"], 3);
+        assert_eq!(lines[2]["error"]["code"], "invalid_request");
+        assert!(lines[3].get("id").is_none());
+        assert_eq!(lines[3]["error"]["code"], "model_not_found");
+    }
+
+    #[actix_web::test]
+    async fn test_batch_waits_for_the_rate_limiter() {
+        let executor = Arc::new(SlowExecutor {
+            runs: Default::default(),
+        });
+        // One at a time and no queueing: separate /generate calls would be
+        // rate limited
+        let state = test_state_with(executor.clone(), |state| {
+            state.rate_limiter.register(
+                "claude".into(),
+                "sonnet".into(),
+                ModelSettings {
+                    concurrent: Some(1),
+                    ..Default::default()
+                },
+            );
+        });
+        let items: Vec<Value> = (0..4)
+            .map(|i| json!({"prompt": format!("record {i}")}))
+            .collect();
+        let (status, lines) = post_batch(
+            state,
+            json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "schema": valid_schema(),
+                "concurrency": 3,
+                "items": items
+            }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert!(lines.iter().all(|line| line.get("output").is_some()));
+        assert_eq!(executor.runs.load(std::sync::atomic::Ordering::SeqCst), 4);
+    }
+
+    #[actix_web::test]
+    async fn test_panicked_item_gets_an_error_line() {
+        let mut mock = MockExecutor::new();
+        mock.expect_run().returning(|_, _, stdin, _| {
+            assert_ne!(stdin, "boom", "executor bug");
+            Ok(CommandOutput {
+                stdout: r#"{"structured_output": {"message": "hello"}}"#.into(),
+                stderr: String::new(),
+            })
+        });
+        let (status, lines) = post_batch(
+            test_state(Arc::new(mock)),
+            json!({
+                "provider": "claude",
+                "schema": valid_schema(),
+                "items": [
+                    {"id": "a", "prompt": "hello"},
+                    {"id": "b", "prompt": "boom"}
+                ]
+            }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["output"], json!({"message": "hello"}));
+        assert_eq!(lines[1]["index"], 1);
+        assert_eq!(lines[1]["id"], "b");
+        assert_eq!(lines[1]["error"]["code"], "internal");
+    }
+
+    #[actix_web::test]
+    async fn test_disconnect_cancels_running_items() {
+        let executor = Arc::new(RaceExecutor::new(std::time::Duration::from_secs(60)));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state(executor.clone())))
+                .route("/batch", web::post().to(batch)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/batch")
+            .set_json(json!({
+                "provider": "claude",
+                "schema": valid_schema(),
+                "items": [{"prompt": "hello"}]
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!executor
+            .claude_cancelled
+            .load(std::sync::atomic::Ordering::SeqCst));
+
+        drop(resp);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(executor
+            .claude_cancelled
+            .load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_batch_is_rejected() {
+        let too_many = vec![json!({"prompt": "hi"}); MAX_ITEMS + 1];
+        for body in [
+            json!({"provider": "claude", "items": []}),
+            json!({"provider": "claude", "items": too_many}),
+            json!({"provider": "claude", "concurrency": 0, "items": [{"prompt": "hi"}]}),
+            json!({"provider": "claude", "schema": {"type": "string"}, "items": [{"prompt": "hi"}]}),
+        ] {
+            let (status, _) = post_batch(test_state(mock_executor()), body).await;
+            assert_eq!(status, 400);
+        }
+    }
+}
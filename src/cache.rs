@@ -0,0 +1,415 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::auth::sha256_hex;
+use crate::config::{CacheBackendKind, CacheConfig, Target};
+use crate::error::AppError;
+use crate::provider::Message;
+
+/// A validated structured output and the target that produced it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    served_by: String,
+    output: Value,
+    stored_at: u64,
+}
+
+#[async_trait]
+trait Backend: Send + Sync {
+    async fn get(&self, key: &str) -> Option<Entry>;
+    async fn put(&self, key: &str, entry: Entry);
+    async fn remove(&self, key: &str);
+}
+
+struct MemoryBackend {
+    entries: Mutex<LruCache<String, Entry>>,
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn get(&self, key: &str) -> Option<Entry> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    async fn put(&self, key: &str, entry: Entry) {
+        self.entries.lock().unwrap().put(key.to_string(), entry);
+    }
+
+    async fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().pop(key);
+    }
+}
+
+/// One JSON file per entry, named after the key. Storing an entry beyond
+/// the capacity removes the file stored longest ago.
+struct DiskBackend {
+    dir: PathBuf,
+    /// Keys of the files in `dir`, in the order they were stored.
+    index: Mutex<LruCache<String, ()>>,
+}
+
+impl DiskBackend {
+    /// Indexes the files already in `dir`, removing leftovers of interrupted
+    /// writes, files older than `ttl` and the oldest files beyond `capacity`.
+    fn open(dir: PathBuf, capacity: NonZeroUsize, ttl: Duration) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if name.ends_with(".json.tmp") {
+                let _ = std::fs::remove_file(&path);
+            } else if let Some(key) = name.strip_suffix(".json") {
+                files.push((std::fs::metadata(&path)?.modified()?, key.to_string()));
+            }
+        }
+        files.sort();
+
+        let backend = Self {
+            dir,
+            index: Mutex::new(LruCache::new(capacity)),
+        };
+        let now = SystemTime::now();
+        for (modified, key) in files {
+            let stale = if now.duration_since(modified).unwrap_or_default() >= ttl {
+                Some(key)
+            } else {
+                backend.track(&key)
+            };
+            if let Some(key) = stale {
+                let _ = std::fs::remove_file(backend.path(&key));
+            }
+        }
+        Ok(backend)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    /// Records `key` as the newest entry, returning the key whose file has to
+    /// go to make room for it.
+    fn track(&self, key: &str) -> Option<String> {
+        match self.index.lock().unwrap().push(key.to_string(), ()) {
+            Some((evicted, ())) if evicted != key => Some(evicted),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for DiskBackend {
+    async fn get(&self, key: &str) -> Option<Entry> {
+        let content = tokio::fs::read(self.path(key)).await.ok()?;
+        serde_json::from_slice(&content).ok()
+    }
+
+    async fn put(&self, key: &str, entry: Entry) {
+        let Ok(content) = serde_json::to_vec(&entry) else {
+            return;
+        };
+        // Write then rename, so readers never see a partial file
+        let tmp = self.dir.join(format!("{key}.json.tmp"));
+        let result = async {
+            tokio::fs::write(&tmp, content).await?;
+            tokio::fs::rename(&tmp, self.path(key)).await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(dir = %self.dir.display(), error = %e, "failed to write cache entry");
+            return;
+        }
+        if let Some(evicted) = self.track(key) {
+            let _ = tokio::fs::remove_file(self.path(&evicted)).await;
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        self.index.lock().unwrap().pop(key);
+        let _ = tokio::fs::remove_file(self.path(key)).await;
+    }
+}
+
+/// Cache of validated structured outputs, keyed on target, conversation and
+/// schema.
+pub struct ResponseCache {
+    backend: Box<dyn Backend>,
+    ttl: Duration,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Result<Self, AppError> {
+        let capacity = NonZeroUsize::new(config.max_entries)
+            .ok_or_else(|| AppError::ConfigLoad("cache: max_entries must be positive".into()))?;
+        let ttl = Duration::from_secs(config.ttl_secs);
+        let backend: Box<dyn Backend> = match config.backend {
+            CacheBackendKind::Memory => Box::new(MemoryBackend {
+                entries: Mutex::new(LruCache::new(capacity)),
+            }),
+            CacheBackendKind::Disk => {
+                // Never fall back to the working directory
+                let dir = config.dir.as_deref().map(PathBuf::from).ok_or_else(|| {
+                    AppError::ConfigLoad("cache: dir is required for the disk backend".into())
+                })?;
+                let backend = DiskBackend::open(dir.clone(), capacity, ttl).map_err(|e| {
+                    AppError::ConfigLoad(format!("cache: failed to open {}: {e}", dir.display()))
+                })?;
+                Box::new(backend)
+            }
+        };
+        Ok(Self { backend, ttl })
+    }
+
+    fn key(target: &Target, messages: &[Message], schema: &Value) -> String {
+        // serde_json keeps object keys sorted, so this serialization is
+        // canonical: schemas differing only in key order share an entry.
+        // Messages keep their roles, unlike the rendered transcript.
+        let material = serde_json::to_string(&(target.to_string(), messages, schema))
+            .expect("serializing JSON values cannot fail");
+        sha256_hex(&material)
+    }
+
+    /// The first unexpired entry for any of `targets`, in order.
+    pub async fn get(
+        &self,
+        targets: &[Target],
+        messages: &[Message],
+        schema: &Value,
+    ) -> Option<(Target, Value)> {
+        for target in targets {
+            let key = Self::key(target, messages, schema);
+            let Some(entry) = self.backend.get(&key).await else {
+                continue;
+            };
+            if unix_now().saturating_sub(entry.stored_at) >= self.ttl.as_secs() {
+                self.backend.remove(&key).await;
+                continue;
+            }
+            return Some((Target::parse(&entry.served_by), entry.output));
+        }
+        None
+    }
+
+    pub async fn put(&self, target: &Target, messages: &[Message], schema: &Value, output: &Value) {
+        let entry = Entry {
+            served_by: target.to_string(),
+            output: output.clone(),
+            stored_at: unix_now(),
+        };
+        self.backend
+            .put(&Self::key(target, messages, schema), entry)
+            .await;
+    }
+}
+
+/// Per-request cache behaviour from the `Cache-Control` header: `no-cache`
+/// skips the lookup but still stores the fresh result, `no-store` skips both.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheControl {
+    pub lookup: bool,
+    pub store: bool,
+}
+
+impl CacheControl {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let directives: Vec<String> = req
+            .headers()
+            .get_all(header::CACHE_CONTROL)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|d| d.trim().to_ascii_lowercase())
+            .collect();
+        let no_store = directives.iter().any(|d| d == "no-store");
+        let no_cache = no_store || directives.iter().any(|d| d == "no-cache");
+        Self {
+            lookup: !no_cache,
+            store: !no_store,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{transcript, Role};
+    use crate::tests::{mock_executor, test_state_with, valid_schema};
+    use actix_web::{test, web, App};
+    use serde_json::json;
+
+    fn user(prompt: &str) -> [Message; 1] {
+        [Message::user(prompt)]
+    }
+
+    fn config(toml_str: &str) -> CacheConfig {
+        toml::from_str(toml_str).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_key_ignores_schema_key_order() {
+        let target = Target::parse("claude/sonnet");
+        let a: Value = serde_json::from_str(r#"{"type": "object", "properties": {}}"#).unwrap();
+        let b: Value = serde_json::from_str(r#"{"properties": {}, "type": "object"}"#).unwrap();
+        assert_eq!(
+            ResponseCache::key(&target, &user("p"), &a),
+            ResponseCache::key(&target, &user("p"), &b)
+        );
+        assert_ne!(
+            ResponseCache::key(&target, &user("p"), &a),
+            ResponseCache::key(&Target::parse("claude"), &user("p"), &a)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_key_includes_roles() {
+        let target = Target::parse("claude");
+        let conversation = [
+            Message {
+                role: Role::System,
+                content: "Be brief.".into(),
+            },
+            Message::user("Hi"),
+        ];
+        // The same text as the transcript the CLI would be given
+        let flattened = transcript(&conversation);
+        assert_ne!(
+            ResponseCache::key(&target, &conversation, &json!({})),
+            ResponseCache::key(&target, &user(&flattened), &json!({}))
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_expired_entries_are_misses() {
+        let cache = ResponseCache::new(&config("ttl_secs = 0")).unwrap();
+        let target = Target::parse("claude/sonnet");
+        cache
+            .put(&target, &user("p"), &json!({}), &json!({"a": 1}))
+            .await;
+        assert!(cache.get(&[target], &user("p"), &json!({})).await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_disk_backend_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&format!(
+            "backend = \"disk\"\ndir = {:?}",
+            dir.path().to_str().unwrap()
+        ));
+        let target = Target::parse("gemini");
+
+        ResponseCache::new(&config)
+            .unwrap()
+            .put(&target, &user("p"), &json!({}), &json!({"a": 1}))
+            .await;
+
+        let reopened = ResponseCache::new(&config).unwrap();
+        let (served_by, output) = reopened
+            .get(
+                &[Target::parse("claude/sonnet"), target.clone()],
+                &user("p"),
+                &json!({}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(served_by, target);
+        assert_eq!(output, json!({"a": 1}));
+    }
+
+    #[actix_web::test]
+    async fn test_disk_backend_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = || std::fs::read_dir(dir.path()).unwrap().count();
+        let disk = |extra: &str| {
+            config(&format!(
+                "backend = \"disk\"\nmax_entries = 2\ndir = {:?}\n{extra}",
+                dir.path().to_str().unwrap()
+            ))
+        };
+        let target = Target::parse("gemini");
+        let schema = json!({});
+
+        let cache = ResponseCache::new(&disk("")).unwrap();
+        for prompt in ["a", "b", "c"] {
+            cache.put(&target, &user(prompt), &schema, &json!({})).await;
+            // Reopening orders files by modification time
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(files(), 2);
+        let targets = [target.clone()];
+        assert!(cache.get(&targets, &user("a"), &schema).await.is_none());
+        assert!(cache.get(&targets, &user("c"), &schema).await.is_some());
+
+        // Reopening picks up the stored files in order
+        let cache = ResponseCache::new(&disk("")).unwrap();
+        cache.put(&target, &user("d"), &schema, &json!({})).await;
+        assert_eq!(files(), 2);
+        assert!(cache.get(&targets, &user("b"), &schema).await.is_none());
+
+        // Expired files are removed on open
+        ResponseCache::new(&disk("ttl_secs = 0")).unwrap();
+        assert_eq!(files(), 0);
+
+        let no_dir = ResponseCache::new(&config("backend = \"disk\""));
+        assert!(no_dir.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_generate_serves_repeated_requests_from_cache() {
+        let state = test_state_with(mock_executor(), |state| {
+            state.cache = Some(ResponseCache::new(&config("")).unwrap());
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let body = json!({
+            "provider": "claude",
+            "model": "sonnet",
+            "prompt": "hello",
+            "schema": valid_schema()
+        });
+
+        let req = test::TestRequest::post()
+            .uri("/generate")
+            .set_json(&body)
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["cached"], false);
+
+        let req = test::TestRequest::post()
+            .uri("/generate")
+            .set_json(&body)
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["cached"], true);
+        assert_eq!(resp["output"], json!({"message": "hello"}));
+
+        let req = test::TestRequest::post()
+            .uri("/generate")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .set_json(&body)
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["cached"], false);
+    }
+}
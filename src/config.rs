@@ -1,22 +1,50 @@
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::Path;
+use std::time::Duration;
 
 use crate::error::AppError;
+use crate::provider::BUILTIN_PROVIDERS;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub server: ServerConfig,
     #[serde(default)]
     pub providers: Vec<ProviderConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Rules for `provider = "auto"` requests.
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Response cache; disabled without a `[cache]` table.
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    #[serde(default)]
+    pub health: HealthConfig,
 }
 
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
     #[serde(default = "default_host")]
     pub host: String,
     #[serde(default = "default_port")]
     pub port: u16,
+    /// How long shutdown waits for in-flight executions to finish.
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    /// How long CLI processes get between SIGTERM and SIGKILL once the
+    /// drain period is over.
+    #[serde(default = "default_kill_grace_secs")]
+    pub kill_grace_secs: u64,
+    /// How often the config file is checked for changes; `0` reloads only
+    /// on SIGHUP.
+    #[serde(default = "default_config_poll_secs")]
+    pub config_poll_secs: u64,
 }
 
 fn default_host() -> String {
@@ -27,9 +55,51 @@ fn default_port() -> u16 {
     3000
 }
 
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_kill_grace_secs() -> u64 {
+    10
+}
+
+fn default_config_poll_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    /// One of the providers compiled into llm-mux, picked by `name`.
+    #[default]
+    Builtin,
+    /// A CLI described by the provider's `[providers.cli]` table.
+    Cli,
+    /// An OpenAI-compatible Chat Completions API, described by the
+    /// provider's `[providers.http]` table.
+    Openai,
+    /// An Anthropic Messages API, described by `[providers.http]`.
+    Anthropic,
+    /// An Ollama server's native chat API, described by `[providers.http]`.
+    Ollama,
+}
+
+impl ProviderKind {
+    pub fn is_http(self) -> bool {
+        matches!(self, Self::Openai | Self::Anthropic | Self::Ollama)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ProviderConfig {
     pub name: String,
+    #[serde(default)]
+    pub kind: ProviderKind,
+    #[serde(default)]
+    pub cli: Option<CliProviderConfig>,
+    #[serde(default)]
+    pub http: Option<HttpProviderConfig>,
     #[serde(default = "default_true")]
     pub supports_auto_model: bool,
     #[serde(default)]
@@ -41,6 +111,12 @@ pub struct ProviderConfig {
     #[serde(default)]
     pub timeout_secs: Option<u64>,
     #[serde(default)]
+    pub max_queue_wait_secs: Option<u64>,
+    #[serde(default)]
+    pub max_queue_depth: Option<u32>,
+    #[serde(default)]
+    pub max_repair_attempts: Option<u32>,
+    #[serde(default)]
     pub models: Vec<ModelConfig>,
 }
 
@@ -48,7 +124,84 @@ fn default_true() -> bool {
     true
 }
 
+/// How a `kind = "cli"` provider is invoked and how its output is read.
+///
+/// Argument templates may contain `{model}`, `{schema}` (compact JSON),
+/// `{schema_file}` (path to a temp file holding the schema) and `{prompt}`.
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CliProviderConfig {
+    pub program: String,
+    /// Always passed.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Passed only when a model is requested.
+    #[serde(default)]
+    pub model_args: Vec<String>,
+    /// Passed only for structured requests.
+    #[serde(default)]
+    pub schema_args: Vec<String>,
+    #[serde(default)]
+    pub prompt_delivery: PromptDelivery,
+    /// Append a "respond with JSON matching this schema" instruction to the
+    /// prompt, for CLIs without native structured output.
+    #[serde(default)]
+    pub append_schema_to_prompt: bool,
+    #[serde(default)]
+    pub output: OutputFormat,
+    /// JSON pointer to the structured output inside the parsed stdout.
+    #[serde(default)]
+    pub output_pointer: Option<String>,
+    /// JSON pointer to the reply text in free-text mode. Without it the
+    /// trimmed stdout is the reply.
+    #[serde(default)]
+    pub text_pointer: Option<String>,
+}
+
+/// Where a `kind = "openai"`, `"anthropic"` or `"ollama"` provider sends
+/// its requests.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpProviderConfig {
+    /// API root including the version, e.g. `http://localhost:8080/v1`.
+    /// Defaults to the vendor's public API, or `http://localhost:11434` for
+    /// Ollama.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Environment variable holding the API key. Without it requests are
+    /// sent unauthenticated, as local servers expect.
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    /// Model sent when a request leaves model selection to the provider.
+    #[serde(default)]
+    pub default_model: Option<String>,
+    /// Cap on generated tokens. Only sent to OpenAI-compatible and Ollama
+    /// APIs when set; Anthropic requests default to 4096.
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptDelivery {
+    #[default]
+    Stdin,
+    /// Substituted for `{prompt}`, or appended as the last argument.
+    Arg,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// The whole stdout is JSON.
+    #[default]
+    Json,
+    /// JSON inside a ```json fenced block, falling back to the whole stdout.
+    Fenced,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ModelConfig {
     pub name: String,
     #[serde(default)]
@@ -59,22 +212,430 @@ pub struct ModelConfig {
     pub concurrent: Option<u32>,
     #[serde(default)]
     pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub max_queue_wait_secs: Option<u64>,
+    #[serde(default)]
+    pub max_queue_depth: Option<u32>,
 }
 
-#[derive(Debug, Clone, Default)]
+/// A named, ordered list of `provider/model` targets. Requests sent to a
+/// route fall back to the next target when one fails.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub name: String,
+    pub targets: Vec<String>,
+    /// Once a target has run this long without an answer, the next target
+    /// is started as well and the first valid output wins. Off by default.
+    #[serde(default)]
+    pub hedge_after_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+}
+
+/// Where `provider = "auto"` requests go. The first rule whose conditions
+/// all hold picks the targets, which are then tried in order like a route's.
+/// A rule without conditions matches every request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    pub name: String,
+    /// Tags the request must all carry, e.g. `fast` or `cheap`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Bounds on the prompt length in characters, counting every message.
+    #[serde(default)]
+    pub min_prompt_chars: Option<usize>,
+    #[serde(default)]
+    pub max_prompt_chars: Option<usize>,
+    /// `true` matches only structured requests, `false` only free text.
+    #[serde(default)]
+    pub structured: Option<bool>,
+    /// Bounds on the schema's total property count and nesting depth;
+    /// free-text requests count as zero.
+    #[serde(default)]
+    pub max_schema_properties: Option<usize>,
+    #[serde(default)]
+    pub max_schema_depth: Option<usize>,
+    /// Skips the rule unless a target has at least this share of its rate
+    /// limits free, from 0.0 to 1.0.
+    #[serde(default)]
+    pub min_headroom: Option<f64>,
+    pub targets: Vec<String>,
+}
+
+/// API keys. Authentication is enabled as soon as at least one key is
+/// configured, either inline or through `keys_file`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub keys: Vec<ApiKeyConfig>,
+    /// TOML file with more `[[keys]]` entries, so hashed keys can live
+    /// outside the main config (e.g. in a mounted secret).
+    #[serde(default)]
+    pub keys_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    keys: Vec<ApiKeyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Identifies the key in logs and metrics; never the secret itself.
+    pub name: String,
+    /// The key in plain text.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Hex-encoded SHA-256 of the key, as printed by `sha256sum`.
+    #[serde(default)]
+    pub key_sha256: Option<String>,
+    /// Providers whose every model this key may use.
+    #[serde(default)]
+    pub providers: Vec<String>,
+    /// Individual `provider/model` targets this key may use. With neither
+    /// `providers` nor `models` set the key may use everything.
+    #[serde(default)]
+    pub models: Vec<String>,
+    #[serde(default)]
+    pub rps: Option<u32>,
+    #[serde(default)]
+    pub rpm: Option<u32>,
+    #[serde(default)]
+    pub concurrent: Option<u32>,
+}
+
+/// Provider health checks behind `/ready` and `/health/providers`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    /// How often binaries are looked up and asked for `--version`.
+    #[serde(default = "default_health_interval_secs")]
+    pub interval_secs: u64,
+    /// Also send `probe_prompt` through each provider on every check. Costs
+    /// one real request per provider and interval.
+    #[serde(default)]
+    pub probe: bool,
+    #[serde(default = "default_probe_prompt")]
+    pub probe_prompt: String,
+    #[serde(default = "default_probe_timeout_secs")]
+    pub probe_timeout_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_health_interval_secs(),
+            probe: false,
+            probe_prompt: default_probe_prompt(),
+            probe_timeout_secs: default_probe_timeout_secs(),
+        }
+    }
+}
+
+fn default_health_interval_secs() -> u64 {
+    60
+}
+
+fn default_probe_prompt() -> String {
+    "Reply with the single word OK.".to_string()
+}
+
+fn default_probe_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackendKind {
+    /// In-memory LRU, lost on restart.
+    #[default]
+    Memory,
+    /// One file per entry in `dir`, kept across restarts.
+    Disk,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub backend: CacheBackendKind,
+    #[serde(default = "default_cache_ttl_secs")]
+    pub ttl_secs: u64,
+    /// How many entries are kept; storing more evicts the oldest.
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    /// Directory of the disk backend.
+    #[serde(default)]
+    pub dir: Option<String>,
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_cache_max_entries() -> usize {
+    1000
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct ModelSettings {
     pub rps: Option<u32>,
     pub rpm: Option<u32>,
     pub concurrent: Option<u32>,
     pub timeout_secs: Option<u64>,
+    pub max_queue_wait_secs: Option<u64>,
+    pub max_queue_depth: Option<u32>,
 }
 
 impl Config {
+    /// Reads and validates the config, rejecting it if any of the problems
+    /// reported by [`Config::validate`] is found.
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, AppError> {
+        let config = Self::parse(path)?;
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(AppError::ConfigLoad(problems.join("; ")));
+        }
+        Ok(config)
+    }
+
+    /// Reads the config and the keys file it points to, without validating
+    /// them beyond what deserialization enforces.
+    pub fn parse<P: AsRef<Path>>(path: P) -> Result<Self, AppError> {
         let content = std::fs::read_to_string(path.as_ref())
             .map_err(|e| AppError::ConfigLoad(format!("failed to read config: {e}")))?;
-        toml::from_str(&content)
-            .map_err(|e| AppError::ConfigLoad(format!("failed to parse config: {e}")))
+        let mut config: Self = toml::from_str(&content)
+            .map_err(|e| AppError::ConfigLoad(format!("failed to parse config: {e}")))?;
+
+        if let Some(keys_file) = &config.auth.keys_file {
+            let content = std::fs::read_to_string(keys_file)
+                .map_err(|e| AppError::ConfigLoad(format!("failed to read {keys_file}: {e}")))?;
+            let file: KeysFile = toml::from_str(&content)
+                .map_err(|e| AppError::ConfigLoad(format!("failed to parse {keys_file}: {e}")))?;
+            config.auth.keys.extend(file.keys);
+        }
+
+        Ok(config)
+    }
+
+    /// Every problem with the parsed config: unknown or duplicate providers
+    /// and models, zero limits, routes to targets that do not exist and
+    /// incomplete provider, key and cache tables.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let mut providers = HashSet::new();
+        for provider in &self.providers {
+            let name = &provider.name;
+            if !providers.insert(name.as_str()) {
+                problems.push(format!("provider '{name}' is defined more than once"));
+            }
+            if (provider.kind == ProviderKind::Cli) != provider.cli.is_some() {
+                problems.push(format!(
+                    "provider '{name}': a [providers.cli] table is required exactly when kind = \"cli\""
+                ));
+            }
+            if provider.kind.is_http() != provider.http.is_some() {
+                problems.push(format!(
+                    "provider '{name}': a [providers.http] table is required exactly when kind = \"openai\", \"anthropic\" or \"ollama\""
+                ));
+            }
+            if provider.supports_auto_model
+                && provider
+                    .http
+                    .as_ref()
+                    .is_some_and(|h| h.default_model.is_none())
+            {
+                problems.push(format!(
+                    "provider '{name}': HTTP providers need http.default_model for auto model selection, or supports_auto_model = false"
+                ));
+            }
+            if name == AUTO_PROVIDER {
+                problems.push(format!(
+                    "provider '{AUTO_PROVIDER}' is reserved for [[routing.rules]]"
+                ));
+            }
+            if provider.kind == ProviderKind::Builtin && !BUILTIN_PROVIDERS.contains(&name.as_str())
+            {
+                problems.push(format!(
+                    "provider '{name}' is not a built-in provider (one of {}); set kind = \"cli\" for a custom one",
+                    BUILTIN_PROVIDERS.join(", ")
+                ));
+            }
+            check_limits(
+                &mut problems,
+                &format!("provider '{name}'"),
+                [
+                    ("rps", provider.rps.map(u64::from)),
+                    ("rpm", provider.rpm.map(u64::from)),
+                    ("concurrent", provider.concurrent.map(u64::from)),
+                    ("timeout_secs", provider.timeout_secs),
+                ],
+            );
+
+            let mut models = HashSet::new();
+            for model in &provider.models {
+                let target = format!("model '{name}/{}'", model.name);
+                if model.name == AUTO_MODEL {
+                    problems.push(format!(
+                        "{target}: '{AUTO_MODEL}' is reserved, set the provider-level limits instead"
+                    ));
+                }
+                if !models.insert(model.name.as_str()) {
+                    problems.push(format!("{target} is defined more than once"));
+                }
+                check_limits(
+                    &mut problems,
+                    &target,
+                    [
+                        ("rps", model.rps.map(u64::from)),
+                        ("rpm", model.rpm.map(u64::from)),
+                        ("concurrent", model.concurrent.map(u64::from)),
+                        ("timeout_secs", model.timeout_secs),
+                    ],
+                );
+            }
+        }
+
+        let mut routes = HashSet::new();
+        for route in &self.routes {
+            let name = &route.name;
+            if !routes.insert(name.as_str()) {
+                problems.push(format!("route '{name}' is defined more than once"));
+            }
+            if route.targets.is_empty() {
+                problems.push(format!("route '{name}' has no targets"));
+            }
+            check_limits(
+                &mut problems,
+                &format!("route '{name}'"),
+                [("hedge_after_ms", route.hedge_after_ms)],
+            );
+            if route.hedge_after_ms.is_some() && route.targets.len() < 2 {
+                problems.push(format!(
+                    "route '{name}': hedge_after_ms needs at least two targets"
+                ));
+            }
+            for target in route.targets.iter().map(|t| Target::parse(t)) {
+                if let Some(problem) = self.check_target(&target) {
+                    problems.push(format!("route '{name}': {problem}"));
+                }
+            }
+        }
+
+        let mut rules = HashSet::new();
+        for rule in &self.routing.rules {
+            let name = &rule.name;
+            if !rules.insert(name.as_str()) {
+                problems.push(format!("routing rule '{name}' is defined more than once"));
+            }
+            if rule.targets.is_empty() {
+                problems.push(format!("routing rule '{name}' has no targets"));
+            }
+            for target in rule.targets.iter().map(|t| Target::parse(t)) {
+                if let Some(problem) = self.check_target(&target) {
+                    problems.push(format!("routing rule '{name}': {problem}"));
+                }
+            }
+            if rule.min_headroom.is_some_and(|h| !(0.0..=1.0).contains(&h)) {
+                problems.push(format!(
+                    "routing rule '{name}': min_headroom must be between 0.0 and 1.0"
+                ));
+            }
+            if matches!(
+                (rule.min_prompt_chars, rule.max_prompt_chars),
+                (Some(min), Some(max)) if min > max
+            ) {
+                problems.push(format!(
+                    "routing rule '{name}': min_prompt_chars is above max_prompt_chars"
+                ));
+            }
+        }
+
+        let mut keys = HashSet::new();
+        for key in &self.auth.keys {
+            let name = &key.name;
+            if !keys.insert(name.as_str()) {
+                problems.push(format!("api key '{name}' is defined more than once"));
+            }
+            if key.key.is_some() == key.key_sha256.is_some() {
+                problems.push(format!(
+                    "api key '{name}': exactly one of key and key_sha256 is required"
+                ));
+            }
+            if let Some(hash) = &key.key_sha256 {
+                if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    problems.push(format!(
+                        "api key '{name}': key_sha256 must be 64 hex characters"
+                    ));
+                }
+            }
+            check_limits(
+                &mut problems,
+                &format!("api key '{name}'"),
+                [
+                    ("rps", key.rps.map(u64::from)),
+                    ("rpm", key.rpm.map(u64::from)),
+                    ("concurrent", key.concurrent.map(u64::from)),
+                ],
+            );
+        }
+
+        if let Some(cache) = &self.cache {
+            if cache.backend == CacheBackendKind::Disk && cache.dir.is_none() {
+                problems.push("cache: dir is required for the disk backend".into());
+            }
+            if cache.max_entries == 0 {
+                problems.push("cache: max_entries must be positive".into());
+            }
+        }
+
+        problems
+    }
+
+    /// Why requests to `target` would always fail, if they would.
+    fn check_target(&self, target: &Target) -> Option<String> {
+        let Some(provider) = self.providers.iter().find(|p| p.name == target.provider) else {
+            // Built-in providers work without a [[providers]] entry, but
+            // only with auto model selection
+            if !BUILTIN_PROVIDERS.contains(&target.provider.as_str()) {
+                return Some(format!("unknown provider in target '{target}'"));
+            }
+            return target
+                .model
+                .is_some()
+                .then(|| format!("unknown model in target '{target}'"));
+        };
+        match &target.model {
+            Some(model) if !provider.models.iter().any(|m| &m.name == model) => {
+                Some(format!("unknown model in target '{target}'"))
+            }
+            None if !provider.supports_auto_model => Some(format!(
+                "provider '{}' does not support auto model selection",
+                provider.name
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn settings(&self) -> Settings {
+        Settings {
+            model_settings: self.model_settings(),
+            provider_settings: self.provider_settings(),
+            routes: self.routes(),
+            routing: self.routing.rules.clone(),
+        }
     }
 
     pub fn model_settings(&self) -> HashMap<(String, String), ModelSettings> {
@@ -89,6 +650,8 @@ impl Config {
                         rpm: model.rpm,
                         concurrent: model.concurrent,
                         timeout_secs: model.timeout_secs,
+                        max_queue_wait_secs: model.max_queue_wait_secs,
+                        max_queue_depth: model.max_queue_depth,
                     },
                 );
             }
@@ -103,11 +666,32 @@ impl Config {
                 (
                     p.name.clone(),
                     ProviderSettings {
+                        kind: p.kind,
                         supports_auto_model: p.supports_auto_model,
                         rps: p.rps,
                         rpm: p.rpm,
                         concurrent: p.concurrent,
                         timeout_secs: p.timeout_secs,
+                        max_queue_wait_secs: p.max_queue_wait_secs,
+                        max_queue_depth: p.max_queue_depth,
+                        max_repair_attempts: p.max_repair_attempts,
+                        cli: p.cli.clone(),
+                        http: p.http.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    pub fn routes(&self) -> HashMap<String, Route> {
+        self.routes
+            .iter()
+            .map(|r| {
+                (
+                    r.name.clone(),
+                    Route {
+                        targets: r.targets.iter().map(|t| Target::parse(t)).collect(),
+                        hedge_after: r.hedge_after_ms.map(Duration::from_millis),
                     },
                 )
             })
@@ -115,13 +699,72 @@ impl Config {
     }
 }
 
+/// The parts of the config that are swapped in on reload.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub model_settings: HashMap<(String, String), ModelSettings>,
+    pub provider_settings: HashMap<String, ProviderSettings>,
+    pub routes: HashMap<String, Route>,
+    pub routing: Vec<RoutingRule>,
+}
+
+/// A route's targets, in the order they are tried.
+#[derive(Debug, Clone, Default)]
+pub struct Route {
+    pub targets: Vec<Target>,
+    /// See [`RouteConfig::hedge_after_ms`].
+    pub hedge_after: Option<Duration>,
+}
+
+impl Settings {
+    /// Rate limiter entries: every configured model, plus the provider-level
+    /// limits under `AUTO_MODEL` for providers with auto model selection.
+    pub fn limits(&self) -> HashMap<(String, String), ModelSettings> {
+        let mut limits = self.model_settings.clone();
+        for (name, settings) in &self.provider_settings {
+            if settings.supports_auto_model {
+                limits.insert(
+                    (name.clone(), AUTO_MODEL.to_string()),
+                    ModelSettings {
+                        rps: settings.rps,
+                        rpm: settings.rpm,
+                        concurrent: settings.concurrent,
+                        timeout_secs: settings.timeout_secs,
+                        max_queue_wait_secs: settings.max_queue_wait_secs,
+                        max_queue_depth: settings.max_queue_depth,
+                    },
+                );
+            }
+        }
+        limits
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ProviderSettings {
+    pub kind: ProviderKind,
     pub supports_auto_model: bool,
     pub rps: Option<u32>,
     pub rpm: Option<u32>,
     pub concurrent: Option<u32>,
     pub timeout_secs: Option<u64>,
+    pub max_queue_wait_secs: Option<u64>,
+    pub max_queue_depth: Option<u32>,
+    pub max_repair_attempts: Option<u32>,
+    pub cli: Option<CliProviderConfig>,
+    pub http: Option<HttpProviderConfig>,
+}
+
+/// Adds a problem for every limit set to zero, which would either reject
+/// or time out every request.
+fn check_limits<const N: usize>(
+    problems: &mut Vec<String>,
+    owner: &str,
+    limits: [(&str, Option<u64>); N],
+) {
+    for (name, _) in limits.iter().filter(|(_, value)| *value == Some(0)) {
+        problems.push(format!("{owner}: {name} must be positive"));
+    }
 }
 
 impl Default for ServerConfig {
@@ -129,6 +772,253 @@ impl Default for ServerConfig {
         Self {
             host: default_host(),
             port: default_port(),
+            drain_timeout_secs: default_drain_timeout_secs(),
+            kill_grace_secs: default_kill_grace_secs(),
+            config_poll_secs: default_config_poll_secs(),
+        }
+    }
+}
+
+/// Model used when a request leaves model selection to the CLI.
+pub const AUTO_MODEL: &str = "_auto";
+
+/// Provider of requests that leave the choice of target to
+/// `[[routing.rules]]`.
+pub const AUTO_PROVIDER: &str = "auto";
+
+/// A `provider/model` pair such as `claude/sonnet`. `provider/_auto` and a
+/// bare `provider` both mean auto model selection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub provider: String,
+    pub model: Option<String>,
+}
+
+impl Target {
+    pub fn parse(s: &str) -> Self {
+        match s.split_once('/') {
+            Some((provider, model)) if model != AUTO_MODEL => Self {
+                provider: provider.to_string(),
+                model: Some(model.to_string()),
+            },
+            Some((provider, _)) => Self {
+                provider: provider.to_string(),
+                model: None,
+            },
+            None => Self {
+                provider: s.to_string(),
+                model: None,
+            },
         }
     }
 }
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.provider,
+            self.model.as_deref().unwrap_or(AUTO_MODEL)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml_str: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(toml_str)
+    }
+
+    #[test]
+    fn test_example_config_is_valid() {
+        let config = Config::parse("config.toml").unwrap();
+        assert_eq!(config.validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let config = parse(
+            r#"
+[server]
+
+[[providers]]
+name = "claud"
+rps = 0
+
+[[providers]]
+name = "gemini"
+supports_auto_model = false
+
+[[providers.models]]
+name = "gemini-2.5-pro"
+
+[[providers.models]]
+name = "gemini-2.5-pro"
+timeout_secs = 0
+
+[[routes]]
+name = "fallback"
+targets = ["gemini/gemini-2.5-flash", "gemini", "codex", "codex/o3", "nope/x"]
+
+[[auth.keys]]
+name = "ci"
+rpm = 0
+
+[[auth.keys]]
+name = "ci"
+key = "secret"
+
+[[auth.keys]]
+name = "deploy"
+key_sha256 = "sha256:f82da6e2"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            [
+                "provider 'claud' is not a built-in provider (one of claude, codex, gemini); set kind = \"cli\" for a custom one",
+                "provider 'claud': rps must be positive",
+                "model 'gemini/gemini-2.5-pro' is defined more than once",
+                "model 'gemini/gemini-2.5-pro': timeout_secs must be positive",
+                "route 'fallback': unknown model in target 'gemini/gemini-2.5-flash'",
+                "route 'fallback': provider 'gemini' does not support auto model selection",
+                "route 'fallback': unknown model in target 'codex/o3'",
+                "route 'fallback': unknown provider in target 'nope/x'",
+                "api key 'ci': exactly one of key and key_sha256 is required",
+                "api key 'ci': rpm must be positive",
+                "api key 'ci' is defined more than once",
+                "api key 'deploy': key_sha256 must be 64 hex characters",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_http_providers() {
+        let config = parse(
+            r#"
+[server]
+
+[[providers]]
+name = "local"
+kind = "openai"
+
+[providers.http]
+base_url = "http://localhost:8080/v1"
+default_model = "qwen"
+
+[[providers]]
+name = "anthropic-api"
+kind = "anthropic"
+
+[providers.http]
+api_key_env = "ANTHROPIC_API_KEY"
+
+[[providers]]
+name = "broken"
+kind = "openai"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            [
+                "provider 'anthropic-api': HTTP providers need http.default_model for auto model selection, or supports_auto_model = false",
+                "provider 'broken': a [providers.http] table is required exactly when kind = \"openai\", \"anthropic\" or \"ollama\"",
+            ]
+        );
+        let settings = &config.provider_settings()["local"];
+        assert_eq!(settings.kind, ProviderKind::Openai);
+        assert_eq!(
+            settings.http.as_ref().unwrap().default_model.as_deref(),
+            Some("qwen")
+        );
+    }
+
+    #[test]
+    fn test_routing_rules_are_checked() {
+        let config = parse(
+            r#"
+[server]
+
+[[providers]]
+name = "auto"
+
+[[routing.rules]]
+name = "fast"
+min_prompt_chars = 500
+max_prompt_chars = 100
+min_headroom = 2.0
+targets = ["claude/haiku"]
+
+[[routing.rules]]
+name = "fast"
+targets = []
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            [
+                "provider 'auto' is reserved for [[routing.rules]]",
+                "provider 'auto' is not a built-in provider (one of claude, codex, gemini); set kind = \"cli\" for a custom one",
+                "routing rule 'fast': unknown model in target 'claude/haiku'",
+                "routing rule 'fast': min_headroom must be between 0.0 and 1.0",
+                "routing rule 'fast': min_prompt_chars is above max_prompt_chars",
+                "routing rule 'fast' is defined more than once",
+                "routing rule 'fast' has no targets",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hedged_routes_are_checked() {
+        let config = parse(
+            r#"
+[server]
+
+[[routes]]
+name = "single"
+targets = ["claude"]
+hedge_after_ms = 2000
+
+[[routes]]
+name = "instant"
+targets = ["claude", "gemini"]
+hedge_after_ms = 0
+
+[[routes]]
+name = "hedged"
+targets = ["claude", "gemini"]
+hedge_after_ms = 2000
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            [
+                "route 'single': hedge_after_ms needs at least two targets",
+                "route 'instant': hedge_after_ms must be positive",
+            ]
+        );
+        assert_eq!(
+            config.routes()["hedged"].hedge_after,
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let e = parse("[server]\n\n[[providers]]\nname = \"claude\"\nrate = 1\n").unwrap_err();
+        assert!(e.to_string().contains("unknown field `rate`"));
+        assert!(parse("[server]\nprot = 3000\n").is_err());
+        assert!(parse("[server]\n[cahce]\n").is_err());
+    }
+}
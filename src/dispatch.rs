@@ -0,0 +1,540 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::auth::{sha256_hex, ApiKey, KEY_LIMITER};
+use crate::config::{Target, AUTO_MODEL};
+use crate::error::AppError;
+use crate::metrics;
+use crate::provider::{get_provider_with_executor, Provider, Usage};
+use crate::rate_limiter::{ConcurrentGuard, Wait};
+use crate::schema;
+use crate::{AppState, GenerateRequest, Output};
+
+/// A provider/model pair that exists in the config, together with the
+/// rate limiter entry and timeout that apply to it.
+pub struct Resolved {
+    provider: Box<dyn Provider>,
+    target: Target,
+    /// Unregistered (unconfigured providers) means unlimited.
+    limiter_model: String,
+    timeout_secs: Option<u64>,
+    max_repair_attempts: u32,
+}
+
+/// A request that passed provider/model resolution and rate limiting.
+/// Holding it keeps the concurrency slot reserved.
+pub struct Admission {
+    pub provider: Box<dyn Provider>,
+    pub target: Target,
+    pub timeout_secs: Option<u64>,
+    pub max_repair_attempts: u32,
+    /// Time spent waiting for the rate limiter.
+    pub queue_wait: Duration,
+    limiter_model: String,
+    wait: Wait,
+    /// The caller's key slot, for admissions from [`admit_any`].
+    _key_guard: Option<ConcurrentGuard>,
+    _guard: ConcurrentGuard,
+}
+
+/// A validated output, the target that produced it and the number of CLI
+/// invocations it took.
+#[derive(Clone)]
+pub struct Execution {
+    pub target: Target,
+    pub output: Output,
+    pub attempts: u32,
+    /// The model the CLI reports for the last attempt.
+    pub model: Option<String>,
+    /// Time spent in CLI runs, summed over all attempts.
+    pub duration: Duration,
+    pub queue_wait: Duration,
+    /// Usage summed over all attempts, if the CLI reports any.
+    pub usage: Option<Usage>,
+    /// Taken from an identical request's run instead of running the CLI.
+    pub shared: bool,
+}
+
+pub fn resolve(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    target: &Target,
+) -> Result<Resolved, AppError> {
+    if let Some(key) = caller {
+        key.check(target)?;
+    }
+
+    let current = state.settings();
+    let provider_name = target.provider.as_str();
+    let provider = get_provider_with_executor(
+        provider_name,
+        current.provider_settings.get(provider_name),
+        state.executor.clone(),
+    )
+    .ok_or_else(|| AppError::ProviderNotFound(provider_name.to_string()))?;
+
+    let (timeout_secs, limiter_model) = match &target.model {
+        Some(model) => {
+            let key = (provider_name.to_string(), model.clone());
+            let settings =
+                current
+                    .model_settings
+                    .get(&key)
+                    .ok_or_else(|| AppError::ModelNotFound {
+                        provider: provider_name.to_string(),
+                        model: Some(model.clone()),
+                    })?;
+            (settings.timeout_secs, model.clone())
+        }
+        None => {
+            let provider_cfg = current.provider_settings.get(provider_name);
+
+            let supports_auto = provider_cfg.map(|p| p.supports_auto_model).unwrap_or(true);
+
+            if !supports_auto {
+                return Err(AppError::AutoModelNotSupported(provider_name.to_string()));
+            }
+
+            // Use provider-level rate limit for auto model
+            (
+                provider_cfg.and_then(|p| p.timeout_secs),
+                AUTO_MODEL.to_string(),
+            )
+        }
+    };
+
+    let max_repair_attempts = current
+        .provider_settings
+        .get(provider_name)
+        .and_then(|p| p.max_repair_attempts)
+        .unwrap_or(0);
+
+    Ok(Resolved {
+        provider,
+        target: target.clone(),
+        limiter_model,
+        timeout_secs,
+        max_repair_attempts,
+    })
+}
+
+/// Takes a slot of the caller's own budget. Keys without a budget are not
+/// registered, which makes this a no-op.
+pub async fn acquire_key(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    wait: Wait,
+) -> Result<Option<ConcurrentGuard>, AppError> {
+    let Some(key) = caller else {
+        return Ok(None);
+    };
+    state
+        .rate_limiter
+        .acquire(KEY_LIMITER, &key.name, wait)
+        .await
+        .map(Some)
+        .map_err(|()| AppError::KeyRateLimited(key.name.clone()))
+}
+
+/// Takes a slot of the target's rate limits. The caller's own budget is
+/// charged separately, see [`acquire_key`].
+pub async fn acquire(
+    state: &AppState,
+    resolved: Resolved,
+    wait: Wait,
+) -> Result<Admission, AppError> {
+    state.check_draining()?;
+    let queued = Instant::now();
+
+    let guard = match state
+        .rate_limiter
+        .acquire(&resolved.target.provider, &resolved.limiter_model, wait)
+        .await
+    {
+        Ok(guard) => guard,
+        // Provider-level limits throttle auto model requests, but never
+        // reject them
+        Err(()) if resolved.target.model.is_none() => state.rate_limiter.unlimited(),
+        Err(()) => {
+            state.metrics.record_rate_limited(&resolved.target);
+            return Err(AppError::RateLimited {
+                provider: resolved.target.provider.clone(),
+                model: resolved.target.model.clone(),
+            });
+        }
+    };
+    // Draining may have started while waiting
+    state.check_draining()?;
+
+    Ok(Admission {
+        provider: resolved.provider,
+        target: resolved.target,
+        timeout_secs: resolved.timeout_secs,
+        max_repair_attempts: resolved.max_repair_attempts,
+        queue_wait: queued.elapsed(),
+        limiter_model: resolved.limiter_model,
+        wait,
+        _key_guard: None,
+        _guard: guard,
+    })
+}
+
+/// Admits the first target that is not rate limited. The admission also
+/// holds the caller's key slot.
+pub async fn admit_any(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    targets: &[Target],
+) -> Result<Admission, AppError> {
+    let wait = Wait::Queue(None);
+    let key_guard = acquire_key(state, caller, wait).await?;
+    let mut result = Err(AppError::InvalidRequest("no targets to run".into()));
+    for target in targets {
+        result = match resolve(state, caller, target) {
+            Ok(resolved) => acquire(state, resolved, wait).await,
+            Err(e) => Err(e),
+        };
+        match &mut result {
+            Err(AppError::RateLimited { .. }) => {
+                warn!(target = %target, "target rate limited, trying next");
+            }
+            Ok(admission) => {
+                admission._key_guard = key_guard;
+                return result;
+            }
+            Err(_) => return result,
+        }
+    }
+    result
+}
+
+/// Counts a repair attempt against the rate limits of an admitted request.
+async fn charge_repair(state: &AppState, admission: &Admission) -> Result<(), AppError> {
+    let target = &admission.target;
+    match state
+        .rate_limiter
+        .charge(&target.provider, &admission.limiter_model, admission.wait)
+        .await
+    {
+        Ok(()) => Ok(()),
+        Err(()) if target.model.is_none() => Ok(()),
+        Err(()) => {
+            state.metrics.record_rate_limited(target);
+            Err(AppError::RateLimited {
+                provider: target.provider.clone(),
+                model: target.model.clone(),
+            })
+        }
+    }
+}
+
+/// Runs an admitted request and validates the result against its schema.
+///
+/// Output that fails to parse or validate is sent back to the provider
+/// together with the errors, up to `max_repair_attempts` times. Each repair
+/// attempt counts against the target's rps/rpm limits.
+pub async fn execute(
+    state: &AppState,
+    admission: &Admission,
+    req: &GenerateRequest,
+) -> Result<Execution, AppError> {
+    let target = &admission.target;
+    let max_repair_attempts = req.repair_attempts(admission.max_repair_attempts);
+
+    info!(
+        provider = %target.provider,
+        model = ?target.model,
+        timeout_secs = ?admission.timeout_secs,
+        max_repair_attempts,
+        "executing request"
+    );
+
+    let conversation = req.conversation();
+    let mut messages = conversation.to_vec();
+    let mut attempts = 0;
+    let mut duration = Duration::ZERO;
+    let mut usage: Option<Usage> = None;
+    loop {
+        attempts += 1;
+        if attempts > 1 {
+            charge_repair(state, admission).await?;
+        }
+
+        let started = Instant::now();
+        let result = admission
+            .provider
+            .execute(
+                &messages,
+                req.schema.as_ref(),
+                target.model.as_deref(),
+                admission.timeout_secs,
+            )
+            .await;
+        let elapsed = started.elapsed();
+        duration += elapsed;
+        state.metrics.observe_execution(target, elapsed);
+        let mut model = None;
+        let result = result.and_then(|completion| {
+            if let Some(run) = &completion.usage {
+                usage.get_or_insert_with(Usage::default).add(run);
+            }
+            model = completion.model;
+            Output::validate(req.schema.as_ref(), completion.output)
+        });
+        if let Err(AppError::OutputValidation { .. }) = &result {
+            state.metrics.record_validation_failure(target);
+        }
+
+        let (invalid_output, errors) = match result {
+            Ok(output) => {
+                return Ok(Execution {
+                    target: target.clone(),
+                    output,
+                    attempts,
+                    model,
+                    duration,
+                    queue_wait: admission.queue_wait,
+                    usage,
+                    shared: false,
+                })
+            }
+            Err(e) if attempts > max_repair_attempts || req.schema.is_none() => return Err(e),
+            Err(AppError::OutputValidation { errors, output }) => (output.to_string(), errors),
+            Err(AppError::OutputParse { message, stdout }) => (stdout, vec![message]),
+            Err(e) => return Err(e),
+        };
+
+        warn!(
+            provider = %target.provider,
+            model = ?target.model,
+            attempt = attempts,
+            errors = ?errors,
+            "invalid output, retrying with repair prompt"
+        );
+        // The repair request replaces the final user turn
+        let last = messages.len() - 1;
+        messages[last].content =
+            schema::repair_prompt(&conversation[last].content, &invalid_output, &errors);
+    }
+}
+
+/// Requests with the same key produce interchangeable results. Messages are
+/// hashed with their roles, as distinct conversations can render to the
+/// same transcript.
+fn flight_key(target: &Target, req: &GenerateRequest) -> String {
+    let material = serde_json::to_string(&(
+        target.to_string(),
+        req.conversation(),
+        &req.schema,
+        req.max_repair_attempts,
+    ))
+    .expect("serializing JSON values cannot fail");
+    sha256_hex(&material)
+}
+
+/// Errors after which a route moves on to its next target.
+fn should_fall_back(e: &AppError) -> bool {
+    matches!(
+        e,
+        AppError::RateLimited { .. }
+            | AppError::Timeout { .. }
+            | AppError::ProviderExecution { .. }
+            | AppError::AuthenticationRequired { .. }
+            | AppError::QuotaExceeded { .. }
+            | AppError::UnknownModel { .. }
+            | AppError::ContextTooLong { .. }
+            | AppError::ContentRefused { .. }
+            | AppError::OutputValidation { .. }
+    )
+}
+
+/// Runs `req` on one target, sharing the run with identical requests already
+/// in flight for it.
+async fn attempt(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    target: &Target,
+    req: &GenerateRequest,
+    wait: Wait,
+    on_admitted: &impl Fn(&Target),
+) -> Result<Execution, AppError> {
+    // Only targets that exist in the config are recorded, so clients cannot
+    // add metric labels
+    let resolved = resolve(state, caller, target)?;
+    // Joining a flight already in progress skips `acquire`
+    state.check_draining()?;
+    let key = flight_key(target, req);
+    let ran = AtomicBool::new(false);
+    let result = state
+        .inflight
+        .run(
+            &key,
+            |admitted| {
+                ran.store(true, Ordering::Relaxed);
+                async move {
+                    let admission = acquire(state, resolved, wait).await?;
+                    admitted.signal();
+                    execute(state, &admission, req).await
+                }
+            },
+            || on_admitted(target),
+        )
+        .await
+        .map(|mut execution| {
+            execution.shared = !ran.load(Ordering::Relaxed);
+            execution
+        });
+    state
+        .metrics
+        .record_request(target, metrics::outcome(&result));
+    result
+}
+
+/// Runs `primary`, and once it has gone `delay` without an answer also
+/// `backup`, if the rate limiter admits it without waiting. Returns the
+/// first valid output, or the error of whichever run failed last (the
+/// primary's if `backup` was turned away), and whether `backup` was run.
+/// The slower run is dropped, which kills its CLI process.
+async fn hedge(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    (primary, backup): (&Target, &Target),
+    req: &GenerateRequest,
+    wait: Wait,
+    delay: Duration,
+    on_admitted: &impl Fn(&Target),
+) -> (Result<Execution, AppError>, bool) {
+    let first = attempt(state, caller, primary, req, wait, on_admitted);
+    tokio::pin!(first);
+    tokio::select! {
+        result = &mut first => return (result, false),
+        _ = tokio::time::sleep(delay) => {}
+    }
+
+    let now = Wait::Queue(Some(tokio::time::Instant::now()));
+    let second = attempt(state, caller, backup, req, now, on_admitted);
+    tokio::pin!(second);
+    info!(primary = %primary, backup = %backup, "primary is slow, hedging");
+
+    let (mut first_error, mut second_error) = (None, None);
+    while first_error.is_none() || second_error.is_none() {
+        tokio::select! {
+            result = &mut first, if first_error.is_none() => match result {
+                Ok(execution) => {
+                    if second_error.is_none() {
+                        state.metrics.record_request(backup, HEDGE_CANCELLED);
+                    }
+                    return (Ok(execution), true);
+                }
+                Err(e) => first_error = Some(e),
+            },
+            result = &mut second, if second_error.is_none() => match result {
+                Ok(execution) => {
+                    if first_error.is_none() {
+                        state.metrics.record_request(primary, HEDGE_CANCELLED);
+                    }
+                    return (Ok(execution), true);
+                }
+                // No room for the backup: leave it to the fallback
+                Err(AppError::RateLimited { .. }) if first_error.is_none() => {
+                    info!(backup = %backup, "backup rate limited, not hedging");
+                    return (first.await, false);
+                }
+                Err(e) => second_error = Some(e),
+            },
+        }
+    }
+    // Both failed. As without hedging, an error that routes do not fall
+    // back from is final; otherwise the backup's error is the latest, unless
+    // the backup never got to run
+    match (first_error, second_error) {
+        (Some(e), _) if !should_fall_back(&e) => (Err(e), true),
+        (Some(e), Some(backup)) if not_admitted(&backup) => (Err(e), true),
+        (_, Some(e)) => (Err(e), true),
+        _ => unreachable!("both runs failed"),
+    }
+}
+
+/// Errors of a run that was turned away before it started.
+fn not_admitted(e: &AppError) -> bool {
+    matches!(
+        e,
+        AppError::RateLimited { .. }
+            | AppError::KeyRateLimited(_)
+            | AppError::Forbidden { .. }
+            | AppError::ShuttingDown
+    )
+}
+
+/// The `outcome` recorded for the slower run of a hedged request.
+const HEDGE_CANCELLED: &str = "hedge_cancelled";
+
+/// Tries each target in order until one produces valid output. `wait`
+/// bounds the rate limiter wait per target; `on_admitted` is called whenever
+/// a target gets past rate limiting.
+///
+/// With `hedge_after`, a target that has not answered in time races the
+/// next one, see [`hedge`].
+pub async fn dispatch(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    targets: &[Target],
+    req: &GenerateRequest,
+    wait: Wait,
+    hedge_after: Option<Duration>,
+    on_admitted: impl Fn(&Target),
+) -> Result<Execution, AppError> {
+    // The caller's budget is charged once, however many targets run
+    let _key_guard = acquire_key(state, caller, wait).await?;
+    dispatch_charged(state, caller, targets, req, wait, hedge_after, on_admitted).await
+}
+
+/// [`dispatch`] for callers that already hold the caller's key guard, see
+/// [`acquire_key`].
+pub async fn dispatch_charged(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    targets: &[Target],
+    req: &GenerateRequest,
+    wait: Wait,
+    hedge_after: Option<Duration>,
+    on_admitted: impl Fn(&Target),
+) -> Result<Execution, AppError> {
+    let mut last_error = AppError::InvalidRequest("no targets to run".into());
+    let mut i = 0;
+    while i < targets.len() {
+        let target = &targets[i];
+        let result = match (hedge_after, targets.get(i + 1)) {
+            (Some(delay), Some(backup)) => {
+                let (result, hedged) = hedge(
+                    state,
+                    caller,
+                    (target, backup),
+                    req,
+                    wait,
+                    delay,
+                    &on_admitted,
+                )
+                .await;
+                if hedged {
+                    i += 1;
+                }
+                result
+            }
+            _ => attempt(state, caller, target, req, wait, &on_admitted).await,
+        };
+        i += 1;
+
+        match result {
+            Ok(execution) => return Ok(execution),
+            Err(e) if should_fall_back(&e) && i < targets.len() => {
+                warn!(target = %target, error = %e, "target failed, falling back");
+                last_error = e;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error)
+}
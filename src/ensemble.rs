@@ -0,0 +1,519 @@
+use std::collections::{BTreeMap, HashSet};
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{info, warn};
+
+use crate::auth::ApiKey;
+use crate::config::Target;
+use crate::dispatch::{acquire_key, dispatch_charged, Execution};
+use crate::error::AppError;
+use crate::jobs::JobError;
+use crate::rate_limiter::Wait;
+use crate::{AppState, GenerateRequest, GenerateResponse, Output};
+
+/// Upper bound for the number of targets of an ensemble.
+const MAX_TARGETS: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Every field takes the value most targets agree on, ties going to the
+    /// earlier target.
+    #[default]
+    Majority,
+    /// Every target must succeed and agree on every field.
+    Unanimous,
+    /// The first valid output wins and the other runs are cancelled.
+    FirstValid,
+}
+
+/// Runs one structured request on several targets at once and merges their
+/// outputs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Ensemble {
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+impl Ensemble {
+    pub fn validate(&self, req: &GenerateRequest) -> Result<(), AppError> {
+        if req.provider.is_some() || req.route.is_some() || req.model.is_some() {
+            return Err(AppError::InvalidRequest(
+                "\"ensemble\" cannot be combined with \"provider\", \"model\" or \"route\"".into(),
+            ));
+        }
+        if !req.tags.is_empty() {
+            return Err(AppError::InvalidRequest(
+                "\"ensemble\" cannot be combined with \"tags\"".into(),
+            ));
+        }
+        if req.schema.is_none() {
+            return Err(AppError::InvalidRequest(
+                "\"ensemble\" requires a \"schema\"".into(),
+            ));
+        }
+        // Run details are in the ensemble report, per target
+        if req.include_meta {
+            return Err(AppError::InvalidRequest(
+                "\"ensemble\" cannot be combined with \"include_meta\"".into(),
+            ));
+        }
+        if !(2..=MAX_TARGETS).contains(&self.targets.len()) {
+            return Err(AppError::InvalidRequest(format!(
+                "\"ensemble.targets\" must list between 2 and {MAX_TARGETS} targets"
+            )));
+        }
+        let mut seen = HashSet::new();
+        if let Some(dup) = self.targets().find(|t| !seen.insert(t.to_string())) {
+            return Err(AppError::InvalidRequest(format!(
+                "\"ensemble.targets\" lists {dup} more than once"
+            )));
+        }
+        Ok(())
+    }
+
+    fn targets(&self) -> impl Iterator<Item = Target> + '_ {
+        self.targets.iter().map(|t| Target::parse(t))
+    }
+}
+
+/// What each target produced, and where their outputs differ.
+#[derive(Debug, Serialize)]
+pub struct EnsembleReport {
+    strategy: Strategy,
+    results: Vec<TargetResult>,
+    /// The competing values per JSON pointer at which outputs differ.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    disagreements: BTreeMap<String, Vec<Vote>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Ok,
+    Error,
+    /// Stopped once another target's output had won.
+    Cancelled,
+}
+
+#[derive(Debug, Serialize)]
+struct TargetResult {
+    target: String,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<JobError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+}
+
+/// One of the values found at a pointer, and the targets that produced it.
+#[derive(Debug, PartialEq, Serialize)]
+struct Vote {
+    /// Absent when these targets left the field out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+    targets: Vec<String>,
+}
+
+/// Answers a validated ensemble request. Each target runs like a
+/// single-target `/generate` request, with its own rate limiting and
+/// repair attempts.
+pub async fn respond(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    req: &GenerateRequest,
+    ensemble: &Ensemble,
+    wait: Wait,
+) -> Result<GenerateResponse, AppError> {
+    let targets: Vec<Target> = ensemble.targets().collect();
+    info!(targets = targets.len(), strategy = ?ensemble.strategy, "running ensemble");
+    // One request, however many targets: the caller's budget is charged once
+    let _key_guard = acquire_key(state, caller, wait).await?;
+
+    let mut runs: FuturesUnordered<_> = targets
+        .iter()
+        .enumerate()
+        .map(|(i, target)| async move {
+            let targets = std::slice::from_ref(target);
+            (
+                i,
+                dispatch_charged(state, caller, targets, req, wait, None, |_| {}).await,
+            )
+        })
+        .collect();
+    let mut results: Vec<Option<Result<Execution, AppError>>> =
+        targets.iter().map(|_| None).collect();
+    while let Some((i, result)) = runs.next().await {
+        let done = ensemble.strategy == Strategy::FirstValid && result.is_ok();
+        results[i] = Some(result);
+        if done {
+            break;
+        }
+    }
+    // Dropping the runs still going kills their CLI processes
+    drop(runs);
+
+    let outputs: Vec<(String, Option<&Value>)> = targets
+        .iter()
+        .zip(&results)
+        .filter_map(|(target, result)| match result {
+            Some(Ok(Execution {
+                output: Output::Structured(output),
+                ..
+            })) => Some((target.to_string(), Some(output))),
+            _ => None,
+        })
+        .collect();
+    let first_error = || {
+        results
+            .iter()
+            .flatten()
+            .find_map(|r| r.as_ref().err().cloned())
+    };
+
+    let mut disagreements = BTreeMap::new();
+    let merged = match merge(&outputs, "", &mut disagreements) {
+        Some(merged) if outputs.len() == targets.len() => merged,
+        _ if ensemble.strategy == Strategy::Unanimous => {
+            return Err(first_error().expect("a target failed"))
+        }
+        Some(merged) => merged,
+        None => return Err(first_error().expect("every target failed")),
+    };
+    if ensemble.strategy == Strategy::Unanimous && !disagreements.is_empty() {
+        let pointers = disagreements.into_keys().collect();
+        return Err(AppError::EnsembleDisagreement(pointers));
+    }
+    if !disagreements.is_empty() {
+        warn!(pointers = ?disagreements.keys().collect::<Vec<_>>(), "ensemble targets disagree");
+    }
+
+    let output = match Output::validate(req.schema.as_ref(), merged) {
+        Ok(output) => output,
+        // Field-wise votes can add up to an object no target produced
+        Err(e) if ensemble.strategy == Strategy::Majority => {
+            warn!(error = %e, "merged ensemble output is invalid, using the most common output");
+            let whole = winner(&tally(&outputs)).expect("a target succeeded");
+            Output::validate(req.schema.as_ref(), whole)?
+        }
+        Err(e) => return Err(e),
+    };
+    let results = targets
+        .iter()
+        .zip(&results)
+        .map(|(target, result)| TargetResult::new(target, result.as_ref()))
+        .collect();
+    Ok(GenerateResponse {
+        output,
+        attempts: None,
+        served_by: None,
+        rule: None,
+        cached: None,
+        meta: None,
+        ensemble: Some(Box::new(EnsembleReport {
+            strategy: ensemble.strategy,
+            results,
+            disagreements,
+        })),
+    })
+}
+
+impl TargetResult {
+    fn new(target: &Target, result: Option<&Result<Execution, AppError>>) -> Self {
+        let mut entry = Self {
+            target: target.to_string(),
+            status: Status::Cancelled,
+            output: None,
+            error: None,
+            duration_ms: None,
+        };
+        match result {
+            Some(Ok(execution)) => {
+                entry.status = Status::Ok;
+                entry.output = match &execution.output {
+                    Output::Structured(output) => Some(output.clone()),
+                    Output::Text(text) => Some(Value::String(text.clone())),
+                };
+                entry.duration_ms = Some(execution.duration.as_millis() as u64);
+            }
+            Some(Err(e)) => {
+                entry.status = Status::Error;
+                entry.error = Some(e.into());
+            }
+            None => {}
+        }
+        entry
+    }
+}
+
+/// Escapes a key for use in a JSON pointer (RFC 6901).
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Votes on the value at `pointer`, given each target's output there, or
+/// `None` where a target left it out. Objects most targets produced are
+/// merged key by key; anything else is compared as a whole. Every pointer
+/// with more than one candidate ends up in `disagreements`.
+fn merge(
+    outputs: &[(String, Option<&Value>)],
+    pointer: &str,
+    disagreements: &mut BTreeMap<String, Vec<Vote>>,
+) -> Option<Value> {
+    let objects: Vec<&Map<String, Value>> = outputs
+        .iter()
+        .filter_map(|(_, v)| v.and_then(Value::as_object))
+        .collect();
+    let present = outputs.iter().filter(|(_, v)| v.is_some()).count();
+    if !objects.is_empty() && objects.len() == present && present * 2 >= outputs.len() {
+        let mut keys: Vec<&String> = Vec::new();
+        for key in objects.iter().flat_map(|o| o.keys()) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        let mut merged = Map::new();
+        for key in keys {
+            let children: Vec<(String, Option<&Value>)> = outputs
+                .iter()
+                .map(|(t, v)| (t.clone(), v.and_then(|v| v.get(key))))
+                .collect();
+            let child = format!("{pointer}/{}", escape(key));
+            if let Some(value) = merge(&children, &child, disagreements) {
+                merged.insert(key.clone(), value);
+            }
+        }
+        return Some(Value::Object(merged));
+    }
+
+    let votes = tally(outputs);
+    let winner = winner(&votes);
+    if votes.len() > 1 {
+        disagreements.insert(pointer.to_string(), votes);
+    }
+    winner
+}
+
+/// Groups the targets by the value they produced, compared as a whole.
+fn tally(outputs: &[(String, Option<&Value>)]) -> Vec<Vote> {
+    let mut votes: Vec<Vote> = Vec::new();
+    for (target, value) in outputs {
+        match votes.iter_mut().find(|v| v.value.as_ref() == *value) {
+            Some(vote) => vote.targets.push(target.clone()),
+            None => votes.push(Vote {
+                value: value.cloned(),
+                targets: vec![target.clone()],
+            }),
+        }
+    }
+    votes
+}
+
+/// The value with the most votes; ties go to the one seen first.
+fn winner(votes: &[Vote]) -> Option<Value> {
+    let mut best: Option<&Vote> = None;
+    for vote in votes {
+        if best.is_none_or(|b| vote.targets.len() > b.targets.len()) {
+            best = Some(vote);
+        }
+    }
+    best.and_then(|v| v.value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::executor::{CommandOutput, MockExecutor};
+    use crate::tests::{mock_executor, test_state, valid_schema, RaceExecutor};
+    use actix_web::{web, App};
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_merge_votes_per_field() {
+        let a = json!({"name": "Ann", "age": 30, "tags": ["x"], "address": {"city": "Oslo", "zip": "1"}});
+        let b = json!({"name": "Ann", "age": 31, "tags": ["x"], "address": {"city": "Oslo"}});
+        let c = json!({"name": "Anne", "age": 31, "address": {"city": "Bergen", "zip": "1"}});
+        let outputs = [
+            ("a".to_string(), Some(&a)),
+            ("b".to_string(), Some(&b)),
+            ("c".to_string(), Some(&c)),
+        ];
+
+        let mut disagreements = BTreeMap::new();
+        let merged = merge(&outputs, "", &mut disagreements).unwrap();
+        assert_eq!(
+            merged,
+            json!({"name": "Ann", "age": 31, "tags": ["x"], "address": {"city": "Oslo", "zip": "1"}})
+        );
+        assert_eq!(
+            disagreements.keys().collect::<Vec<_>>(),
+            ["/address/city", "/address/zip", "/age", "/name", "/tags"]
+        );
+        assert_eq!(
+            disagreements["/address/zip"],
+            [
+                Vote {
+                    value: Some(json!("1")),
+                    targets: vec!["a".into(), "c".into()],
+                },
+                Vote {
+                    value: None,
+                    targets: vec!["b".into()],
+                },
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_merge_falls_back_to_a_whole_output() {
+        let mut mock = MockExecutor::new();
+        mock.expect_run().returning(|program, args, _, _| {
+            let output = match program {
+                "gemini" => r#"{"a": 2, "b": 2, "c": 1}"#,
+                _ if args.iter().any(|a| a == "sonnet") => {
+                    r#"{"structured_output": {"a": 1, "b": 1, "c": 1}}"#
+                }
+                _ => r#"{"structured_output": {"a": 1, "b": 2, "c": 2}}"#,
+            };
+            Ok(CommandOutput {
+                stdout: output.into(),
+                stderr: String::new(),
+            })
+        });
+        // Field by field the majority is {"a": 1, "b": 2, "c": 1}, which no
+        // target produced and the schema rules out
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "a": {"type": "integer"},
+                "b": {"type": "integer"},
+                "c": {"type": "integer"}
+            },
+            "not": {"properties": {"a": {"const": 1}, "b": {"const": 2}, "c": {"const": 1}}}
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state(Arc::new(mock))))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/generate")
+            .set_json(json!({
+                "prompt": "hello",
+                "schema": schema,
+                "ensemble": {"targets": ["claude/sonnet", "claude", "gemini"]}
+            }))
+            .to_request();
+        let body: Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        // No output wins outright: the earlier target's is used
+        assert_eq!(body["output"], json!({"a": 1, "b": 1, "c": 1}), "{body}");
+    }
+
+    async fn post_ensemble(executor: Arc<RaceExecutor>, ensemble: Value) -> (u16, Value) {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state(executor)))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/generate")
+            .set_json(json!({
+                "prompt": "hello",
+                "schema": valid_schema(),
+                "ensemble": ensemble
+            }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        let status = resp.status().as_u16();
+        (status, actix_web::test::read_body_json(resp).await)
+    }
+
+    #[actix_web::test]
+    async fn test_strategies() {
+        let executor = Arc::new(RaceExecutor::new(Duration::ZERO));
+        let (status, body) = post_ensemble(
+            executor.clone(),
+            json!({"targets": ["claude/sonnet", "gemini", "codex"]}),
+        )
+        .await;
+        assert_eq!(status, 200);
+        // Tied: the earlier target wins
+        assert_eq!(body["output"]["message"], "from claude");
+        let report = &body["ensemble"];
+        assert_eq!(report["strategy"], "majority");
+        assert_eq!(report["results"][1]["output"]["message"], "from gemini");
+        assert_eq!(report["results"][2]["status"], "error");
+        assert_eq!(
+            report["results"][2]["error"]["code"],
+            "auto_model_not_supported"
+        );
+        assert_eq!(
+            report["disagreements"]["/message"][1]["targets"],
+            json!(["gemini/_auto"])
+        );
+
+        let (status, body) = post_ensemble(
+            executor,
+            json!({"targets": ["claude/sonnet", "gemini"], "strategy": "unanimous"}),
+        )
+        .await;
+        assert_eq!(status, 422);
+        assert_eq!(body["code"], "ensemble_disagreement");
+        assert!(body["error"].as_str().unwrap().ends_with("/message"));
+    }
+
+    #[actix_web::test]
+    async fn test_first_valid_cancels_the_rest() {
+        let executor = Arc::new(RaceExecutor::new(Duration::from_secs(5)));
+        let (status, body) = post_ensemble(
+            executor.clone(),
+            json!({"targets": ["claude/sonnet", "gemini"], "strategy": "first_valid"}),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["output"]["message"], "from gemini");
+        assert_eq!(body["ensemble"]["results"][0]["status"], "cancelled");
+        assert!(executor
+            .claude_cancelled
+            .load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_ensembles_are_rejected() {
+        let state = test_state(mock_executor());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let two = json!({"targets": ["claude/sonnet", "gemini"]});
+        for body in [
+            json!({"prompt": "hi", "ensemble": two}),
+            json!({"prompt": "hi", "schema": valid_schema(), "provider": "claude", "ensemble": two}),
+            json!({"prompt": "hi", "schema": valid_schema(), "ensemble": {"targets": ["claude"]}}),
+            json!({"prompt": "hi", "schema": valid_schema(), "ensemble": {"targets": ["gemini", "gemini/_auto"]}}),
+            json!({"prompt": "hi", "schema": valid_schema(), "ensemble": {"targets": ["claude", "gemini"], "strategy": "best"}}),
+            json!({"prompt": "hi", "schema": valid_schema(), "include_meta": true, "ensemble": two}),
+        ] {
+            let req = actix_web::test::TestRequest::post()
+                .uri("/generate")
+                .set_json(&body)
+                .to_request();
+            assert_eq!(
+                actix_web::test::call_service(&app, req).await.status(),
+                400,
+                "{body}"
+            );
+        }
+    }
+}
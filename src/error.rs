@@ -1,18 +1,49 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::{header, StatusCode};
 use actix_web::{HttpResponse, ResponseError};
 use serde::Serialize;
 use thiserror::Error;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ErrorResponse {
     pub error: String,
+    /// Machine-readable [`AppError::code`].
+    pub code: &'static str,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stderr: Option<String>,
+    /// Unix time at which an exhausted upstream quota resets, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resets_at: Option<u64>,
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum AppError {
     #[error("provider execution failed: {message}")]
-    ProviderExecution { message: String, stderr: String },
+    ProviderExecution {
+        message: String,
+        stderr: String,
+        stdout: String,
+    },
+
+    #[error("{provider} requires authentication: {message}")]
+    AuthenticationRequired { provider: String, message: String },
+
+    #[error("{provider} quota exceeded: {message}")]
+    QuotaExceeded {
+        provider: String,
+        message: String,
+        resets_at: Option<u64>,
+    },
+
+    #[error("{provider} does not know the model: {message}")]
+    UnknownModel { provider: String, message: String },
+
+    #[error("{provider} context too long: {message}")]
+    ContextTooLong { provider: String, message: String },
+
+    #[error("{provider} refused the request: {message}")]
+    ContentRefused { provider: String, message: String },
 
     #[error("provider not found: {0}")]
     ProviderNotFound(String),
@@ -44,75 +75,161 @@ pub enum AppError {
     #[error("output parse error: {message}")]
     OutputParse { message: String, stdout: String },
 
+    #[error("route not found: {0}")]
+    RouteNotFound(String),
+
+    #[error("no routing rule matches the request")]
+    NoMatchingRule,
+
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("job not found: {0}")]
+    JobNotFound(String),
+
+    #[error("missing or invalid API key")]
+    Unauthorized,
+
+    #[error("API key '{key}' may not use {target}")]
+    Forbidden { key: String, target: String },
+
+    #[error("rate limited: API key '{0}'")]
+    KeyRateLimited(String),
+
+    #[error("server is shutting down")]
+    ShuttingDown,
+
+    #[error("ensemble targets disagree at {}", .0.join(", "))]
+    EnsembleDisagreement(Vec<String>),
+
     #[error("output validation failed: {errors:?}")]
     OutputValidation {
         errors: Vec<String>,
         output: serde_json::Value,
     },
+
+    #[error("internal error: {0}")]
+    Internal(String),
 }
 
-impl ResponseError for AppError {
-    fn error_response(&self) -> HttpResponse {
-        let (status, response) = match self {
-            Self::ProviderExecution { message, stderr } => (
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-                ErrorResponse {
-                    error: message.clone(),
-                    stderr: Some(stderr.clone()),
-                },
-            ),
+impl AppError {
+    /// Stable snake_case identifier of the variant, used in metrics labels
+    /// and error responses.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ProviderExecution { .. } => "provider_execution",
+            Self::AuthenticationRequired { .. } => "authentication_required",
+            Self::QuotaExceeded { .. } => "quota_exceeded",
+            Self::UnknownModel { .. } => "unknown_model",
+            Self::ContextTooLong { .. } => "context_too_long",
+            Self::ContentRefused { .. } => "content_refused",
+            Self::ProviderNotFound(_) => "provider_not_found",
+            Self::ModelNotFound { .. } => "model_not_found",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::AutoModelNotSupported(_) => "auto_model_not_supported",
+            Self::Timeout { .. } => "timeout",
+            Self::InvalidSchema(_) => "invalid_schema",
+            Self::ConfigLoad(_) => "config_load",
+            Self::OutputParse { .. } => "output_parse",
+            Self::RouteNotFound(_) => "route_not_found",
+            Self::NoMatchingRule => "no_matching_rule",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::JobNotFound(_) => "job_not_found",
+            Self::Unauthorized => "unauthorized",
+            Self::Forbidden { .. } => "forbidden",
+            Self::KeyRateLimited(_) => "key_rate_limited",
+            Self::ShuttingDown => "shutting_down",
+            Self::OutputValidation { .. } => "output_validation",
+            Self::EnsembleDisagreement(_) => "ensemble_disagreement",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    /// HTTP status used whenever this error is reported to a client.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::ProviderExecution { .. }
+            | Self::ConfigLoad(_)
+            | Self::OutputParse { .. }
+            | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::ProviderNotFound(_)
             | Self::ModelNotFound { .. }
-            | Self::AutoModelNotSupported(_) => (
-                actix_web::http::StatusCode::BAD_REQUEST,
-                ErrorResponse {
-                    error: self.to_string(),
-                    stderr: None,
-                },
-            ),
-            Self::RateLimited { .. } => (
-                actix_web::http::StatusCode::TOO_MANY_REQUESTS,
-                ErrorResponse {
-                    error: self.to_string(),
-                    stderr: None,
-                },
-            ),
-            Self::Timeout { .. } => (
-                actix_web::http::StatusCode::GATEWAY_TIMEOUT,
-                ErrorResponse {
-                    error: self.to_string(),
-                    stderr: None,
-                },
-            ),
-            Self::InvalidSchema(_) => (
-                actix_web::http::StatusCode::BAD_REQUEST,
-                ErrorResponse {
-                    error: self.to_string(),
-                    stderr: None,
-                },
-            ),
-            Self::ConfigLoad(_) => (
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-                ErrorResponse {
-                    error: self.to_string(),
-                    stderr: None,
-                },
-            ),
-            Self::OutputParse { message, stdout } => (
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-                ErrorResponse {
-                    error: message.clone(),
-                    stderr: Some(stdout.clone()),
-                },
-            ),
-            Self::OutputValidation { errors, output } => (
-                actix_web::http::StatusCode::UNPROCESSABLE_ENTITY,
-                ErrorResponse {
-                    error: format!("output validation failed: {}", errors.join("; ")),
-                    stderr: Some(output.to_string()),
-                },
-            ),
+            | Self::AutoModelNotSupported(_)
+            | Self::RouteNotFound(_)
+            | Self::NoMatchingRule
+            | Self::InvalidRequest(_)
+            | Self::InvalidSchema(_)
+            | Self::UnknownModel { .. } => StatusCode::BAD_REQUEST,
+            Self::Unauthorized | Self::AuthenticationRequired { .. } => StatusCode::UNAUTHORIZED,
+            Self::Forbidden { .. } => StatusCode::FORBIDDEN,
+            Self::JobNotFound(_) => StatusCode::NOT_FOUND,
+            Self::ContextTooLong { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::OutputValidation { .. }
+            | Self::ContentRefused { .. }
+            | Self::EnsembleDisagreement(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::RateLimited { .. } | Self::KeyRateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::ShuttingDown | Self::QuotaExceeded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// HTTP status and body used whenever this error is reported to a client.
+    pub fn payload(&self) -> (StatusCode, ErrorResponse) {
+        let mut response = ErrorResponse {
+            error: self.to_string(),
+            code: self.code(),
+            stderr: None,
+            resets_at: None,
+        };
+        match self {
+            Self::ProviderExecution {
+                message, stderr, ..
+            } => {
+                response.error = message.clone();
+                response.stderr = Some(stderr.clone());
+            }
+            Self::OutputParse { message, stdout } => {
+                response.error = message.clone();
+                response.stderr = Some(stdout.clone());
+            }
+            Self::OutputValidation { errors, output } => {
+                response.error = format!("output validation failed: {}", errors.join("; "));
+                response.stderr = Some(output.to_string());
+            }
+            Self::QuotaExceeded { resets_at, .. } => response.resets_at = *resets_at,
+            _ => {}
+        }
+        (self.status(), response)
+    }
+
+    /// Seconds until an exhausted upstream quota resets, for `Retry-After`.
+    pub fn retry_after(&self) -> Option<u64> {
+        let Self::QuotaExceeded {
+            resets_at: Some(resets_at),
+            ..
+        } = self
+        else {
+            return None;
         };
-        HttpResponse::build(status).json(response)
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Some(resets_at.saturating_sub(now))
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let (status, response) = self.payload();
+        let mut builder = HttpResponse::build(status);
+        if let Some(secs) = self.retry_after() {
+            builder.insert_header((header::RETRY_AFTER, secs.to_string()));
+        }
+        builder.json(response)
     }
 }
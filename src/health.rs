@@ -0,0 +1,440 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpResponse};
+use futures_util::future::join_all;
+use serde::Serialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::config::{HealthConfig, AUTO_MODEL};
+use crate::error::AppError;
+use crate::provider::classify::classify;
+use crate::provider::{get_provider_with_executor, Backend, Message};
+use crate::AppState;
+
+const VERSION_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Installed, but `--version`, the API ping or the probe failed for
+    /// another reason.
+    Degraded,
+    Unauthenticated,
+    /// The binary is not on `PATH`.
+    Missing,
+}
+
+impl Status {
+    fn is_usable(self) -> bool {
+        matches!(self, Self::Ok | Self::Degraded)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    pub ok: bool,
+    /// The provider's rate limits were full, so no probe was sent.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program: Option<String>,
+    /// Base URL of HTTP API providers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<ProbeResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Unix time of the check.
+    pub checked_at: u64,
+}
+
+/// Latest check result per provider. Empty until the first check finished.
+#[derive(Default)]
+pub struct HealthStore {
+    providers: RwLock<Option<BTreeMap<String, ProviderHealth>>>,
+}
+
+impl HealthStore {
+    pub fn snapshot(&self) -> Option<BTreeMap<String, ProviderHealth>> {
+        self.providers.read().unwrap().clone()
+    }
+
+    fn set(&self, providers: BTreeMap<String, ProviderHealth>) {
+        *self.providers.write().unwrap() = Some(providers);
+    }
+}
+
+/// Looks `program` up like a shell would.
+fn find_program(program: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    };
+
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+fn status_of(e: &AppError) -> Status {
+    match e {
+        AppError::AuthenticationRequired { .. } => Status::Unauthenticated,
+        _ => Status::Degraded,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+async fn check_provider(state: &AppState, name: &str, config: &HealthConfig) -> ProviderHealth {
+    let current = state.settings();
+    let settings = current.provider_settings.get(name);
+    let provider = get_provider_with_executor(name, settings, state.executor.clone());
+    let mut health = ProviderHealth {
+        status: Status::Ok,
+        program: None,
+        url: None,
+        path: None,
+        version: None,
+        probe: None,
+        error: None,
+        checked_at: unix_now(),
+    };
+
+    let Some(provider) = provider else {
+        health.program = Some(name.to_string());
+        health.status = Status::Missing;
+        health.error = Some(format!("no provider implementation for '{name}'"));
+        return health;
+    };
+    match provider.backend() {
+        Backend::Program(program) => {
+            health.program = Some(program.to_string());
+            let Some(path) = find_program(program) else {
+                health.status = Status::Missing;
+                health.error = Some(format!("'{program}' not found on PATH"));
+                return health;
+            };
+            health.path = Some(path.display().to_string());
+
+            match state
+                .executor
+                .run(
+                    program,
+                    &["--version".to_string()],
+                    "",
+                    Some(VERSION_TIMEOUT_SECS),
+                )
+                .await
+            {
+                Ok(output) => health.version = Some(output.stdout.trim().to_string()),
+                Err(e) => {
+                    let e = classify(name, provider.signatures(), e);
+                    health.status = status_of(&e);
+                    health.error = Some(e.to_string());
+                    return health;
+                }
+            }
+        }
+        Backend::Url(url) => {
+            health.url = Some(url.to_string());
+            if let Err(e) = provider.ping(VERSION_TIMEOUT_SECS).await {
+                health.status = status_of(&e);
+                health.error = Some(e.to_string());
+                return health;
+            }
+        }
+    }
+
+    if config.probe {
+        // Providers without auto model selection are probed with their
+        // first configured model.
+        let model = if settings.is_none_or(|s| s.supports_auto_model) {
+            None
+        } else {
+            current
+                .model_settings
+                .keys()
+                .filter(|(provider, _)| provider == name)
+                .map(|(_, model)| model.clone())
+                .min()
+        };
+        // Probes count against the provider's limits but never wait for
+        // them, so they cannot crowd out real requests.
+        let Ok(_guard) = state
+            .rate_limiter
+            .try_acquire(name, model.as_deref().unwrap_or(AUTO_MODEL))
+        else {
+            health.probe = Some(ProbeResult {
+                ok: false,
+                skipped: true,
+                latency_ms: 0,
+                error: None,
+            });
+            return health;
+        };
+        let started = Instant::now();
+        let result = provider
+            .execute(
+                &[Message::user(config.probe_prompt.as_str())],
+                None,
+                model.as_deref(),
+                Some(config.probe_timeout_secs),
+            )
+            .await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        health.probe = Some(match result {
+            Ok(_) => ProbeResult {
+                ok: true,
+                skipped: false,
+                latency_ms,
+                error: None,
+            },
+            Err(e) => {
+                health.status = status_of(&e);
+                ProbeResult {
+                    ok: false,
+                    skipped: false,
+                    latency_ms,
+                    error: Some(e.to_string()),
+                }
+            }
+        });
+    }
+
+    health
+}
+
+/// Checks every configured provider concurrently and stores the results.
+pub async fn check_all(state: &AppState, config: &HealthConfig) {
+    let names: Vec<String> = state.settings().provider_settings.keys().cloned().collect();
+    let checks = names.into_iter().map(|name| async move {
+        let health = check_provider(state, &name, config).await;
+        (name, health)
+    });
+    let mut results = BTreeMap::new();
+    for (name, health) in join_all(checks).await {
+        if health.status != Status::Ok {
+            warn!(provider = %name, status = ?health.status, error = ?health.error, "provider unhealthy");
+        }
+        results.insert(name, health);
+    }
+    state.health.set(results);
+}
+
+/// Re-runs `check_all` every `interval_secs` in the background.
+pub fn spawn_checker(state: Arc<AppState>, config: HealthConfig) {
+    info!(
+        interval_secs = config.interval_secs,
+        probe = config.probe,
+        "starting provider health checks"
+    );
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            check_all(&state, &config).await;
+        }
+    });
+}
+
+/// `GET /ready`: fails while draining, before the first health check and
+/// when no provider is usable.
+pub async fn ready(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    if state.draining.load(Ordering::SeqCst) {
+        return HttpResponse::ServiceUnavailable().json(json!({"status": "draining"}));
+    }
+    match state.health.snapshot() {
+        None => HttpResponse::ServiceUnavailable().json(json!({"status": "starting"})),
+        Some(providers) if providers.values().any(|p| p.status.is_usable()) => {
+            HttpResponse::Ok().json(json!({"status": "ready"}))
+        }
+        Some(_) => HttpResponse::ServiceUnavailable().json(json!({"status": "no usable provider"})),
+    }
+}
+
+/// `GET /health/providers`: the latest check result for every provider.
+pub async fn providers(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "providers": state.health.snapshot().unwrap_or_default(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProviderKind;
+    use crate::provider::executor::{CommandOutput, MockExecutor};
+    use crate::tests::test_state_with;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    /// Every provider runs `program` instead of its real CLI.
+    fn state_with_program(executor: MockExecutor, program: &str) -> Arc<AppState> {
+        let spec = format!("program = {program:?}");
+        test_state_with(Arc::new(executor), |state| {
+            let current = Arc::make_mut(state.settings.get_mut().unwrap());
+            for settings in current.provider_settings.values_mut() {
+                settings.cli = Some(toml::from_str(&spec).unwrap());
+            }
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_missing_binary() {
+        let state = state_with_program(MockExecutor::new(), "llm-mux-no-such-cli");
+        let health = check_provider(&state, "claude", &HealthConfig::default()).await;
+        assert_eq!(health.status, Status::Missing);
+        assert!(health.version.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_ready_and_provider_report() {
+        let mut mock = MockExecutor::new();
+        mock.expect_run().returning(|_, args, _, _| {
+            if args == ["--version"] {
+                Ok(CommandOutput {
+                    stdout: "1.2.3\n".into(),
+                    stderr: String::new(),
+                })
+            } else {
+                Err(AppError::ProviderExecution {
+                    message: "exited with status: 1".into(),
+                    stderr: "invalid API key".into(),
+                    stdout: String::new(),
+                })
+            }
+        });
+        // `sh` is on PATH everywhere the tests run
+        let state = state_with_program(mock, "sh");
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .route("/ready", web::get().to(ready))
+                .route("/health/providers", web::get().to(providers)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 503);
+
+        check_all(&state, &HealthConfig::default()).await;
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let req = test::TestRequest::get()
+            .uri("/health/providers")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["providers"]["claude"]["status"], "ok");
+        assert_eq!(body["providers"]["claude"]["version"], "1.2.3");
+
+        // A failing probe marks every provider unauthenticated
+        let config = HealthConfig {
+            probe: true,
+            ..Default::default()
+        };
+        check_all(&state, &config).await;
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 503);
+        let req = test::TestRequest::get()
+            .uri("/health/providers")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["providers"]["gemini"]["status"], "unauthenticated");
+        assert_eq!(body["providers"]["gemini"]["probe"]["ok"], false);
+    }
+
+    #[actix_web::test]
+    async fn test_probe_is_skipped_when_limits_are_full() {
+        let mut mock = MockExecutor::new();
+        mock.expect_run()
+            .withf(|_, args, _, _| args == ["--version"])
+            .returning(|_, _, _, _| {
+                Ok(CommandOutput {
+                    stdout: "1.2.3\n".into(),
+                    stderr: String::new(),
+                })
+            });
+        let state = state_with_program(mock, "sh");
+        let _slots = [
+            state
+                .rate_limiter
+                .try_acquire("claude", AUTO_MODEL)
+                .unwrap(),
+            state
+                .rate_limiter
+                .try_acquire("claude", AUTO_MODEL)
+                .unwrap(),
+        ];
+        let config = HealthConfig {
+            probe: true,
+            ..Default::default()
+        };
+        let health = check_provider(&state, "claude", &config).await;
+        assert_eq!(health.status, Status::Ok);
+        let probe = health.probe.unwrap();
+        assert!(probe.skipped);
+        assert!(!probe.ok);
+    }
+
+    #[actix_web::test]
+    async fn test_version_failures_use_provider_signatures() {
+        let provider =
+            get_provider_with_executor("claude", None, Arc::new(MockExecutor::new())).unwrap();
+        let error = AppError::ProviderExecution {
+            message: "exited with status: 1".into(),
+            stderr: "Please run /login".into(),
+            stdout: String::new(),
+        };
+        let e = classify("claude", provider.signatures(), error);
+        assert_eq!(status_of(&e), Status::Unauthenticated);
+    }
+
+    #[actix_web::test]
+    async fn test_http_provider_is_pinged() {
+        // Nothing listens on the discard port
+        let state = test_state_with(Arc::new(MockExecutor::new()), |state| {
+            let current = Arc::make_mut(state.settings.get_mut().unwrap());
+            let settings = current.provider_settings.get_mut("claude").unwrap();
+            settings.kind = ProviderKind::Openai;
+            settings.http = Some(
+                toml::from_str("base_url = \"http://127.0.0.1:9/v1\"\ndefault_model = \"m\"")
+                    .unwrap(),
+            );
+        });
+        let health = check_provider(&state, "claude", &HealthConfig::default()).await;
+        assert_eq!(health.url.as_deref(), Some("http://127.0.0.1:9/v1"));
+        assert!(health.program.is_none());
+        assert_eq!(health.status, Status::Degraded);
+        assert!(health.error.unwrap().contains("127.0.0.1:9"));
+    }
+}
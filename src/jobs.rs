@@ -0,0 +1,415 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpResponse};
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::task::AbortHandle;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth::ApiKey;
+use crate::dispatch::{dispatch, resolve, Execution};
+use crate::error::{AppError, ErrorResponse};
+use crate::rate_limiter::Wait;
+use crate::{AppState, GenerateRequest, Output};
+
+/// Finished jobs are kept this long so clients can still fetch the result.
+const JOB_RETENTION: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        !matches!(self, Self::Queued | Self::Running)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobError {
+    pub status: u16,
+    #[serde(flatten)]
+    pub body: ErrorResponse,
+}
+
+impl From<&AppError> for JobError {
+    fn from(e: &AppError) -> Self {
+        let (status, body) = e.payload();
+        Self {
+            status: status.as_u16(),
+            body,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobView {
+    pub id: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
+    #[serde(flatten)]
+    pub output: Option<Output>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JobError>,
+}
+
+struct Job {
+    view: JobView,
+    /// Name of the API key that created the job. Other keys cannot see it.
+    owner: Option<String>,
+    finished_at: Option<Instant>,
+    handle: Option<AbortHandle>,
+}
+
+impl Job {
+    fn owned_by(&self, caller: Option<&ApiKey>) -> bool {
+        self.owner.as_deref() == caller.map(|key| key.name.as_str())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct JobStore {
+    jobs: Arc<DashMap<String, Job>>,
+}
+
+impl JobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&self, req: &GenerateRequest, owner: Option<&ApiKey>) -> JobView {
+        self.prune();
+
+        let view = JobView {
+            id: Uuid::new_v4().to_string(),
+            status: JobStatus::Queued,
+            provider: req.provider.clone(),
+            model: req.model.clone(),
+            route: req.route.clone(),
+            served_by: None,
+            output: None,
+            error: None,
+        };
+        self.jobs.insert(
+            view.id.clone(),
+            Job {
+                view: view.clone(),
+                owner: owner.map(|key| key.name.clone()),
+                finished_at: None,
+                handle: None,
+            },
+        );
+        view
+    }
+
+    fn set_handle(&self, id: &str, handle: AbortHandle) {
+        if let Some(mut job) = self.jobs.get_mut(id) {
+            job.handle = Some(handle);
+        }
+    }
+
+    fn set_running(&self, id: &str) {
+        if let Some(mut job) = self.jobs.get_mut(id) {
+            if job.view.status == JobStatus::Queued {
+                job.view.status = JobStatus::Running;
+            }
+        }
+    }
+
+    fn finish(&self, id: &str, result: Result<Execution, AppError>) {
+        if let Some(mut job) = self.jobs.get_mut(id) {
+            if job.view.status.is_finished() {
+                return;
+            }
+            match result {
+                Ok(execution) => {
+                    job.view.status = JobStatus::Succeeded;
+                    job.view.served_by = Some(execution.target.to_string());
+                    job.view.output = Some(execution.output);
+                }
+                Err(e) => {
+                    job.view.status = JobStatus::Failed;
+                    job.view.error = Some(JobError::from(&e));
+                }
+            }
+            job.finished_at = Some(Instant::now());
+            job.handle = None;
+        }
+    }
+
+    /// The job, if it exists and was created by `caller`.
+    pub fn get(&self, id: &str, caller: Option<&ApiKey>) -> Option<JobView> {
+        self.jobs
+            .get(id)
+            .filter(|job| job.owned_by(caller))
+            .map(|job| job.view.clone())
+    }
+
+    /// Aborts the job if it is still queued or running and was created by
+    /// `caller`. Aborting drops the execution future, which kills the CLI
+    /// child process.
+    pub fn cancel(&self, id: &str, caller: Option<&ApiKey>) -> Option<JobView> {
+        let mut job = self.jobs.get_mut(id).filter(|job| job.owned_by(caller))?;
+        if !job.view.status.is_finished() {
+            if let Some(handle) = job.handle.take() {
+                handle.abort();
+            }
+            job.view.status = JobStatus::Cancelled;
+            job.finished_at = Some(Instant::now());
+        }
+        Some(job.view.clone())
+    }
+
+    fn prune(&self) {
+        self.jobs.retain(|_, job| {
+            job.finished_at
+                .is_none_or(|finished| finished.elapsed() < JOB_RETENTION)
+        });
+    }
+}
+
+/// `POST /jobs`: validates the request, then runs it in the background.
+pub async fn create_job(
+    state: web::Data<Arc<AppState>>,
+    caller: Option<web::ReqData<Arc<ApiKey>>>,
+    req: web::Json<GenerateRequest>,
+) -> Result<HttpResponse, AppError> {
+    req.validate()?;
+
+    let caller = caller.map(web::ReqData::into_inner);
+    let plan = req.plan(&state, caller.as_deref())?;
+    for target in &plan.targets {
+        resolve(&state, caller.as_deref(), target)?;
+    }
+    let req = req.into_inner();
+    let view = state.jobs.insert(&req, caller.as_deref());
+
+    info!(job_id = %view.id, provider = ?req.provider, model = ?req.model, route = ?req.route, "job queued");
+
+    let id = view.id.clone();
+    let state = state.get_ref().clone();
+    let handle = tokio::spawn({
+        let state = state.clone();
+        async move {
+            let result = dispatch(
+                &state,
+                caller.as_deref(),
+                &plan.targets,
+                &req,
+                Wait::Queue(None),
+                plan.hedge_after,
+                |_| state.jobs.set_running(&id),
+            )
+            .await;
+            info!(job_id = %id, ok = result.is_ok(), "job finished");
+            state.jobs.finish(&id, result);
+        }
+    });
+    state.jobs.set_handle(&view.id, handle.abort_handle());
+
+    Ok(HttpResponse::Accepted().json(view))
+}
+
+/// `GET /jobs/{id}`
+pub async fn get_job(
+    state: web::Data<Arc<AppState>>,
+    caller: Option<web::ReqData<Arc<ApiKey>>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let view = state
+        .jobs
+        .get(&id, caller.as_deref().map(Arc::as_ref))
+        .ok_or_else(|| AppError::JobNotFound(id.into_inner()))?;
+    Ok(HttpResponse::Ok().json(view))
+}
+
+/// `DELETE /jobs/{id}`
+pub async fn cancel_job(
+    state: web::Data<Arc<AppState>>,
+    caller: Option<web::ReqData<Arc<ApiKey>>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let view = state
+        .jobs
+        .cancel(&id, caller.as_deref().map(Arc::as_ref))
+        .ok_or_else(|| AppError::JobNotFound(id.into_inner()))?;
+    info!(job_id = %view.id, status = ?view.status, "job cancel requested");
+    Ok(HttpResponse::Ok().json(view))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{mock_executor, test_state, test_state_with, valid_schema};
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    macro_rules! jobs_app {
+        ($state:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new($state))
+                    .route("/jobs", web::post().to(create_job))
+                    .route("/jobs/{id}", web::get().to(get_job))
+                    .route("/jobs/{id}", web::delete().to(cancel_job)),
+            )
+            .await
+        };
+    }
+
+    #[actix_web::test]
+    async fn test_job_lifecycle() {
+        let app = jobs_app!(test_state(mock_executor()));
+
+        let req = test::TestRequest::post()
+            .uri("/jobs")
+            .set_json(json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(created["status"], "queued");
+        let id = created["id"].as_str().unwrap().to_string();
+
+        let mut job = Value::Null;
+        for _ in 0..50 {
+            let req = test::TestRequest::get()
+                .uri(&format!("/jobs/{id}"))
+                .to_request();
+            job = test::call_and_read_body_json(&app, req).await;
+            if job["status"] == "succeeded" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(job["status"], "succeeded");
+        assert_eq!(job["output"], json!({"message": "hello"}));
+    }
+
+    #[actix_web::test]
+    async fn test_job_rejects_invalid_request_upfront() {
+        let app = jobs_app!(test_state(mock_executor()));
+
+        let req = test::TestRequest::post()
+            .uri("/jobs")
+            .set_json(json!({
+                "provider": "claude",
+                "model": "unknown-model",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_job_not_found() {
+        let app = jobs_app!(test_state(mock_executor()));
+
+        let req = test::TestRequest::get().uri("/jobs/missing").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_cancel_marks_job_cancelled() {
+        let store = JobStore::new();
+        let req: GenerateRequest = serde_json::from_value(json!({
+            "provider": "claude",
+            "prompt": "hello"
+        }))
+        .unwrap();
+        let view = store.insert(&req, None);
+
+        let cancelled = store.cancel(&view.id, None).unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+
+        // A late result from the aborted task must not overwrite the cancellation
+        store.finish(
+            &view.id,
+            Ok(Execution {
+                target: crate::config::Target::parse("claude"),
+                output: Output::Structured(json!({})),
+                attempts: 1,
+                model: None,
+                duration: Duration::ZERO,
+                queue_wait: Duration::ZERO,
+                usage: None,
+                shared: false,
+            }),
+        );
+        assert_eq!(
+            store.get(&view.id, None).unwrap().status,
+            JobStatus::Cancelled
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_jobs_are_private_to_their_key() {
+        let keys: Vec<crate::config::ApiKeyConfig> = toml::from_str::<crate::config::AuthConfig>(
+            r#"
+            [[keys]]
+            name = "alice"
+            key = "alice-secret"
+
+            [[keys]]
+            name = "bob"
+            key = "bob-secret"
+            "#,
+        )
+        .unwrap()
+        .keys;
+        let state = test_state_with(mock_executor(), |state| {
+            state.api_keys = crate::auth::ApiKeys::new(&keys);
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(actix_web::middleware::from_fn(crate::auth::authenticate))
+                .route("/jobs", web::post().to(create_job))
+                .route("/jobs/{id}", web::get().to(get_job))
+                .route("/jobs/{id}", web::delete().to(cancel_job)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/jobs")
+            .insert_header(("X-API-Key", "alice-secret"))
+            .set_json(json!({"provider": "claude", "prompt": "hello"}))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        let uri = format!("/jobs/{}", created["id"].as_str().unwrap());
+
+        for req in [test::TestRequest::get(), test::TestRequest::delete()] {
+            let req = req
+                .uri(&uri)
+                .insert_header(("X-API-Key", "bob-secret"))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 404);
+        }
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("X-API-Key", "alice-secret"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+}
@@ -1,41 +1,342 @@
+mod auth;
+mod batch;
+mod cache;
 mod config;
+mod dispatch;
+mod ensemble;
 mod error;
+mod health;
+mod jobs;
+mod metrics;
+mod openai;
 mod provider;
 mod rate_limiter;
+mod reload;
+mod router;
 mod schema;
+mod shutdown;
+mod singleflight;
+mod stream;
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web::middleware::from_fn;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tracing::{info, warn};
+use tracing::{error, info, info_span, warn, Instrument};
 use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
 
-use crate::config::{Config, ModelSettings, ProviderSettings};
+use crate::auth::{ApiKey, ApiKeys};
+use crate::cache::{CacheControl, ResponseCache};
+use crate::config::{Config, Settings, Target, AUTO_PROVIDER};
+use crate::dispatch::{dispatch, Execution};
+use crate::ensemble::{Ensemble, EnsembleReport};
 use crate::error::AppError;
-use crate::provider::{get_provider_with_executor, CliExecutor, Executor};
-use crate::rate_limiter::RateLimiter;
+use crate::health::HealthStore;
+use crate::jobs::JobStore;
+use crate::metrics::Metrics;
+use crate::provider::{ChildProcesses, CliExecutor, Executor, Message, Role, Usage};
+use crate::rate_limiter::{RateLimiter, Wait};
+use crate::singleflight::Singleflight;
 
 #[derive(Debug, Deserialize)]
 struct GenerateRequest {
-    provider: String,
+    #[serde(default)]
+    provider: Option<String>,
+    #[serde(default)]
     model: Option<String>,
-    prompt: String,
-    schema: Value,
+    /// Name of a `[[routes]]` entry, used instead of `provider`/`model`.
+    #[serde(default)]
+    route: Option<String>,
+    /// Hints such as `fast` or `cheap` for `provider: "auto"`, matched
+    /// against the `tags` of `[[routing.rules]]`.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Targets to run concurrently instead of `provider`/`route`, and how to
+    /// merge their outputs.
+    #[serde(default)]
+    ensemble: Option<Ensemble>,
+    #[serde(default)]
+    prompt: Option<String>,
+    /// A conversation to send instead of `prompt`, ending with a user turn.
+    #[serde(default)]
+    messages: Vec<Message>,
+    /// JSON Schema for structured output. Omit for free-text output.
+    #[serde(default)]
+    schema: Option<Value>,
+    /// Lowers the provider's `max_repair_attempts`.
+    #[serde(default)]
+    max_repair_attempts: Option<u32>,
+    /// Adds a `meta` object with timing and usage to the response.
+    #[serde(default)]
+    include_meta: bool,
+}
+
+impl GenerateRequest {
+    fn validate(&self) -> Result<(), AppError> {
+        match (&self.prompt, self.messages.last()) {
+            (Some(_), None) => {}
+            (None, Some(last)) if last.role == Role::User => {}
+            (None, Some(_)) => {
+                return Err(AppError::InvalidRequest(
+                    "the last of \"messages\" must have role \"user\"".into(),
+                ))
+            }
+            (Some(_), Some(_)) => {
+                return Err(AppError::InvalidRequest(
+                    "\"prompt\" cannot be combined with \"messages\"".into(),
+                ))
+            }
+            (None, None) => {
+                return Err(AppError::InvalidRequest(
+                    "one of \"prompt\" or \"messages\" is required".into(),
+                ))
+            }
+        }
+        if let Some(ensemble) = &self.ensemble {
+            ensemble.validate(self)?;
+        }
+        match &self.schema {
+            Some(schema) => schema::validate_structured_schema(schema),
+            None => Ok(()),
+        }
+    }
+
+    /// The number of repair attempts to make: the request's, capped at the
+    /// provider's `configured` one.
+    fn repair_attempts(&self, configured: u32) -> u32 {
+        self.max_repair_attempts
+            .map_or(configured, |requested| requested.min(configured))
+    }
+
+    /// The conversation to send: `messages`, or `prompt` as a lone user
+    /// message.
+    fn conversation(&self) -> Cow<'_, [Message]> {
+        match &self.prompt {
+            Some(prompt) => Cow::Owned(vec![Message::user(prompt.as_str())]),
+            None => Cow::Borrowed(&self.messages),
+        }
+    }
+
+    /// The provider/model pairs to try, in order.
+    fn plan(&self, state: &AppState, caller: Option<&ApiKey>) -> Result<Plan, AppError> {
+        if self.ensemble.is_some() {
+            return Err(AppError::InvalidRequest(
+                "\"ensemble\" is only supported by /generate and /batch".into(),
+            ));
+        }
+        let auto = self.provider.as_deref() == Some(AUTO_PROVIDER);
+        if !self.tags.is_empty() && !auto {
+            return Err(AppError::InvalidRequest(format!(
+                "\"tags\" require provider \"{AUTO_PROVIDER}\""
+            )));
+        }
+        match (&self.provider, &self.route) {
+            (Some(_), None) if auto && self.model.is_some() => Err(AppError::InvalidRequest(
+                format!("\"model\" cannot be combined with provider \"{AUTO_PROVIDER}\""),
+            )),
+            (Some(_), None) if auto => {
+                let routed = router::route(
+                    &state.settings().routing,
+                    &state.rate_limiter,
+                    &router::Features::of(self),
+                    caller,
+                )?;
+                info!(rule = %routed.rule, "routing rule matched");
+                Ok(Plan {
+                    targets: routed.targets,
+                    rule: Some(routed.rule),
+                    hedge_after: None,
+                })
+            }
+            (Some(provider), None) => Ok(Plan::single(Target {
+                provider: provider.clone(),
+                model: self.model.clone(),
+            })),
+            (None, Some(route)) if self.model.is_none() => state
+                .settings()
+                .routes
+                .get(route)
+                .cloned()
+                .map(|route| Plan {
+                    targets: route.targets,
+                    rule: None,
+                    hedge_after: route.hedge_after,
+                })
+                .ok_or_else(|| AppError::RouteNotFound(route.clone())),
+            (None, Some(_)) => Err(AppError::InvalidRequest(
+                "\"model\" cannot be combined with \"route\"".into(),
+            )),
+            _ => Err(AppError::InvalidRequest(
+                "exactly one of \"provider\" or \"route\" is required".into(),
+            )),
+        }
+    }
+}
+
+/// The targets a request is dispatched to.
+struct Plan {
+    targets: Vec<Target>,
+    /// The `[[routing.rules]]` entry that picked the targets, for
+    /// `provider: "auto"`.
+    rule: Option<String>,
+    /// The route's hedging delay, if it hedges.
+    hedge_after: Option<Duration>,
+}
+
+impl Plan {
+    fn single(target: Target) -> Self {
+        Self {
+            targets: vec![target],
+            rule: None,
+            hedge_after: None,
+        }
+    }
+
+    /// Whether the client left the choice of target to llm-mux.
+    fn reports_target(&self, req: &GenerateRequest) -> bool {
+        req.route.is_some() || self.rule.is_some()
+    }
+}
+
+/// Serializes as `"output": {...}` for structured requests and as
+/// `"text": "..."` in free-text mode.
+#[derive(Debug, Clone, Serialize)]
+enum Output {
+    #[serde(rename = "output")]
+    Structured(Value),
+    #[serde(rename = "text")]
+    Text(String),
+}
+
+impl Output {
+    /// Checks a provider's output against the request schema, if there is one.
+    fn validate(schema: Option<&Value>, output: Value) -> Result<Self, AppError> {
+        match (schema, output) {
+            (Some(schema), output) => {
+                schema::validate_output(schema, &output)?;
+                Ok(Self::Structured(output))
+            }
+            (None, Value::String(text)) => Ok(Self::Text(text)),
+            (None, output) => Ok(Self::Text(output.to_string())),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 struct GenerateResponse {
-    output: Value,
+    #[serde(flatten)]
+    output: Output,
+    /// Number of CLI invocations used; only reported when repair is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attempts: Option<u32>,
+    /// The target that produced the output; only reported for routes and
+    /// `provider: "auto"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    served_by: Option<String>,
+    /// The routing rule that picked the targets of `provider: "auto"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<String>,
+    /// Whether the output came from the response cache; only reported when
+    /// caching applies to the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached: Option<bool>,
+    /// Only reported when the request sets `include_meta`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<Meta>,
+    /// Per-target results of an ensemble request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ensemble: Option<Box<EnsembleReport>>,
+}
+
+/// Where and how a response was produced.
+#[derive(Debug, Serialize)]
+struct Meta {
+    request_id: String,
+    provider: String,
+    /// The model reported by the CLI, else the requested one. `None` for
+    /// auto model selection with CLIs that do not report it.
+    model: Option<String>,
+    /// Time spent in CLI runs, summed over all attempts.
+    duration_ms: u64,
+    /// Time spent waiting for the rate limiter.
+    queue_ms: u64,
+    attempts: u32,
+    /// Token usage and cost, if the CLI reports them. Left out when the
+    /// output was shared, as the request that ran the CLI reports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+    /// The output came from an identical request already in flight.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    shared: bool,
+}
+
+impl Meta {
+    fn new(request_id: &str, execution: &Execution) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            provider: execution.target.provider.clone(),
+            model: execution
+                .model
+                .clone()
+                .or_else(|| execution.target.model.clone()),
+            duration_ms: execution.duration.as_millis() as u64,
+            queue_ms: execution.queue_wait.as_millis() as u64,
+            attempts: execution.attempts,
+            usage: execution.usage.clone().filter(|_| !execution.shared),
+            shared: execution.shared,
+        }
+    }
+
+    /// A cache hit ran no CLI.
+    fn cached(request_id: &str, target: &Target) -> Self {
+        Self {
+            request_id: request_id.to_string(),
+            provider: target.provider.clone(),
+            model: target.model.clone(),
+            duration_ms: 0,
+            queue_ms: 0,
+            attempts: 0,
+            usage: None,
+            shared: false,
+        }
+    }
 }
 
 struct AppState {
     executor: Arc<dyn Executor>,
     rate_limiter: RateLimiter,
-    model_settings: HashMap<(String, String), ModelSettings>,
-    provider_settings: HashMap<String, ProviderSettings>,
+    /// Replaced as a whole when the config is reloaded.
+    settings: RwLock<Arc<Settings>>,
+    jobs: JobStore,
+    metrics: Metrics,
+    api_keys: ApiKeys,
+    cache: Option<ResponseCache>,
+    inflight: Singleflight<Result<Execution, AppError>>,
+    /// Set on shutdown: new executions are refused and `/ready` fails.
+    draining: AtomicBool,
+    health: HealthStore,
+}
+
+impl AppState {
+    /// A snapshot of the current providers, models and routes.
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Fails once shutdown has started draining.
+    fn check_draining(&self) -> Result<(), AppError> {
+        if self.draining.load(std::sync::atomic::Ordering::SeqCst) {
+            Err(AppError::ShuttingDown)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 async fn health() -> HttpResponse {
@@ -44,80 +345,169 @@ async fn health() -> HttpResponse {
 
 async fn generate(
     state: web::Data<Arc<AppState>>,
+    caller: Option<web::ReqData<Arc<ApiKey>>>,
+    http_req: HttpRequest,
     req: web::Json<GenerateRequest>,
 ) -> Result<HttpResponse, AppError> {
-    schema::validate_structured_schema(&req.schema)?;
-
-    let provider = get_provider_with_executor(&req.provider, state.executor.clone())
-        .ok_or_else(|| AppError::ProviderNotFound(req.provider.clone()))?;
-
-    let (timeout_secs, _guard) = match &req.model {
-        Some(model) => {
-            let key = (req.provider.clone(), model.clone());
-            if !state.model_settings.contains_key(&key) {
-                return Err(AppError::ModelNotFound {
-                    provider: req.provider.clone(),
-                    model: req.model.clone(),
-                });
-            }
+    req.validate()?;
 
-            let guard = state
-                .rate_limiter
-                .try_acquire(&req.provider, model)
-                .map_err(|()| AppError::RateLimited {
-                    provider: req.provider.clone(),
-                    model: req.model.clone(),
-                })?;
-
-            let timeout = state.model_settings.get(&key).and_then(|s| s.timeout_secs);
-            (timeout, Some(guard))
-        }
-        None => {
-            let provider_cfg = state.provider_settings.get(&req.provider);
+    let response = respond(
+        &state,
+        caller.as_deref().map(Arc::as_ref),
+        &req,
+        CacheControl::from_request(&http_req),
+        Wait::Queue(None),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(response))
+}
 
-            let supports_auto = provider_cfg.map(|p| p.supports_auto_model).unwrap_or(true);
+/// Answers a validated request from the cache, or by dispatching it to its
+/// targets. Everything logged on the way carries the request's id.
+async fn respond(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    req: &GenerateRequest,
+    control: CacheControl,
+    wait: Wait,
+) -> Result<GenerateResponse, AppError> {
+    let request_id = Uuid::new_v4().to_string();
+    let span = info_span!("generate", %request_id);
+    respond_as(state, caller, req, control, wait, &request_id)
+        .instrument(span)
+        .await
+}
 
-            if !supports_auto {
-                return Err(AppError::AutoModelNotSupported(req.provider.clone()));
-            }
+async fn respond_as(
+    state: &AppState,
+    caller: Option<&ApiKey>,
+    req: &GenerateRequest,
+    control: CacheControl,
+    wait: Wait,
+    request_id: &str,
+) -> Result<GenerateResponse, AppError> {
+    if let Some(ensemble) = &req.ensemble {
+        return ensemble::respond(state, caller, req, ensemble, wait).await;
+    }
+    let plan = req.plan(state, caller)?;
+    let targets = &plan.targets;
+    let reports_target = plan.reports_target(req);
 
-            // Use provider-level rate limit for auto model
-            let guard = if provider_cfg.is_some() {
-                state
-                    .rate_limiter
-                    .try_acquire(&req.provider, "_auto")
-                    .map_err(|()| AppError::RateLimited {
-                        provider: req.provider.clone(),
-                        model: None,
-                    })
-                    .ok()
-            } else {
-                None
-            };
+    // Only structured outputs are cached
+    let cache = state.cache.as_ref().zip(req.schema.as_ref());
+    let conversation = req.conversation();
+    if let Some((cache, schema)) = cache.filter(|_| control.lookup) {
+        state.check_draining()?;
+        let allowed: Vec<Target> = targets
+            .iter()
+            .filter(|t| caller.is_none_or(|key| key.allows(t)))
+            .cloned()
+            .collect();
+        if let Some((target, output)) = cache.get(&allowed, &conversation, schema).await {
+            info!(target = %target, "serving cached output");
+            return Ok(GenerateResponse {
+                output: Output::Structured(output),
+                attempts: None,
+                served_by: reports_target.then(|| target.to_string()),
+                rule: plan.rule,
+                cached: Some(true),
+                meta: req.include_meta.then(|| Meta::cached(request_id, &target)),
+                ensemble: None,
+            });
+        }
+    }
+
+    let execution = dispatch(state, caller, targets, req, wait, plan.hedge_after, |_| {}).await?;
 
-            let timeout = provider_cfg.and_then(|p| p.timeout_secs);
-            (timeout, guard)
+    if let (Some((cache, schema)), Output::Structured(output)) = (cache, &execution.output) {
+        if control.store {
+            cache
+                .put(&execution.target, &conversation, schema, output)
+                .await;
         }
-    };
+    }
 
-    info!(
-        provider = %req.provider,
-        model = ?req.model,
-        timeout_secs = ?timeout_secs,
-        "executing request"
-    );
+    let configured = state
+        .settings()
+        .provider_settings
+        .get(&execution.target.provider)
+        .and_then(|p| p.max_repair_attempts)
+        .unwrap_or(0);
+    let repair_enabled = req.schema.is_some() && req.repair_attempts(configured) > 0;
 
-    let output = provider
-        .execute(&req.prompt, &req.schema, req.model.as_deref(), timeout_secs)
-        .await?;
+    Ok(GenerateResponse {
+        attempts: repair_enabled.then_some(execution.attempts),
+        served_by: reports_target.then(|| execution.target.to_string()),
+        rule: plan.rule,
+        cached: cache.map(|_| false),
+        meta: req.include_meta.then(|| Meta::new(request_id, &execution)),
+        ensemble: None,
+        output: execution.output,
+    })
+}
 
-    schema::validate_output(&req.schema, &output)?;
+/// `llm-mux check-config [path]`: prints every problem with the config and
+/// exits with status 1 if there is any.
+fn check_config(path: &str) -> i32 {
+    let problems = match Config::parse(path) {
+        Ok(config) => config.validate(),
+        Err(e) => vec![e.to_string()],
+    };
+    if problems.is_empty() {
+        println!("{path}: ok");
+        return 0;
+    }
+    for problem in &problems {
+        eprintln!("{path}: {problem}");
+    }
+    1
+}
 
-    Ok(HttpResponse::Ok().json(GenerateResponse { output }))
+/// Loads the config or exits. With `allow_invalid`
+/// (`LLM_MUX_ALLOW_INVALID_CONFIG=1`) problems are only logged: a config that
+/// fails validation is used as is, one that cannot be read or parsed is
+/// replaced by the defaults.
+fn load_config(path: &str, allow_invalid: bool) -> Config {
+    let problems = match Config::parse(path) {
+        Ok(config) => {
+            let problems = config.validate();
+            if problems.is_empty() || allow_invalid {
+                for problem in &problems {
+                    warn!(path, "ignoring config problem: {problem}");
+                }
+                return config;
+            }
+            problems
+        }
+        Err(e) if allow_invalid => {
+            warn!(path, error = %e, "failed to load config, using defaults");
+            return Config::default();
+        }
+        Err(e) => vec![e.to_string()],
+    };
+    for problem in &problems {
+        error!(path, "{problem}");
+    }
+    error!("invalid config, set LLM_MUX_ALLOW_INVALID_CONFIG=1 to start anyway");
+    std::process::exit(1);
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("check-config") if args.len() <= 2 => {
+            let default_path =
+                std::env::var("LLM_MUX_CONFIG").unwrap_or_else(|_| "config.toml".to_string());
+            std::process::exit(check_config(args.get(1).unwrap_or(&default_path)));
+        }
+        Some(_) => {
+            eprintln!("usage: llm-mux [check-config [path]]");
+            std::process::exit(2);
+        }
+    }
+
     tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::from_default_env().add_directive("llm_mux=info".parse().unwrap()),
@@ -125,83 +515,125 @@ async fn main() -> std::io::Result<()> {
         .init();
 
     let config_path = std::env::var("LLM_MUX_CONFIG").unwrap_or_else(|_| "config.toml".to_string());
+    let allow_invalid = std::env::var("LLM_MUX_ALLOW_INVALID_CONFIG").is_ok_and(|v| v == "1");
+    let config = load_config(&config_path, allow_invalid);
 
-    let config = match Config::load(&config_path) {
-        Ok(c) => c,
-        Err(e) => {
-            warn!(
-                "failed to load config from {}: {}, using defaults",
-                config_path, e
-            );
-            Config {
-                server: config::ServerConfig::default(),
-                providers: vec![],
-            }
-        }
-    };
-
-    let model_settings = config.model_settings();
-    let provider_settings = config.provider_settings();
-
+    let settings = config.settings();
     let rate_limiter = RateLimiter::new();
-    for (key, settings) in &model_settings {
-        info!(provider = %key.0, model = %key.1, "registering model settings");
-        rate_limiter.register(key.0.clone(), key.1.clone(), settings.clone());
-    }
-
-    // Register provider-level rate limits for auto model
-    for (name, settings) in &provider_settings {
-        if settings.supports_auto_model {
-            let auto_settings = ModelSettings {
-                rps: settings.rps,
-                rpm: settings.rpm,
-                concurrent: settings.concurrent,
-                timeout_secs: settings.timeout_secs,
-            };
-            info!(provider = %name, "registering auto model settings");
-            rate_limiter.register(name.clone(), "_auto".into(), auto_settings);
+    auth::register_budgets(&rate_limiter, &config.auth.keys);
+    rate_limiter.sync(settings.limits(), |provider| provider == auth::KEY_LIMITER);
+
+    let cache = match &config.cache {
+        Some(cache_config) => {
+            info!(backend = ?cache_config.backend, ttl_secs = cache_config.ttl_secs, "response cache enabled");
+            Some(ResponseCache::new(cache_config).map_err(std::io::Error::other)?)
         }
-    }
+        None => None,
+    };
 
+    let metrics = Metrics::new();
+    let children = ChildProcesses::default();
     let state = Arc::new(AppState {
-        executor: CliExecutor::new(),
+        executor: CliExecutor::new(metrics.clone(), children.clone()),
         rate_limiter,
-        model_settings,
-        provider_settings,
+        settings: RwLock::new(Arc::new(settings)),
+        jobs: JobStore::new(),
+        metrics,
+        api_keys: ApiKeys::new(&config.auth.keys),
+        cache,
+        inflight: Singleflight::new(),
+        draining: AtomicBool::new(false),
+        health: HealthStore::default(),
     });
+    if state.api_keys.is_enabled() {
+        info!(
+            keys = config.auth.keys.len(),
+            "API key authentication enabled"
+        );
+    } else {
+        warn!("no API keys configured, authentication is disabled");
+    }
+
+    health::spawn_checker(state.clone(), config.health.clone());
+    reload::spawn_watcher(
+        state.clone(),
+        config_path.into(),
+        (config.server.config_poll_secs > 0)
+            .then(|| Duration::from_secs(config.server.config_poll_secs)),
+    );
 
     let bind_addr = format!("{}:{}", config.server.host, config.server.port);
     info!("starting server on {}", bind_addr);
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(state.clone()))
-            .route("/health", web::get().to(health))
-            .route("/generate", web::post().to(generate))
+    let server = HttpServer::new({
+        let state = state.clone();
+        move || {
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .wrap(from_fn(auth::authenticate))
+                .route("/health", web::get().to(health))
+                .route("/ready", web::get().to(health::ready))
+                .route("/health/providers", web::get().to(health::providers))
+                .route("/metrics", web::get().to(metrics::metrics))
+                .route("/generate", web::post().to(generate))
+                .route("/generate/stream", web::post().to(stream::generate_stream))
+                .service(
+                    web::resource("/batch")
+                        .app_data(batch::json_config())
+                        .route(web::post().to(batch::batch)),
+                )
+                .route("/jobs", web::post().to(jobs::create_job))
+                .route("/jobs/{id}", web::get().to(jobs::get_job))
+                .route("/jobs/{id}", web::delete().to(jobs::cancel_job))
+                .service(
+                    web::scope("/v1")
+                        .app_data(openai::json_config())
+                        .route(
+                            "/chat/completions",
+                            web::post().to(openai::chat_completions),
+                        )
+                        .route("/models", web::get().to(openai::list_models)),
+                )
+        }
     })
+    // Signals are handled below so in-flight CLI runs can drain first
+    .disable_signals()
     .bind(&bind_addr)?
-    .run()
-    .await
+    .run();
+
+    let handle = server.handle();
+    let drain_timeout = Duration::from_secs(config.server.drain_timeout_secs);
+    let kill_grace = Duration::from_secs(config.server.kill_grace_secs);
+    tokio::spawn(async move {
+        shutdown::signalled().await;
+        shutdown::drain(&state, &children, drain_timeout, kill_grace).await;
+        handle.stop(true).await;
+    });
+
+    server.await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{ModelSettings, ProviderSettings, Route};
     use crate::provider::executor::{CommandOutput, MockExecutor};
     use actix_web::{dev::ServiceResponse, test};
+    use std::collections::HashMap;
 
-    fn mock_executor() -> Arc<dyn Executor> {
+    pub(crate) fn mock_executor() -> Arc<dyn Executor> {
         let mut mock = MockExecutor::new();
         mock.expect_run().returning(|_, _, _, _| {
             Ok(CommandOutput {
-                stdout: r#"{"structured_output": {"message": "hello"}}"#.to_string(),
+                stdout: r#"{"result": "hello", "structured_output": {"message": "hello"}}"#
+                    .to_string(),
                 stderr: String::new(),
             })
         });
         Arc::new(mock)
     }
 
-    fn valid_schema() -> Value {
+    pub(crate) fn valid_schema() -> Value {
         serde_json::json!({
             "type": "object",
             "properties": {
@@ -210,12 +642,21 @@ mod tests {
         })
     }
 
-    fn test_state(executor: Arc<dyn Executor>) -> Arc<AppState> {
+    pub(crate) fn test_state(executor: Arc<dyn Executor>) -> Arc<AppState> {
+        test_state_with(executor, |_| {})
+    }
+
+    /// `test_state` with a hook to adjust the state before it is shared.
+    pub(crate) fn test_state_with(
+        executor: Arc<dyn Executor>,
+        customize: impl FnOnce(&mut AppState),
+    ) -> Arc<AppState> {
         let settings = ModelSettings {
             rps: Some(10),
             rpm: Some(100),
             concurrent: Some(2),
             timeout_secs: Some(60),
+            ..Default::default()
         };
 
         let rate_limiter = RateLimiter::new();
@@ -235,6 +676,8 @@ mod tests {
                 rpm: Some(100),
                 concurrent: Some(2),
                 timeout_secs: Some(60),
+                max_repair_attempts: Some(1),
+                ..Default::default()
             },
         );
         provider_settings.insert(
@@ -245,6 +688,7 @@ mod tests {
                 rpm: Some(100),
                 concurrent: Some(2),
                 timeout_secs: Some(60),
+                ..Default::default()
             },
         );
         provider_settings.insert(
@@ -255,16 +699,55 @@ mod tests {
             },
         );
 
-        Arc::new(AppState {
+        let mut routes = HashMap::new();
+        routes.insert(
+            "fallback".into(),
+            Route {
+                targets: vec![Target::parse("claude/sonnet"), Target::parse("gemini")],
+                hedge_after: None,
+            },
+        );
+
+        let mut state = AppState {
             executor,
             rate_limiter,
-            model_settings,
-            provider_settings,
-        })
+            settings: RwLock::new(Arc::new(Settings {
+                model_settings,
+                provider_settings,
+                routes,
+                routing: Vec::new(),
+            })),
+            jobs: JobStore::new(),
+            metrics: Metrics::new(),
+            api_keys: ApiKeys::default(),
+            cache: None,
+            inflight: Singleflight::new(),
+            draining: AtomicBool::new(false),
+            health: HealthStore::default(),
+        };
+        customize(&mut state);
+        Arc::new(state)
+    }
+
+    /// Returns the given claude stdouts in order, one per invocation.
+    fn sequence_executor(outputs: Vec<&'static str>) -> Arc<dyn Executor> {
+        let mut mock = MockExecutor::new();
+        let mut outputs = outputs.into_iter();
+        mock.expect_run().returning(move |_, _, _, _| {
+            Ok(CommandOutput {
+                stdout: outputs.next().expect("unexpected invocation").to_string(),
+                stderr: String::new(),
+            })
+        });
+        Arc::new(mock)
     }
 
     async fn post_generate(body: Value) -> ServiceResponse {
-        let state = test_state(mock_executor());
+        post_generate_with(mock_executor(), body).await
+    }
+
+    async fn post_generate_with(executor: Arc<dyn Executor>, body: Value) -> ServiceResponse {
+        let state = test_state(executor);
         let app = test::init_service(
             App::new()
                 .app_data(web::Data::new(state))
@@ -326,6 +809,30 @@ mod tests {
         assert_eq!(resp.status(), 200);
     }
 
+    #[actix_web::test]
+    async fn test_auto_model_is_not_rejected_past_its_limits() {
+        let state = test_state_with(mock_executor(), |state| {
+            for _ in 0..2 {
+                std::mem::forget(state.rate_limiter.try_acquire("claude", "_auto").unwrap());
+            }
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/generate", web::post().to(generate)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/generate")
+            .set_json(serde_json::json!({
+                "provider": "claude",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
     #[actix_web::test]
     async fn test_valid_request_with_model() {
         let resp = post_generate(serde_json::json!({
@@ -390,4 +897,826 @@ mod tests {
         .await;
         assert_eq!(resp.status(), 400);
     }
+
+    #[actix_web::test]
+    async fn test_repair_retries_invalid_output() {
+        let resp = post_generate_with(
+            sequence_executor(vec![
+                r#"{"structured_output": {"message": 1}}"#,
+                r#"{"structured_output": {"message": "fixed"}}"#,
+            ]),
+            serde_json::json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["output"]["message"], "fixed");
+        assert_eq!(body["attempts"], 2);
+    }
+
+    #[actix_web::test]
+    async fn test_repair_covers_parse_errors() {
+        let resp = post_generate_with(
+            sequence_executor(vec![
+                "not json",
+                r#"{"structured_output": {"message": "fixed"}}"#,
+            ]),
+            serde_json::json!({
+                "provider": "claude",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_repair_attempts_exhausted() {
+        let resp = post_generate_with(
+            sequence_executor(vec![
+                r#"{"structured_output": {"message": 1}}"#,
+                r#"{"structured_output": {"message": 2}}"#,
+            ]),
+            serde_json::json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 422);
+    }
+
+    #[actix_web::test]
+    async fn test_repair_disabled_by_request() {
+        let resp = post_generate_with(
+            sequence_executor(vec![r#"{"structured_output": {"message": 1}}"#]),
+            serde_json::json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema(),
+                "max_repair_attempts": 0
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 422);
+    }
+
+    #[actix_web::test]
+    async fn test_repair_attempts_are_capped_and_rate_limited() {
+        // claude allows one repair attempt; a third run would panic
+        let resp = post_generate_with(
+            sequence_executor(vec![
+                r#"{"structured_output": {"message": 1}}"#,
+                r#"{"structured_output": {"message": 2}}"#,
+            ]),
+            serde_json::json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema(),
+                "max_repair_attempts": 100
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 422);
+
+        let state = test_state_with(
+            sequence_executor(vec![r#"{"structured_output": {"message": 1}}"#]),
+            |state| {
+                state.rate_limiter.register(
+                    "claude".into(),
+                    "sonnet".into(),
+                    ModelSettings {
+                        rps: Some(1),
+                        ..Default::default()
+                    },
+                );
+            },
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/generate", web::post().to(generate)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/generate")
+            .set_json(serde_json::json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 429);
+    }
+
+    #[actix_web::test]
+    async fn test_text_mode_without_schema() {
+        let resp = post_generate(serde_json::json!({
+            "provider": "claude",
+            "model": "sonnet",
+            "prompt": "hello"
+        }))
+        .await;
+        assert_eq!(resp.status(), 200);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({"text": "hello"}));
+    }
+
+    #[actix_web::test]
+    async fn test_text_mode_omits_json_schema_flag() {
+        let mut mock = MockExecutor::new();
+        mock.expect_run()
+            .withf(|program, args, _, _| {
+                program == "claude" && !args.iter().any(|a| a == "--json-schema")
+            })
+            .returning(|_, _, _, _| {
+                Ok(CommandOutput {
+                    stdout: r#"{"result": "plain"}"#.to_string(),
+                    stderr: String::new(),
+                })
+            });
+
+        let resp = post_generate_with(
+            Arc::new(mock),
+            serde_json::json!({
+                "provider": "claude",
+                "prompt": "hello"
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    /// Claude always fails; gemini answers with a valid message.
+    pub(crate) fn failing_claude_executor() -> Arc<dyn Executor> {
+        let mut mock = MockExecutor::new();
+        mock.expect_run()
+            .returning(|program, _, _, _| match program {
+                "claude" => Err(AppError::ProviderExecution {
+                    message: "claude exited with status: 1".into(),
+                    stderr: String::new(),
+                    stdout: String::new(),
+                }),
+                _ => Ok(CommandOutput {
+                    stdout: r#"{"message": "from gemini"}"#.to_string(),
+                    stderr: String::new(),
+                }),
+            });
+        Arc::new(mock)
+    }
+
+    #[actix_web::test]
+    async fn test_messages_are_rendered_per_provider() {
+        let mut mock = MockExecutor::new();
+        mock.expect_run()
+            .withf(|program, args, stdin, _| {
+                let system = args.iter().position(|a| a == "--system-prompt");
+                program == "claude"
+                    && system.is_some_and(|i| args[i + 1] == "Answer in French.")
+                    && stdin == "[user]\nhello\n\n[assistant]\nbonjour\n\n[user]\nbye"
+            })
+            .returning(|_, _, _, _| {
+                Ok(CommandOutput {
+                    stdout: r#"{"result": "au revoir"}"#.to_string(),
+                    stderr: String::new(),
+                })
+            });
+        let resp = post_generate_with(
+            Arc::new(mock),
+            serde_json::json!({
+                "provider": "claude",
+                "messages": [
+                    {"role": "system", "content": "Answer in French."},
+                    {"role": "user", "content": "hello"},
+                    {"role": "assistant", "content": "bonjour"},
+                    {"role": "user", "content": "bye"}
+                ]
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["text"], "au revoir");
+
+        for (body, error) in [
+            (
+                serde_json::json!({"provider": "claude", "prompt": "hi", "messages": [{"role": "user", "content": "hi"}]}),
+                "\"prompt\" cannot be combined with \"messages\"",
+            ),
+            (
+                serde_json::json!({"provider": "claude", "messages": [{"role": "user", "content": "hi"}, {"role": "assistant", "content": "hello"}]}),
+                "the last of \"messages\" must have role \"user\"",
+            ),
+            (
+                serde_json::json!({"provider": "claude"}),
+                "one of \"prompt\" or \"messages\" is required",
+            ),
+        ] {
+            let resp = post_generate_with(mock_executor(), body).await;
+            assert_eq!(resp.status(), 400);
+            let body: Value = test::read_body_json(resp).await;
+            assert!(body["error"].as_str().unwrap().ends_with(error), "{body}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_route_falls_back_to_next_target() {
+        let resp = post_generate_with(
+            failing_claude_executor(),
+            serde_json::json!({
+                "route": "fallback",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 200);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["output"]["message"], "from gemini");
+        assert_eq!(body["served_by"], "gemini/_auto");
+    }
+
+    #[actix_web::test]
+    async fn test_auto_provider_follows_routing_rules() {
+        let state = test_state_with(failing_claude_executor(), |state| {
+            let current = Arc::make_mut(state.settings.get_mut().unwrap());
+            let config: Config = toml::from_str(
+                r#"
+[server]
+
+[[routing.rules]]
+name = "fast"
+tags = ["fast"]
+targets = ["claude/sonnet", "gemini"]
+
+[[routing.rules]]
+name = "free-text"
+structured = false
+targets = ["claude"]
+"#,
+            )
+            .unwrap();
+            current.routing = config.routing.rules;
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/generate", web::post().to(generate)),
+        )
+        .await;
+        let post = |body: Value| {
+            test::TestRequest::post()
+                .uri("/generate")
+                .set_json(body)
+                .to_request()
+        };
+
+        let req = post(serde_json::json!({
+            "provider": "auto",
+            "tags": ["fast"],
+            "prompt": "hello",
+            "schema": valid_schema()
+        }));
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["output"]["message"], "from gemini");
+        assert_eq!(body["served_by"], "gemini/_auto");
+        assert_eq!(body["rule"], "fast");
+
+        for (body, code) in [
+            (
+                serde_json::json!({"provider": "auto", "prompt": "hello", "schema": valid_schema()}),
+                "no_matching_rule",
+            ),
+            (
+                serde_json::json!({"provider": "auto", "model": "sonnet", "prompt": "hello"}),
+                "invalid_request",
+            ),
+            (
+                serde_json::json!({"provider": "claude", "tags": ["fast"], "prompt": "hello"}),
+                "invalid_request",
+            ),
+        ] {
+            let resp = test::call_service(&app, post(body)).await;
+            assert_eq!(resp.status(), 400);
+            let body: Value = test::read_body_json(resp).await;
+            assert_eq!(body["code"], code);
+        }
+    }
+
+    /// Claude answers (or fails, if `claude_fails`) after `claude_delay`,
+    /// gemini right away.
+    pub(crate) struct RaceExecutor {
+        claude_delay: Duration,
+        claude_fails: bool,
+        pub(crate) claude_cancelled: AtomicBool,
+        gemini_runs: std::sync::atomic::AtomicU32,
+    }
+
+    impl RaceExecutor {
+        pub(crate) fn new(claude_delay: Duration) -> Self {
+            Self {
+                claude_delay,
+                claude_fails: false,
+                claude_cancelled: AtomicBool::new(false),
+                gemini_runs: Default::default(),
+            }
+        }
+    }
+
+    /// Sets the flag when dropped while armed.
+    struct SetOnDrop<'a>(Option<&'a AtomicBool>);
+
+    impl Drop for SetOnDrop<'_> {
+        fn drop(&mut self) {
+            if let Some(flag) = self.0 {
+                flag.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Executor for RaceExecutor {
+        async fn run(
+            &self,
+            program: &str,
+            _: &[String],
+            _: &str,
+            _: Option<u64>,
+        ) -> Result<CommandOutput, AppError> {
+            let stdout = if program == "claude" {
+                let mut cancelled = SetOnDrop(Some(&self.claude_cancelled));
+                tokio::time::sleep(self.claude_delay).await;
+                cancelled.0 = None;
+                if self.claude_fails {
+                    return Err(AppError::ProviderExecution {
+                        message: "claude exited with status: 1".into(),
+                        stderr: String::new(),
+                        stdout: String::new(),
+                    });
+                }
+                r#"{"structured_output": {"message": "from claude"}}"#
+            } else {
+                self.gemini_runs
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                r#"{"message": "from gemini"}"#
+            };
+            Ok(CommandOutput {
+                stdout: stdout.to_string(),
+                stderr: String::new(),
+            })
+        }
+
+        async fn run_streaming(
+            &self,
+            program: &str,
+            args: &[String],
+            stdin: &str,
+            timeout_secs: Option<u64>,
+            _: tokio::sync::mpsc::UnboundedSender<String>,
+        ) -> Result<CommandOutput, AppError> {
+            self.run(program, args, stdin, timeout_secs).await
+        }
+    }
+
+    /// Sends a request to a route that hedges claude with gemini/flash after
+    /// 50ms.
+    async fn post_hedged(
+        claude_delay: Duration,
+        customize: impl FnOnce(&mut AppState),
+    ) -> (Value, Arc<RaceExecutor>, Arc<AppState>) {
+        let executor = Arc::new(RaceExecutor::new(claude_delay));
+        let (body, state) = post_hedged_as(executor.clone(), None, customize).await;
+        (body, executor, state)
+    }
+
+    /// `post_hedged` with `executor`, sending `key` if given.
+    async fn post_hedged_as(
+        executor: Arc<RaceExecutor>,
+        key: Option<&str>,
+        customize: impl FnOnce(&mut AppState),
+    ) -> (Value, Arc<AppState>) {
+        let state = test_state_with(executor.clone(), |state| {
+            let flash = ModelSettings {
+                concurrent: Some(2),
+                ..Default::default()
+            };
+            state
+                .rate_limiter
+                .register("gemini".into(), "flash".into(), flash.clone());
+            let current = Arc::make_mut(state.settings.get_mut().unwrap());
+            current
+                .model_settings
+                .insert(("gemini".into(), "flash".into()), flash);
+            current.routes.insert(
+                "hedged".into(),
+                Route {
+                    targets: vec![
+                        Target::parse("claude/sonnet"),
+                        Target::parse("gemini/flash"),
+                    ],
+                    hedge_after: Some(Duration::from_millis(50)),
+                },
+            );
+            customize(state);
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .wrap(from_fn(auth::authenticate))
+                .route("/generate", web::post().to(generate)),
+        )
+        .await;
+        let mut req = test::TestRequest::post()
+            .uri("/generate")
+            .set_json(serde_json::json!({
+                "route": "hedged",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }));
+        if let Some(key) = key {
+            req = req.insert_header(("X-API-Key", key));
+        }
+        let body = test::call_and_read_body_json(&app, req.to_request()).await;
+        (body, state)
+    }
+
+    #[actix_web::test]
+    async fn test_slow_primary_is_hedged() {
+        let started = std::time::Instant::now();
+        let (body, executor, state) = post_hedged(Duration::from_secs(5), |_| {}).await;
+        assert_eq!(body["output"]["message"], "from gemini");
+        assert_eq!(body["served_by"], "gemini/flash");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        // The primary's run was dropped rather than left running
+        assert!(executor
+            .claude_cancelled
+            .load(std::sync::atomic::Ordering::SeqCst));
+        assert!(state
+            .metrics
+            .render(&state.rate_limiter)
+            .contains(r#"provider="claude",model="sonnet",outcome="hedge_cancelled""#));
+    }
+
+    #[actix_web::test]
+    async fn test_hedging_waits_for_delay_and_headroom() {
+        // Answers before the hedge delay
+        let (body, executor, _) = post_hedged(Duration::from_millis(5), |_| {}).await;
+        assert_eq!(body["served_by"], "claude/sonnet");
+        assert_eq!(
+            executor
+                .gemini_runs
+                .load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+
+        // Gemini has no free slot, so claude is waited for
+        let (body, executor, _) = post_hedged(Duration::from_millis(200), |state| {
+            for _ in 0..2 {
+                std::mem::forget(state.rate_limiter.try_acquire("gemini", "flash").unwrap());
+            }
+        })
+        .await;
+        assert_eq!(body["served_by"], "claude/sonnet");
+        assert_eq!(
+            executor
+                .gemini_runs
+                .load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+        assert!(!executor
+            .claude_cancelled
+            .load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[actix_web::test]
+    async fn test_backup_turned_away_keeps_the_primary_error() {
+        let keys: Vec<config::ApiKeyConfig> = toml::from_str::<config::AuthConfig>(
+            r#"
+            [[keys]]
+            name = "claude-only"
+            key = "claude-secret"
+            providers = ["claude"]
+            "#,
+        )
+        .unwrap()
+        .keys;
+        let executor = RaceExecutor {
+            claude_fails: true,
+            ..RaceExecutor::new(Duration::from_millis(200))
+        };
+        let (body, _) = post_hedged_as(Arc::new(executor), Some("claude-secret"), |state| {
+            state.api_keys = ApiKeys::new(&keys);
+        })
+        .await;
+        assert_eq!(body["code"], "provider_execution", "{body}");
+    }
+
+    #[actix_web::test]
+    async fn test_single_provider_does_not_fall_back() {
+        let resp = post_generate_with(
+            failing_claude_executor(),
+            serde_json::json!({
+                "provider": "claude",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 500);
+    }
+
+    #[actix_web::test]
+    async fn test_known_cli_failures_get_specific_errors() {
+        let mut mock = MockExecutor::new();
+        mock.expect_run().returning(|_, _, _, _| {
+            Err(AppError::ProviderExecution {
+                message: "claude exited with status: 1".into(),
+                stderr: String::new(),
+                stdout: r#"{"type":"result","is_error":true,"result":"Claude AI usage limit reached|4102444800"}"#.into(),
+            })
+        });
+        let resp = post_generate_with(
+            Arc::new(mock),
+            serde_json::json!({
+                "provider": "claude",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+        assert_eq!(resp.status(), 503);
+        assert!(resp.headers().contains_key("retry-after"));
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "quota_exceeded");
+        assert_eq!(body["resets_at"], 4102444800u64);
+        assert_eq!(
+            body["error"],
+            "claude quota exceeded: Claude AI usage limit reached"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_include_meta_reports_usage() {
+        let mut mock = MockExecutor::new();
+        mock.expect_run().returning(|_, _, _, _| {
+            Ok(CommandOutput {
+                stdout: serde_json::json!({
+                    "structured_output": {"message": "hello"},
+                    "usage": {"input_tokens": 12, "output_tokens": 5},
+                    "total_cost_usd": 0.25,
+                    "modelUsage": {
+                        "claude-haiku-4-5": {"outputTokens": 1},
+                        "claude-sonnet-4-5": {"outputTokens": 5}
+                    }
+                })
+                .to_string(),
+                stderr: String::new(),
+            })
+        });
+        let mock: Arc<dyn Executor> = Arc::new(mock);
+        let body = serde_json::json!({
+            "provider": "claude",
+            "model": "sonnet",
+            "prompt": "hello",
+            "schema": valid_schema()
+        });
+
+        let resp = post_generate_with(mock.clone(), body.clone()).await;
+        let resp: Value = test::read_body_json(resp).await;
+        assert!(resp.get("meta").is_none());
+
+        let mut body = body;
+        body["include_meta"] = true.into();
+        let resp = post_generate_with(mock, body).await;
+        let resp: Value = test::read_body_json(resp).await;
+        let meta = &resp["meta"];
+        assert!(meta["request_id"].is_string());
+        assert_eq!(meta["provider"], "claude");
+        assert_eq!(meta["model"], "claude-sonnet-4-5");
+        assert_eq!(meta["attempts"], 1);
+        assert!(meta["duration_ms"].is_u64());
+        assert!(meta["queue_ms"].is_u64());
+        assert_eq!(
+            meta["usage"],
+            serde_json::json!({"input_tokens": 12, "output_tokens": 5, "cost_usd": 0.25})
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_route_not_found() {
+        let resp = post_generate(serde_json::json!({
+            "route": "missing",
+            "prompt": "hello",
+            "schema": valid_schema()
+        }))
+        .await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_provider_and_route_are_exclusive() {
+        let resp = post_generate(serde_json::json!({
+            "provider": "claude",
+            "route": "fallback",
+            "prompt": "hello",
+            "schema": valid_schema()
+        }))
+        .await;
+        assert_eq!(resp.status(), 400);
+
+        let resp = post_generate(serde_json::json!({
+            "prompt": "hello",
+            "schema": valid_schema()
+        }))
+        .await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    /// Answers like `mock_executor`, but only after a delay, and counts runs.
+    pub(crate) struct SlowExecutor {
+        pub(crate) runs: std::sync::atomic::AtomicU32,
+    }
+
+    #[async_trait::async_trait]
+    impl Executor for SlowExecutor {
+        async fn run(
+            &self,
+            _: &str,
+            _: &[String],
+            _: &str,
+            _: Option<u64>,
+        ) -> Result<CommandOutput, AppError> {
+            self.runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            Ok(CommandOutput {
+                stdout: r#"{"structured_output": {"message": "hello"}, "total_cost_usd": 0.01}"#
+                    .to_string(),
+                stderr: String::new(),
+            })
+        }
+
+        async fn run_streaming(
+            &self,
+            program: &str,
+            args: &[String],
+            stdin: &str,
+            timeout_secs: Option<u64>,
+            _: tokio::sync::mpsc::UnboundedSender<String>,
+        ) -> Result<CommandOutput, AppError> {
+            self.run(program, args, stdin, timeout_secs).await
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_identical_requests_share_one_execution() {
+        let executor = Arc::new(SlowExecutor {
+            runs: Default::default(),
+        });
+        let state = test_state_with(executor.clone(), |state| {
+            state.rate_limiter.register(
+                "claude".into(),
+                "sonnet".into(),
+                ModelSettings {
+                    concurrent: Some(1),
+                    ..Default::default()
+                },
+            );
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/generate", web::post().to(generate)),
+        )
+        .await;
+        let request = || {
+            test::TestRequest::post()
+                .uri("/generate")
+                .set_json(serde_json::json!({
+                    "provider": "claude",
+                    "model": "sonnet",
+                    "prompt": "hello",
+                    "schema": valid_schema(),
+                    "include_meta": true
+                }))
+                .to_request()
+        };
+
+        let (a, b, c) = tokio::join!(
+            test::call_and_read_body_json::<_, _, Value>(&app, request()),
+            test::call_and_read_body_json::<_, _, Value>(&app, request()),
+            test::call_and_read_body_json::<_, _, Value>(&app, request()),
+        );
+        assert_eq!(executor.runs.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // Only the request that ran the CLI reports its cost
+        let metas = [&a["meta"], &b["meta"], &c["meta"]];
+        let ran: Vec<_> = metas.iter().filter(|m| m.get("shared").is_none()).collect();
+        assert_eq!(ran.len(), 1, "{metas:?}");
+        assert_eq!(ran[0]["usage"]["cost_usd"], 0.01);
+        for meta in metas.iter().filter(|m| m.get("shared").is_some()) {
+            assert_eq!(meta["shared"], true);
+            assert!(meta.get("usage").is_none(), "{meta}");
+        }
+        let ids: std::collections::HashSet<_> =
+            metas.iter().map(|m| m["request_id"].as_str()).collect();
+        assert_eq!(ids.len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_keys_are_skipped_when_allowed() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            r#"
+            [server]
+
+            [[auth.keys]]
+            name = "no-secret"
+
+            [[auth.keys]]
+            name = "ci"
+            key = "ci-secret"
+            "#,
+        )
+        .unwrap();
+        let config = load_config(file.path().to_str().unwrap(), true);
+        let keys = ApiKeys::new(&config.auth.keys);
+        assert_eq!(keys.find("ci-secret").unwrap().name, "ci");
+    }
+
+    #[actix_web::test]
+    async fn test_keys_sharing_a_flight_are_each_charged() {
+        let keys: Vec<config::ApiKeyConfig> = toml::from_str::<config::AuthConfig>(
+            r#"
+            [[keys]]
+            name = "alice"
+            key = "alice-secret"
+            rpm = 1
+
+            [[keys]]
+            name = "bob"
+            key = "bob-secret"
+            rpm = 1
+            "#,
+        )
+        .unwrap()
+        .keys;
+        let executor = Arc::new(SlowExecutor {
+            runs: Default::default(),
+        });
+        let state = test_state_with(executor.clone(), |state| {
+            auth::register_budgets(&state.rate_limiter, &keys);
+            state.api_keys = ApiKeys::new(&keys);
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(from_fn(auth::authenticate))
+                .route("/generate", web::post().to(generate)),
+        )
+        .await;
+        let request = |key: &str| {
+            test::TestRequest::post()
+                .uri("/generate")
+                .insert_header(("X-API-Key", key))
+                .set_json(serde_json::json!({
+                    "provider": "claude",
+                    "model": "sonnet",
+                    "prompt": "hello",
+                    "schema": valid_schema()
+                }))
+                .to_request()
+        };
+
+        let (a, b) = tokio::join!(
+            test::call_service(&app, request("alice-secret")),
+            test::call_service(&app, request("bob-secret")),
+        );
+        assert_eq!([a.status(), b.status()], [200; 2]);
+        assert_eq!(executor.runs.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // Both budgets were used up, not just the one of the key that ran
+        for name in ["alice", "bob"] {
+            let resp = test::call_service(&app, request(&format!("{name}-secret"))).await;
+            assert_eq!(resp.status(), 429);
+            let body: Value = test::read_body_json(resp).await;
+            assert_eq!(body["error"], format!("rate limited: API key '{name}'"));
+        }
+    }
 }
@@ -0,0 +1,437 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use dashmap::DashMap;
+
+use crate::config::{Target, AUTO_MODEL};
+use crate::error::AppError;
+use crate::rate_limiter::RateLimiter;
+use crate::AppState;
+
+/// Upper bounds (seconds) of the execution latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 180.0, 300.0,
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Registry {
+    requests: DashMap<(String, String, &'static str), AtomicU64>,
+    rate_limited: DashMap<(String, String), AtomicU64>,
+    validation_failures: DashMap<(String, String), AtomicU64>,
+    exits: DashMap<(String, String), AtomicU64>,
+    latency: DashMap<(String, String), Mutex<Histogram>>,
+}
+
+/// Process-wide counters and histograms, rendered in the Prometheus text
+/// format by `GET /metrics`. Gauges are read from the `RateLimiter` at
+/// scrape time.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+}
+
+/// The `outcome` label for a finished request.
+pub fn outcome<T>(result: &Result<T, AppError>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(e) => e.code(),
+    }
+}
+
+fn target_key(target: &Target) -> (String, String) {
+    (
+        target.provider.clone(),
+        target
+            .model
+            .clone()
+            .unwrap_or_else(|| AUTO_MODEL.to_string()),
+    )
+}
+
+fn increment<K: std::hash::Hash + Eq>(map: &DashMap<K, AtomicU64>, key: K) {
+    map.entry(key).or_default().fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts one attempt against `target`; `outcome` is `success` or an
+    /// `AppError::code`.
+    pub fn record_request(&self, target: &Target, outcome: &'static str) {
+        let (provider, model) = target_key(target);
+        increment(&self.registry.requests, (provider, model, outcome));
+    }
+
+    pub fn record_rate_limited(&self, target: &Target) {
+        increment(&self.registry.rate_limited, target_key(target));
+    }
+
+    pub fn record_validation_failure(&self, target: &Target) {
+        increment(&self.registry.validation_failures, target_key(target));
+    }
+
+    /// Counts a child process exit; `code` is the exit code, or `signal` when
+    /// the process was killed.
+    pub fn record_exit(&self, program: &str, code: Option<i32>) {
+        let code = code.map_or_else(|| "signal".to_string(), |c| c.to_string());
+        increment(&self.registry.exits, (program.to_string(), code));
+    }
+
+    pub fn observe_execution(&self, target: &Target, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let entry = self.registry.latency.entry(target_key(target)).or_default();
+        let mut histogram = entry.lock().unwrap();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    pub fn render(&self, rate_limiter: &RateLimiter) -> String {
+        let mut out = String::new();
+        let r = &self.registry;
+
+        let requests = r.requests.iter().map(|e| {
+            let (provider, model, outcome) = e.key();
+            (
+                labels(provider, model).with("outcome", outcome),
+                e.value().load(Ordering::Relaxed) as f64,
+            )
+        });
+        write_family(
+            &mut out,
+            "llm_mux_requests_total",
+            "counter",
+            "Requests per provider/model by outcome.",
+            requests,
+        );
+
+        let rate_limited = r.rate_limited.iter().map(|e| {
+            let (provider, model) = e.key();
+            (
+                labels(provider, model),
+                e.value().load(Ordering::Relaxed) as f64,
+            )
+        });
+        write_family(
+            &mut out,
+            "llm_mux_rate_limit_rejections_total",
+            "counter",
+            "Requests rejected by the rate limiter.",
+            rate_limited,
+        );
+
+        let validation_failures = r.validation_failures.iter().map(|e| {
+            let (provider, model) = e.key();
+            (
+                labels(provider, model),
+                e.value().load(Ordering::Relaxed) as f64,
+            )
+        });
+        write_family(
+            &mut out,
+            "llm_mux_schema_validation_failures_total",
+            "counter",
+            "Provider outputs that failed schema validation.",
+            validation_failures,
+        );
+
+        let exits = r.exits.iter().map(|e| {
+            let (program, code) = e.key();
+            (
+                vec![("program", program.clone()), ("code", code.clone())],
+                e.value().load(Ordering::Relaxed) as f64,
+            )
+        });
+        write_family(
+            &mut out,
+            "llm_mux_process_exits_total",
+            "counter",
+            "CLI child process exits by exit code.",
+            exits,
+        );
+
+        let mut latency = Vec::new();
+        for e in r.latency.iter() {
+            let (provider, model) = e.key();
+            let histogram = e.value().lock().unwrap();
+            for (count, le) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                latency.push((
+                    "_bucket",
+                    labels(provider, model).with("le", &le.to_string()),
+                    *count as f64,
+                ));
+            }
+            latency.push((
+                "_bucket",
+                labels(provider, model).with("le", "+Inf"),
+                histogram.count as f64,
+            ));
+            latency.push(("_sum", labels(provider, model), histogram.sum));
+            latency.push(("_count", labels(provider, model), histogram.count as f64));
+        }
+        write_histogram(
+            &mut out,
+            "llm_mux_execution_duration_seconds",
+            "Wall-clock duration of provider CLI executions.",
+            latency,
+        );
+
+        let snapshot = rate_limiter.snapshot();
+        let mut in_flight = Vec::new();
+        let mut concurrency_limit = Vec::new();
+        let mut window_requests = Vec::new();
+        let mut window_limit = Vec::new();
+        let mut queued = Vec::new();
+        for s in &snapshot {
+            let l = labels(&s.provider, &s.model);
+            if let Some((current, max)) = s.concurrent {
+                in_flight.push((l.clone(), current as f64));
+                concurrency_limit.push((l.clone(), max as f64));
+            }
+            for (window, usage) in [("1s", s.rps), ("60s", s.rpm)] {
+                if let Some((used, max)) = usage {
+                    window_requests.push((l.clone().with("window", window), used as f64));
+                    window_limit.push((l.clone().with("window", window), max as f64));
+                }
+            }
+            queued.push((l, s.queued as f64));
+        }
+        write_family(
+            &mut out,
+            "llm_mux_in_flight",
+            "gauge",
+            "Requests currently holding a concurrency slot.",
+            in_flight,
+        );
+        write_family(
+            &mut out,
+            "llm_mux_concurrency_limit",
+            "gauge",
+            "Configured concurrency limit.",
+            concurrency_limit,
+        );
+        write_family(
+            &mut out,
+            "llm_mux_window_requests",
+            "gauge",
+            "Requests counted in the sliding rate limit window.",
+            window_requests,
+        );
+        write_family(
+            &mut out,
+            "llm_mux_window_limit",
+            "gauge",
+            "Configured sliding rate limit window size.",
+            window_limit,
+        );
+        write_family(
+            &mut out,
+            "llm_mux_queued_requests",
+            "gauge",
+            "Requests waiting in the rate limiter queue.",
+            queued,
+        );
+
+        out
+    }
+}
+
+fn labels(provider: &str, model: &str) -> Labels {
+    vec![
+        ("provider", provider.to_string()),
+        ("model", model.to_string()),
+    ]
+}
+
+trait WithLabel {
+    fn with(self, name: &'static str, value: &str) -> Self;
+}
+
+impl WithLabel for Labels {
+    fn with(mut self, name: &'static str, value: &str) -> Self {
+        self.push((name, value.to_string()));
+        self
+    }
+}
+
+fn format_labels(labels: &Labels) -> String {
+    let inner: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", inner.join(","))
+}
+
+fn write_family(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (Labels, f64)>,
+) {
+    // Sorted so scrapes are stable and easy to diff
+    let samples: BTreeMap<String, f64> = samples
+        .into_iter()
+        .map(|(labels, value)| (format_labels(&labels), value))
+        .collect();
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{name}{labels} {value}");
+    }
+}
+
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    samples: Vec<(&'static str, Labels, f64)>,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    for (suffix, labels, value) in samples {
+        let _ = writeln!(out, "{name}{suffix}{} {value}", format_labels(&labels));
+    }
+}
+
+/// `GET /metrics`
+pub async fn metrics(state: web::Data<Arc<AppState>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render(&state.rate_limiter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ModelSettings;
+    use crate::tests::{mock_executor, test_state, valid_schema};
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_render() {
+        let metrics = Metrics::new();
+        let target = Target::parse("claude/sonnet");
+        metrics.record_request(&target, "success");
+        metrics.record_request(&target, "timeout");
+        metrics.record_rate_limited(&target);
+        metrics.record_exit("claude", Some(1));
+        metrics.observe_execution(&target, Duration::from_secs(3));
+
+        let limiter = RateLimiter::new();
+        limiter.register(
+            "claude".into(),
+            "sonnet".into(),
+            ModelSettings {
+                rpm: Some(50),
+                concurrent: Some(1),
+                ..Default::default()
+            },
+        );
+        let _guard = limiter.try_acquire("claude", "sonnet").unwrap();
+
+        let text = metrics.render(&limiter);
+        assert!(text.contains(
+            "llm_mux_requests_total{provider=\"claude\",model=\"sonnet\",outcome=\"success\"} 1"
+        ));
+        assert!(text.contains(
+            "llm_mux_requests_total{provider=\"claude\",model=\"sonnet\",outcome=\"timeout\"} 1"
+        ));
+        assert!(text.contains(
+            "llm_mux_rate_limit_rejections_total{provider=\"claude\",model=\"sonnet\"} 1"
+        ));
+        assert!(text.contains("llm_mux_process_exits_total{program=\"claude\",code=\"1\"} 1"));
+        assert!(text.contains(
+            "llm_mux_execution_duration_seconds_bucket{provider=\"claude\",model=\"sonnet\",le=\"2.5\"} 0"
+        ));
+        assert!(text.contains(
+            "llm_mux_execution_duration_seconds_bucket{provider=\"claude\",model=\"sonnet\",le=\"5\"} 1"
+        ));
+        assert!(text.contains("llm_mux_in_flight{provider=\"claude\",model=\"sonnet\"} 1"));
+        assert!(text.contains(
+            "llm_mux_window_requests{provider=\"claude\",model=\"sonnet\",window=\"60s\"} 1"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_endpoint_counts_outcomes() {
+        let state = test_state(mock_executor());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/generate", web::post().to(crate::generate))
+                .route("/metrics", web::get().to(metrics)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/generate")
+            .set_json(json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+        let req = test::TestRequest::post()
+            .uri("/generate")
+            .set_json(json!({
+                "provider": "claude",
+                "model": "made-up",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/plain; version=0.0.4"
+        );
+        let body = test::read_body(resp).await;
+        let text = std::str::from_utf8(&body).unwrap();
+        assert!(text.contains(
+            "llm_mux_requests_total{provider=\"claude\",model=\"sonnet\",outcome=\"success\"} 1"
+        ));
+        assert!(text.contains(
+            "llm_mux_execution_duration_seconds_count{provider=\"claude\",model=\"sonnet\"} 1"
+        ));
+        assert!(text.contains("llm_mux_concurrency_limit{provider=\"claude\",model=\"sonnet\"} 2"));
+        assert!(!text.contains("made-up"));
+    }
+
+    #[actix_web::test]
+    async fn test_label_escaping() {
+        let labels = vec![("program", "a\"b\\c".to_string())];
+        assert_eq!(format_labels(&labels), r#"{program="a\"b\\c"}"#);
+    }
+}
@@ -0,0 +1,483 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::auth::ApiKey;
+use crate::config::{Target, AUTO_MODEL};
+use crate::dispatch::dispatch;
+use crate::error::AppError;
+use crate::provider::{Message, Role};
+use crate::rate_limiter::Wait;
+use crate::{AppState, GenerateRequest, Output};
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    response_format: Option<ResponseFormat>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    role: String,
+    content: MessageContent,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentPart {
+    #[serde(default)]
+    text: Option<String>,
+}
+
+impl MessageContent {
+    fn text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts
+                .iter()
+                .filter_map(|p| p.text.as_deref())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonSchemaFormat {
+    schema: Value,
+}
+
+/// An `AppError` rendered as an OpenAI error body.
+#[derive(Debug)]
+pub struct OpenAiError(AppError);
+
+impl From<AppError> for OpenAiError {
+    fn from(e: AppError) -> Self {
+        Self(e)
+    }
+}
+
+impl std::fmt::Display for OpenAiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl OpenAiError {
+    fn kind(&self) -> (&'static str, Option<&'static str>) {
+        match &self.0 {
+            AppError::ProviderNotFound(_)
+            | AppError::ModelNotFound { .. }
+            | AppError::AutoModelNotSupported(_)
+            | AppError::RouteNotFound(_)
+            | AppError::NoMatchingRule => ("invalid_request_error", Some("model_not_found")),
+            AppError::InvalidRequest(_) => ("invalid_request_error", None),
+            AppError::InvalidSchema(_) => ("invalid_request_error", Some("invalid_schema")),
+            AppError::JobNotFound(_) => ("invalid_request_error", None),
+            AppError::Unauthorized => ("invalid_request_error", Some("invalid_api_key")),
+            AppError::Forbidden { .. } => ("invalid_request_error", Some("model_not_found")),
+            AppError::RateLimited { .. } | AppError::KeyRateLimited(_) => {
+                ("rate_limit_error", Some("rate_limit_exceeded"))
+            }
+            AppError::AuthenticationRequired { .. } => {
+                ("authentication_error", Some("authentication_required"))
+            }
+            AppError::QuotaExceeded { .. } => ("rate_limit_error", Some("insufficient_quota")),
+            AppError::UnknownModel { .. } => ("invalid_request_error", Some("model_not_found")),
+            AppError::ContextTooLong { .. } => {
+                ("invalid_request_error", Some("context_length_exceeded"))
+            }
+            AppError::ContentRefused { .. } => ("invalid_request_error", Some("content_filter")),
+            AppError::Timeout { .. } => ("server_error", Some("timeout")),
+            AppError::OutputValidation { .. } => ("server_error", Some("output_validation_failed")),
+            AppError::EnsembleDisagreement(_) => ("server_error", Some("ensemble_disagreement")),
+            AppError::ProviderExecution { .. }
+            | AppError::ShuttingDown
+            | AppError::OutputParse { .. }
+            | AppError::ConfigLoad(_)
+            | AppError::Internal(_) => ("server_error", None),
+        }
+    }
+}
+
+impl ResponseError for OpenAiError {
+    fn status_code(&self) -> StatusCode {
+        self.0.payload().0
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let (status, body) = self.0.payload();
+        let (error_type, code) = self.kind();
+        HttpResponse::build(status).json(json!({
+            "error": {
+                "message": body.error,
+                "type": error_type,
+                "param": null,
+                "code": code,
+            }
+        }))
+    }
+}
+
+fn invalid_request(message: impl Into<String>) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": {
+            "message": message.into(),
+            "type": "invalid_request_error",
+            "param": null,
+            "code": null,
+        }
+    }))
+}
+
+/// Makes body deserialization failures under `/v1` use the OpenAI error shape.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err: JsonPayloadError, _req: &HttpRequest| {
+        let message = err.to_string();
+        actix_web::error::InternalError::from_response(err, invalid_request(message)).into()
+    })
+}
+
+/// Maps the chat messages onto llm-mux roles; `developer` is OpenAI's newer
+/// name for `system`. Tool messages are not supported.
+fn to_messages(messages: &[ChatMessage]) -> Result<Vec<Message>, String> {
+    messages
+        .iter()
+        .map(|m| {
+            let role = match m.role.as_str() {
+                "system" | "developer" => Role::System,
+                "user" => Role::User,
+                "assistant" => Role::Assistant,
+                other => return Err(format!("unsupported message role '{other}'")),
+            };
+            Ok(Message {
+                role,
+                content: m.content.text(),
+            })
+        })
+        .collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletionResponse {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<Choice>,
+}
+
+#[derive(Debug, Serialize)]
+struct Choice {
+    index: u32,
+    message: AssistantMessage,
+    finish_reason: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct AssistantMessage {
+    role: &'static str,
+    content: String,
+}
+
+/// `POST /v1/chat/completions`: OpenAI-compatible facade over `/generate`,
+/// so OpenAI SDK clients can point their base URL at `/v1`.
+pub async fn chat_completions(
+    state: web::Data<Arc<AppState>>,
+    caller: Option<web::ReqData<Arc<ApiKey>>>,
+    req: web::Json<ChatCompletionRequest>,
+) -> Result<HttpResponse, OpenAiError> {
+    if req.stream {
+        return Ok(invalid_request("stream is not supported"));
+    }
+    if req.messages.is_empty() {
+        return Ok(invalid_request("messages must not be empty"));
+    }
+    let messages = match to_messages(&req.messages) {
+        Ok(messages) => messages,
+        Err(message) => return Ok(invalid_request(message)),
+    };
+
+    let schema = match &req.response_format {
+        Some(ResponseFormat::JsonSchema { json_schema }) => Some(json_schema.schema.clone()),
+        Some(ResponseFormat::Text) | None => None,
+        Some(ResponseFormat::JsonObject) => {
+            return Ok(invalid_request(
+                "response_format json_object is not supported, use json_schema",
+            ))
+        }
+    };
+
+    // A route name is accepted wherever a `provider/model` id is
+    let (provider, model, route) = if state.settings().routes.contains_key(&req.model) {
+        (None, None, Some(req.model.clone()))
+    } else {
+        let target = Target::parse(&req.model);
+        (Some(target.provider), target.model, None)
+    };
+    let generate = GenerateRequest {
+        provider,
+        model,
+        route,
+        tags: Vec::new(),
+        ensemble: None,
+        prompt: None,
+        messages,
+        schema,
+        max_repair_attempts: None,
+        include_meta: false,
+    };
+    generate.validate()?;
+
+    let plan = generate.plan(&state, caller.as_deref().map(Arc::as_ref))?;
+    let execution = dispatch(
+        &state,
+        caller.as_deref().map(Arc::as_ref),
+        &plan.targets,
+        &generate,
+        Wait::Queue(None),
+        plan.hedge_after,
+        |_| {},
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(ChatCompletionResponse {
+        id: format!("chatcmpl-{}", Uuid::new_v4().simple()),
+        object: "chat.completion",
+        created: unix_now(),
+        model: req.model.clone(),
+        choices: vec![Choice {
+            index: 0,
+            message: AssistantMessage {
+                role: "assistant",
+                content: match execution.output {
+                    Output::Structured(output) => output.to_string(),
+                    Output::Text(text) => text,
+                },
+            },
+            finish_reason: "stop",
+        }],
+    }))
+}
+
+/// `GET /v1/models`: every configured model, plus `provider/_auto` for
+/// providers that support auto model selection. Models the caller's key may
+/// not use are left out.
+pub async fn list_models(
+    state: web::Data<Arc<AppState>>,
+    caller: Option<web::ReqData<Arc<ApiKey>>>,
+) -> HttpResponse {
+    let current = state.settings();
+    let mut ids: Vec<(String, String)> = current
+        .model_settings
+        .keys()
+        .map(|(provider, model)| (format!("{provider}/{model}"), provider.clone()))
+        .collect();
+    ids.extend(
+        current
+            .provider_settings
+            .iter()
+            .filter(|(_, settings)| settings.supports_auto_model)
+            .map(|(provider, _)| (format!("{provider}/{AUTO_MODEL}"), provider.clone())),
+    );
+    if let Some(key) = &caller {
+        ids.retain(|(id, _)| key.allows(&Target::parse(id)));
+    }
+    ids.sort();
+
+    let data: Vec<Value> = ids
+        .into_iter()
+        .map(|(id, owned_by)| {
+            json!({
+                "id": id,
+                "object": "model",
+                "created": 0,
+                "owned_by": owned_by,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(json!({ "object": "list", "data": data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{mock_executor, test_state, valid_schema};
+    use actix_web::{test, App};
+
+    macro_rules! openai_app {
+        () => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(test_state(mock_executor())))
+                    .service(
+                        web::scope("/v1")
+                            .app_data(json_config())
+                            .route("/chat/completions", web::post().to(chat_completions))
+                            .route("/models", web::get().to(list_models)),
+                    ),
+            )
+            .await
+        };
+    }
+
+    fn chat_body(model: &str) -> Value {
+        json!({
+            "model": model,
+            "messages": [
+                {"role": "system", "content": "be brief"},
+                {"role": "user", "content": [{"type": "text", "text": "hello"}]}
+            ],
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": {"name": "reply", "schema": valid_schema()}
+            }
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_chat_completion() {
+        let app = openai_app!();
+        let req = test::TestRequest::post()
+            .uri("/v1/chat/completions")
+            .set_json(chat_body("claude/sonnet"))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(resp["object"], "chat.completion");
+        assert_eq!(resp["model"], "claude/sonnet");
+        let content = resp["choices"][0]["message"]["content"].as_str().unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(content).unwrap(),
+            json!({"message": "hello"})
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_chat_completion_unknown_model() {
+        let app = openai_app!();
+        let req = test::TestRequest::post()
+            .uri("/v1/chat/completions")
+            .set_json(chat_body("claude/unknown"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(body["error"]["code"], "model_not_found");
+    }
+
+    #[actix_web::test]
+    async fn test_chat_completion_text_mode() {
+        let app = openai_app!();
+        let req = test::TestRequest::post()
+            .uri("/v1/chat/completions")
+            .set_json(json!({
+                "model": "claude/sonnet",
+                "messages": [{"role": "user", "content": "hello"}]
+            }))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["choices"][0]["message"]["content"], "hello");
+    }
+
+    #[actix_web::test]
+    async fn test_chat_completion_rejects_json_object() {
+        let app = openai_app!();
+        let req = test::TestRequest::post()
+            .uri("/v1/chat/completions")
+            .set_json(json!({
+                "model": "claude/sonnet",
+                "messages": [{"role": "user", "content": "hello"}],
+                "response_format": {"type": "json_object"}
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_malformed_body_uses_openai_error_shape() {
+        let app = openai_app!();
+        let req = test::TestRequest::post()
+            .uri("/v1/chat/completions")
+            .set_json(json!({"messages": []}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+
+    #[actix_web::test]
+    async fn test_list_models() {
+        let app = openai_app!();
+        let req = test::TestRequest::get().uri("/v1/models").to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+
+        let ids: Vec<&str> = resp["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["claude/_auto", "claude/sonnet", "gemini/_auto"]);
+    }
+
+    #[actix_web::test]
+    async fn test_to_messages() {
+        let messages = vec![
+            ChatMessage {
+                role: "developer".into(),
+                content: MessageContent::Text("be brief".into()),
+            },
+            ChatMessage {
+                role: "user".into(),
+                content: MessageContent::Text("hi".into()),
+            },
+        ];
+        let messages = to_messages(&messages).unwrap();
+        assert_eq!(messages[0].role, Role::System);
+        assert_eq!(messages[1], Message::user("hi"));
+
+        let tool = ChatMessage {
+            role: "tool".into(),
+            content: MessageContent::Text("{}".into()),
+        };
+        assert!(to_messages(&[tool]).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::error::AppError;
+
+/// Kind of failure a known stderr/stdout signature stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Authentication,
+    Quota,
+    UnknownModel,
+    ContextTooLong,
+    Refused,
+}
+
+/// Lowercase fragments that identify `failure` in CLI output.
+pub struct Signature {
+    pub failure: Failure,
+    pub patterns: &'static [&'static str],
+}
+
+/// Signatures shared by every CLI. Provider tables are checked first.
+const COMMON: &[Signature] = &[
+    Signature {
+        failure: Failure::Authentication,
+        patterns: &[
+            "not logged in",
+            "please log in",
+            "please login",
+            "please run /login",
+            "login required",
+            "unauthenticated",
+            "invalid api key",
+            "api key not valid",
+            "authentication failed",
+            "authentication required",
+            "token has expired",
+            "401 unauthorized",
+        ],
+    },
+    Signature {
+        failure: Failure::Quota,
+        patterns: &[
+            "usage limit",
+            "quota exceeded",
+            "exceeded your current quota",
+            "insufficient_quota",
+            "resource_exhausted",
+        ],
+    },
+    Signature {
+        failure: Failure::UnknownModel,
+        patterns: &[
+            "unknown model",
+            "invalid model",
+            "model not found",
+            "model_not_found",
+            "model is not supported",
+        ],
+    },
+    Signature {
+        failure: Failure::ContextTooLong,
+        patterns: &[
+            "prompt is too long",
+            "input is too long",
+            "context length",
+            "context window",
+            "context_length_exceeded",
+            "maximum context",
+            "too many tokens",
+        ],
+    },
+    Signature {
+        failure: Failure::Refused,
+        patterns: &[
+            "content policy",
+            "usage policies",
+            "content filter",
+            "blocked due to safety",
+        ],
+    },
+];
+
+/// Turns a failed CLI run into a specific error when its output matches a
+/// known signature in `signatures` or the common table. Other errors are
+/// returned unchanged.
+pub fn classify(provider: &str, signatures: &[Signature], error: AppError) -> AppError {
+    let AppError::ProviderExecution { stderr, stdout, .. } = &error else {
+        return error;
+    };
+
+    let lines: Vec<String> = stderr.lines().chain(stdout.lines()).map(text).collect();
+    let matched = signatures.iter().chain(COMMON).find_map(|signature| {
+        lines
+            .iter()
+            .find(|line| {
+                let line = line.to_lowercase();
+                signature.patterns.iter().any(|p| line.contains(p))
+            })
+            .map(|line| (signature.failure, line))
+    });
+    let Some((failure, line)) = matched else {
+        return error;
+    };
+
+    let provider = provider.to_string();
+    let message = summarize(line);
+    match failure {
+        Failure::Authentication => AppError::AuthenticationRequired { provider, message },
+        Failure::Quota => AppError::QuotaExceeded {
+            provider,
+            resets_at: lines.iter().find_map(|line| reset_time(line)),
+            message,
+        },
+        Failure::UnknownModel => AppError::UnknownModel { provider, message },
+        Failure::ContextTooLong => AppError::ContextTooLong { provider, message },
+        Failure::Refused => AppError::ContentRefused { provider, message },
+    }
+}
+
+/// The human-readable part of an output line: CLIs that print JSON errors
+/// carry the message in `result`, `message` or `error.message`.
+fn text(line: &str) -> String {
+    let Ok(value) = serde_json::from_str::<Value>(line) else {
+        return line.to_string();
+    };
+    ["/result", "/message", "/error/message", "/error"]
+        .iter()
+        .find_map(|pointer| value.pointer(pointer)?.as_str())
+        .unwrap_or(line)
+        .to_string()
+}
+
+/// The matched line without claude's `|<timestamp>` suffix, capped in length.
+fn summarize(line: &str) -> String {
+    const MAX_LEN: usize = 300;
+    let line = match line.rsplit_once('|') {
+        Some((text, ts)) if ts.trim().parse::<u64>().is_ok() => text,
+        _ => line,
+    };
+    line.trim().chars().take(MAX_LEN).collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Unix time at which a quota resets, from either claude's
+/// `limit reached|<timestamp>` or a relative "try again in 2h 30m" /
+/// "retry in 23.5s".
+fn reset_time(line: &str) -> Option<u64> {
+    if let Some((_, ts)) = line.rsplit_once('|') {
+        if let Ok(ts) = ts.trim().parse() {
+            return Some(ts);
+        }
+    }
+
+    let line = line.to_lowercase();
+    let rest = ["try again in ", "retry in ", "retry after "]
+        .iter()
+        .find_map(|marker| line.split_once(marker).map(|(_, rest)| rest))?;
+    parse_duration(rest).map(|secs| unix_now() + secs)
+}
+
+/// Parses a leading duration such as "2 days 3 hours", "1h30m" or "23.5s"
+/// into seconds. Parsing stops at the first word that is not part of it.
+fn parse_duration(text: &str) -> Option<u64> {
+    let mut total = 0.0;
+    let mut found = false;
+    let mut rest = text.trim_start();
+    loop {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let Ok(value) = rest[..digits].parse::<f64>() else {
+            break;
+        };
+        let after = rest[digits..].trim_start();
+        let unit_len = after
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(after.len());
+        let seconds = match &after[..unit_len] {
+            "s" | "sec" | "secs" | "second" | "seconds" => 1.0,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60.0,
+            "h" | "hr" | "hrs" | "hour" | "hours" => 3600.0,
+            "d" | "day" | "days" => 86400.0,
+            _ => break,
+        };
+        total += value * seconds;
+        found = true;
+        rest = after[unit_len..].trim_start_matches([' ', ',']);
+        rest = rest.strip_prefix("and ").unwrap_or(rest);
+    }
+    found.then(|| total.ceil() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed(stderr: &str, stdout: &str) -> AppError {
+        AppError::ProviderExecution {
+            message: "cli exited with status: 1".into(),
+            stderr: stderr.into(),
+            stdout: stdout.into(),
+        }
+    }
+
+    #[test]
+    fn test_classifies_known_signatures() {
+        let e = classify(
+            "claude",
+            &[],
+            failed("", "Invalid API key · Please run /login"),
+        );
+        assert!(
+            matches!(&e, AppError::AuthenticationRequired { provider, message }
+                if provider == "claude" && message == "Invalid API key · Please run /login")
+        );
+
+        let stdout = r#"{"type":"result","is_error":true,"result":"Claude AI usage limit reached|1760000000"}"#;
+        let e = classify("claude", &[], failed("", stdout));
+        assert!(matches!(
+            &e,
+            AppError::QuotaExceeded { message, resets_at: Some(1760000000), .. }
+                if message == "Claude AI usage limit reached"
+        ));
+
+        let e = classify(
+            "x",
+            &[],
+            failed("Error: prompt is too long: 250000 tokens", ""),
+        );
+        assert_eq!(e.code(), "context_too_long");
+
+        let e = classify("x", &[], failed("segfault", ""));
+        assert_eq!(e.code(), "provider_execution");
+    }
+
+    #[test]
+    fn test_provider_signatures_take_precedence() {
+        const SIGNATURES: &[Signature] = &[Signature {
+            failure: Failure::UnknownModel,
+            patterns: &["issue with the selected model"],
+        }];
+        let stderr = "There's an issue with the selected model (foo). Run --model to pick a \
+                      different model. Not logged in?";
+        assert_eq!(
+            classify("claude", SIGNATURES, failed(stderr, "")).code(),
+            "unknown_model"
+        );
+        assert_eq!(
+            classify("claude", &[], failed(stderr, "")).code(),
+            "authentication_required"
+        );
+    }
+
+    #[test]
+    fn test_reset_time() {
+        let now = unix_now();
+        let reset = reset_time("You've hit your usage limit. Try again in 2 days 3 hours.");
+        assert!(reset.unwrap() >= now + 2 * 86400 + 3 * 3600);
+        let reset = reset_time("Quota exceeded. Please retry in 23.5s.").unwrap();
+        assert!((now + 24..now + 30).contains(&reset));
+        assert_eq!(reset_time("limit reached ∙ resets 3pm"), None);
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration("4 minutes and 10 seconds"), Some(250));
+    }
+}
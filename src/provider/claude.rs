@@ -2,10 +2,27 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use serde_json::Value;
+use tokio::sync::mpsc;
 
 use crate::error::AppError;
+use crate::provider::classify::{classify, Failure, Signature};
 use crate::provider::executor::Executor;
-use crate::provider::Provider;
+use crate::provider::{split_system, transcript, Backend, Completion, Message, Provider, Usage};
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        failure: Failure::UnknownModel,
+        patterns: &["issue with the selected model"],
+    },
+    Signature {
+        failure: Failure::Quota,
+        patterns: &["limit reached"],
+    },
+    Signature {
+        failure: Failure::Authentication,
+        patterns: &["oauth token has expired", "run /login"],
+    },
+];
 
 pub struct ClaudeProvider {
     executor: Arc<dyn Executor>,
@@ -15,40 +32,116 @@ impl ClaudeProvider {
     pub fn new(executor: Arc<dyn Executor>) -> Self {
         Self { executor }
     }
+
+    fn args(
+        schema: Option<&Value>,
+        model: Option<&str>,
+        system: Option<String>,
+        output_format: &str,
+    ) -> Result<Vec<String>, AppError> {
+        let mut args: Vec<String> = Vec::new();
+        if let Some(m) = model {
+            args.extend(["--model".into(), m.into()]);
+        }
+        // Replaces claude's default coding-agent system prompt
+        if let Some(system) = system {
+            args.extend(["--system-prompt".into(), system]);
+        }
+        args.extend(["--output-format".into(), output_format.into()]);
+        // stream-json is only accepted together with --verbose in print mode
+        if output_format == "stream-json" {
+            args.push("--verbose".into());
+        }
+        if let Some(schema) = schema {
+            let schema_compact = serde_json::to_string(schema)
+                .map_err(|e| AppError::InvalidSchema(format!("{e}")))?;
+            args.extend(["--json-schema".into(), schema_compact]);
+        }
+        args.push("-p".into());
+        Ok(args)
+    }
+}
+
+/// Picks `structured_output`, or the plain `result` text in free-text mode,
+/// out of a claude JSON result, together with the reported usage and model.
+fn extract_output(
+    response: &Value,
+    structured: bool,
+    stdout: &str,
+) -> Result<Completion, AppError> {
+    let field = if structured {
+        "structured_output"
+    } else {
+        "result"
+    };
+    let output = response
+        .get(field)
+        .filter(|v| structured || v.is_string())
+        .cloned()
+        .ok_or_else(|| AppError::OutputParse {
+            message: format!("missing '{field}' field"),
+            stdout: stdout.to_string(),
+        })?;
+
+    let usage = response.get("usage");
+    let cost_usd = response.get("total_cost_usd").and_then(Value::as_f64);
+    let tokens = |field: &str| usage.and_then(|u| u.get(field)).and_then(Value::as_u64);
+    let usage = (usage.is_some() || cost_usd.is_some()).then(|| Usage {
+        input_tokens: tokens("input_tokens"),
+        output_tokens: tokens("output_tokens"),
+        cache_read_input_tokens: tokens("cache_read_input_tokens"),
+        cache_creation_input_tokens: tokens("cache_creation_input_tokens"),
+        cost_usd,
+    });
+
+    // `modelUsage` lists every model the run used, including small ones for
+    // housekeeping; the one that wrote the most output answered.
+    let model = response
+        .get("modelUsage")
+        .and_then(Value::as_object)
+        .and_then(|models| {
+            models
+                .iter()
+                .max_by_key(|(_, u)| u.get("outputTokens").and_then(Value::as_u64))
+                .map(|(name, _)| name.clone())
+        });
+
+    Ok(Completion {
+        output,
+        model,
+        usage,
+    })
 }
 
 #[async_trait]
 impl Provider for ClaudeProvider {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "claude"
     }
 
+    fn backend(&self) -> Backend<'_> {
+        Backend::Program("claude")
+    }
+
+    fn signatures(&self) -> &'static [Signature] {
+        SIGNATURES
+    }
+
     async fn execute(
         &self,
-        prompt: &str,
-        schema: &Value,
+        messages: &[Message],
+        schema: Option<&Value>,
         model: Option<&str>,
         timeout_secs: Option<u64>,
-    ) -> Result<Value, AppError> {
-        let schema_compact =
-            serde_json::to_string(schema).map_err(|e| AppError::InvalidSchema(format!("{e}")))?;
-
-        let mut args: Vec<String> = Vec::new();
-        if let Some(m) = model {
-            args.extend(["--model".into(), m.into()]);
-        }
-        args.extend([
-            "--output-format".into(),
-            "json".into(),
-            "--json-schema".into(),
-            schema_compact,
-            "-p".into(),
-        ]);
+    ) -> Result<Completion, AppError> {
+        let (system, messages) = split_system(messages);
+        let args = Self::args(schema, model, system, "json")?;
 
         let output = self
             .executor
-            .run("claude", &args, prompt, timeout_secs)
-            .await?;
+            .run("claude", &args, &transcript(&messages), timeout_secs)
+            .await
+            .map_err(|e| classify("claude", SIGNATURES, e))?;
 
         let response: Value =
             serde_json::from_str(&output.stdout).map_err(|e| AppError::OutputParse {
@@ -56,12 +149,45 @@ impl Provider for ClaudeProvider {
                 stdout: output.stdout.clone(),
             })?;
 
-        response
-            .get("structured_output")
-            .cloned()
+        extract_output(&response, schema.is_some(), &output.stdout)
+    }
+
+    async fn execute_streaming(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+        chunks: mpsc::UnboundedSender<String>,
+    ) -> Result<Completion, AppError> {
+        let (system, messages) = split_system(messages);
+        let args = Self::args(schema, model, system, "stream-json")?;
+
+        let output = self
+            .executor
+            .run_streaming(
+                "claude",
+                &args,
+                &transcript(&messages),
+                timeout_secs,
+                chunks,
+            )
+            .await
+            .map_err(|e| classify("claude", SIGNATURES, e))?;
+
+        // stream-json emits one event per line; the final `result` event carries
+        // the same payload as `--output-format json`.
+        let result = output
+            .stdout
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .find(|event| event.get("type").and_then(Value::as_str) == Some("result"))
             .ok_or_else(|| AppError::OutputParse {
-                message: "missing 'structured_output' field".to_string(),
-                stdout: output.stdout,
-            })
+                message: "missing 'result' event in stream".to_string(),
+                stdout: output.stdout.clone(),
+            })?;
+
+        extract_output(&result, schema.is_some(), &output.stdout)
     }
 }
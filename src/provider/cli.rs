@@ -0,0 +1,424 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use tempfile::NamedTempFile;
+use tokio::sync::mpsc;
+
+use crate::config::{CliProviderConfig, OutputFormat, PromptDelivery};
+use crate::error::AppError;
+use crate::provider::classify::classify;
+use crate::provider::executor::Executor;
+use crate::provider::gemini::{extract_json, with_schema_instruction};
+use crate::provider::{transcript, Backend, Completion, Message, Provider};
+
+/// A provider whose invocation is declared in `config.toml` instead of code.
+pub struct CliProvider {
+    name: String,
+    spec: CliProviderConfig,
+    executor: Arc<dyn Executor>,
+}
+
+/// Placeholders understood in argument templates.
+const PLACEHOLDERS: [&str; 4] = ["{model}", "{schema_file}", "{schema}", "{prompt}"];
+
+/// Expands the placeholders of `template` in a single pass, so text inserted
+/// for one placeholder is never scanned for another. Placeholders for which
+/// `value` returns `None` are kept as they are.
+fn expand<'a>(template: &str, value: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((at, placeholder)) = PLACEHOLDERS
+        .iter()
+        .filter_map(|p| rest.find(p).map(|at| (at, *p)))
+        .min()
+    {
+        expanded.push_str(&rest[..at]);
+        expanded.push_str(value(placeholder).unwrap_or(placeholder));
+        rest = &rest[at + placeholder.len()..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Everything needed to run the CLI once. `_schema_file` must outlive the
+/// child process.
+struct Invocation {
+    args: Vec<String>,
+    stdin: String,
+    _schema_file: Option<NamedTempFile>,
+}
+
+impl CliProvider {
+    pub fn new(name: &str, spec: CliProviderConfig, executor: Arc<dyn Executor>) -> Self {
+        Self {
+            name: name.to_string(),
+            spec,
+            executor,
+        }
+    }
+
+    fn invocation(
+        &self,
+        prompt: &str,
+        schema: Option<&Value>,
+        model: Option<&str>,
+    ) -> Result<Invocation, AppError> {
+        let mut templates: Vec<&String> = self.spec.args.iter().collect();
+        if model.is_some() {
+            templates.extend(&self.spec.model_args);
+        }
+        if schema.is_some() {
+            templates.extend(&self.spec.schema_args);
+        }
+
+        let prompt = match schema {
+            Some(schema) if self.spec.append_schema_to_prompt => {
+                with_schema_instruction(prompt, schema)?
+            }
+            _ => prompt.to_string(),
+        };
+
+        let schema_json = schema
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| AppError::InvalidSchema(format!("{e}")))?
+            .unwrap_or_default();
+
+        let schema_file = match schema {
+            Some(_) if templates.iter().any(|t| t.contains("{schema_file}")) => {
+                let file = tempfile::Builder::new()
+                    .suffix(".json")
+                    .tempfile()
+                    .map_err(|e| AppError::ProviderExecution {
+                        message: format!("failed to create temp file: {e}"),
+                        stderr: String::new(),
+                        stdout: String::new(),
+                    })?;
+                std::fs::write(file.path(), &schema_json).map_err(|e| {
+                    AppError::ProviderExecution {
+                        message: format!("failed to write schema: {e}"),
+                        stderr: String::new(),
+                        stdout: String::new(),
+                    }
+                })?;
+                Some(file)
+            }
+            _ => None,
+        };
+        let schema_path = schema_file
+            .as_ref()
+            .map(|f| f.path().to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let by_arg = self.spec.prompt_delivery == PromptDelivery::Arg;
+        let mut prompt_used = false;
+        let mut args: Vec<String> = templates
+            .into_iter()
+            .map(|t| {
+                prompt_used |= t.contains("{prompt}");
+                expand(t, |placeholder| match placeholder {
+                    "{model}" => Some(model.unwrap_or_default()),
+                    "{schema_file}" => Some(&schema_path),
+                    "{schema}" => Some(&schema_json),
+                    "{prompt}" => by_arg.then_some(prompt.as_str()),
+                    _ => None,
+                })
+            })
+            .collect();
+
+        let stdin = if !by_arg {
+            prompt
+        } else {
+            if !prompt_used {
+                args.push(prompt);
+            }
+            String::new()
+        };
+
+        Ok(Invocation {
+            args,
+            stdin,
+            _schema_file: schema_file,
+        })
+    }
+
+    fn parse_output(&self, stdout: String, structured: bool) -> Result<Value, AppError> {
+        let parse = |text: &str| {
+            serde_json::from_str::<Value>(text).map_err(|e| AppError::OutputParse {
+                message: format!("failed to parse output: {e}"),
+                stdout: stdout.clone(),
+            })
+        };
+        let pointer = |value: Value, pointer: &str| {
+            value
+                .pointer(pointer)
+                .cloned()
+                .ok_or_else(|| AppError::OutputParse {
+                    message: format!("missing '{pointer}' in output"),
+                    stdout: stdout.clone(),
+                })
+        };
+
+        if !structured {
+            return match &self.spec.text_pointer {
+                Some(p) => match pointer(parse(&stdout)?, p)? {
+                    Value::String(text) => Ok(Value::String(text)),
+                    _ => Err(AppError::OutputParse {
+                        message: format!("'{p}' in output is not a string"),
+                        stdout: stdout.clone(),
+                    }),
+                },
+                None => Ok(Value::String(stdout.trim().to_string())),
+            };
+        }
+
+        let json_str = match self.spec.output {
+            OutputFormat::Json => stdout.as_str(),
+            OutputFormat::Fenced => extract_json(&stdout).unwrap_or(&stdout),
+        };
+        let value = parse(json_str)?;
+
+        match &self.spec.output_pointer {
+            Some(p) => pointer(value, p),
+            None => Ok(value),
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for CliProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn backend(&self) -> Backend<'_> {
+        Backend::Program(&self.spec.program)
+    }
+
+    async fn execute(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+    ) -> Result<Completion, AppError> {
+        let invocation = self.invocation(&transcript(messages), schema, model)?;
+
+        let output = self
+            .executor
+            .run(
+                &self.spec.program,
+                &invocation.args,
+                &invocation.stdin,
+                timeout_secs,
+            )
+            .await
+            .map_err(|e| classify(&self.name, &[], e))?;
+
+        self.parse_output(output.stdout, schema.is_some())
+            .map(Completion::from)
+    }
+
+    async fn execute_streaming(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+        chunks: mpsc::UnboundedSender<String>,
+    ) -> Result<Completion, AppError> {
+        let invocation = self.invocation(&transcript(messages), schema, model)?;
+
+        let output = self
+            .executor
+            .run_streaming(
+                &self.spec.program,
+                &invocation.args,
+                &invocation.stdin,
+                timeout_secs,
+                chunks,
+            )
+            .await
+            .map_err(|e| classify(&self.name, &[], e))?;
+
+        self.parse_output(output.stdout, schema.is_some())
+            .map(Completion::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::executor::{CommandOutput, MockExecutor};
+    use serde_json::json;
+
+    fn spec(toml_str: &str) -> CliProviderConfig {
+        toml::from_str(toml_str).unwrap()
+    }
+
+    fn provider(spec: CliProviderConfig, stdout: &'static str) -> CliProvider {
+        let mut mock = MockExecutor::new();
+        mock.expect_run().returning(move |_, _, _, _| {
+            Ok(CommandOutput {
+                stdout: stdout.to_string(),
+                stderr: String::new(),
+            })
+        });
+        CliProvider::new("custom", spec, Arc::new(mock))
+    }
+
+    fn schema() -> Value {
+        json!({"type": "object", "properties": {"a": {"type": "string"}}})
+    }
+
+    #[test]
+    fn test_argument_templates() {
+        let p = provider(
+            spec(
+                r#"
+                program = "tool"
+                args = ["run"]
+                model_args = ["--model", "{model}"]
+                schema_args = ["--schema", "{schema}"]
+                "#,
+            ),
+            "",
+        );
+
+        let inv = p.invocation("hi", Some(&schema()), Some("m1")).unwrap();
+        assert_eq!(
+            inv.args,
+            vec!["run", "--model", "m1", "--schema", &schema().to_string()]
+        );
+        assert_eq!(inv.stdin, "hi");
+
+        let inv = p.invocation("hi", None, None).unwrap();
+        assert_eq!(inv.args, vec!["run"]);
+    }
+
+    #[test]
+    fn test_schema_file_and_prompt_argument() {
+        let p = provider(
+            spec(
+                r#"
+                program = "tool"
+                args = ["-p", "{prompt}"]
+                schema_args = ["--schema-file", "{schema_file}"]
+                prompt_delivery = "arg"
+                "#,
+            ),
+            "",
+        );
+
+        let inv = p.invocation("hi", Some(&schema()), None).unwrap();
+        assert_eq!(inv.args[..2], ["-p", "hi"]);
+        let written = std::fs::read_to_string(&inv.args[3]).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&written).unwrap(), schema());
+        assert!(inv.stdin.is_empty());
+    }
+
+    #[test]
+    fn test_inserted_values_are_not_expanded_again() {
+        let p = provider(
+            spec(
+                r#"
+                program = "tool"
+                args = ["--schema={schema}", "{prompt}"]
+                model_args = ["{model}"]
+                prompt_delivery = "arg"
+                "#,
+            ),
+            "",
+        );
+
+        let schema = json!({"type": "object", "description": "{prompt} {model}", "properties": {}});
+        let inv = p
+            .invocation("say {schema}", Some(&schema), Some("m1"))
+            .unwrap();
+        assert_eq!(
+            inv.args,
+            vec![
+                format!("--schema={schema}"),
+                "say {schema}".to_string(),
+                "m1".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prompt_argument_appended_without_placeholder() {
+        let p = provider(
+            spec(
+                r#"
+                program = "ollama"
+                args = ["run", "llama3"]
+                prompt_delivery = "arg"
+                append_schema_to_prompt = true
+                "#,
+            ),
+            "",
+        );
+
+        let inv = p.invocation("hi", Some(&schema()), None).unwrap();
+        assert_eq!(inv.args.len(), 3);
+        assert!(inv.args[2].starts_with("hi\n\n---\nRespond with ONLY valid JSON"));
+    }
+
+    #[tokio::test]
+    async fn test_output_pointer() {
+        let p = provider(
+            spec(
+                r#"
+                program = "tool"
+                output_pointer = "/result/data"
+                "#,
+            ),
+            r#"{"result": {"data": {"a": "x"}}}"#,
+        );
+
+        let output = p
+            .execute(&[Message::user("hi")], Some(&schema()), None, None)
+            .await
+            .unwrap();
+        assert_eq!(output.output, json!({"a": "x"}));
+    }
+
+    #[tokio::test]
+    async fn test_fenced_output() {
+        let p = provider(
+            spec(
+                r#"
+                program = "tool"
+                output = "fenced"
+                "#,
+            ),
+            "Sure!\n```json\n{\"a\": \"x\"}\n```\n",
+        );
+
+        let output = p
+            .execute(&[Message::user("hi")], Some(&schema()), None, None)
+            .await
+            .unwrap();
+        assert_eq!(output.output, json!({"a": "x"}));
+    }
+
+    #[tokio::test]
+    async fn test_text_pointer() {
+        let p = provider(
+            spec(
+                r#"
+                program = "tool"
+                text_pointer = "/response"
+                "#,
+            ),
+            r#"{"response": "plain text"}"#,
+        );
+
+        let output = p
+            .execute(&[Message::user("hi")], None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(output.output, json!("plain text"));
+    }
+}
@@ -2,10 +2,24 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use serde_json::Value;
+use tempfile::NamedTempFile;
+use tokio::sync::mpsc;
 
 use crate::error::AppError;
+use crate::provider::classify::{classify, Failure, Signature};
 use crate::provider::executor::Executor;
-use crate::provider::Provider;
+use crate::provider::{transcript, Backend, Completion, Message, Provider};
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        failure: Failure::Authentication,
+        patterns: &["codex login"],
+    },
+    Signature {
+        failure: Failure::UnknownModel,
+        patterns: &["does not exist or you do not have access"],
+    },
+];
 
 pub struct CodexProvider {
     executor: Arc<dyn Executor>,
@@ -15,55 +29,106 @@ impl CodexProvider {
     pub fn new(executor: Arc<dyn Executor>) -> Self {
         Self { executor }
     }
+
+    /// Writes the schema (if any) to a temp file and builds the `codex exec`
+    /// arguments. The returned file must be kept alive until the CLI has exited.
+    fn args(
+        schema: Option<&Value>,
+        model: Option<&str>,
+    ) -> Result<(Option<NamedTempFile>, Vec<String>), AppError> {
+        let mut args: Vec<String> = vec!["exec".into()];
+        if let Some(m) = model {
+            args.extend(["--model".into(), m.into()]);
+        }
+
+        let schema_file = match schema {
+            Some(schema) => {
+                let schema_file = tempfile::Builder::new()
+                    .suffix(".json")
+                    .tempfile()
+                    .map_err(|e| AppError::ProviderExecution {
+                        message: format!("failed to create temp file: {e}"),
+                        stderr: String::new(),
+                        stdout: String::new(),
+                    })?;
+
+                std::fs::write(schema_file.path(), serde_json::to_string(schema).unwrap())
+                    .map_err(|e| AppError::ProviderExecution {
+                        message: format!("failed to write schema: {e}"),
+                        stderr: String::new(),
+                        stdout: String::new(),
+                    })?;
+
+                let schema_path = schema_file.path().to_string_lossy().to_string();
+                args.extend(["--output-schema".into(), schema_path]);
+                Some(schema_file)
+            }
+            None => None,
+        };
+        args.push("--skip-git-repo-check".into());
+
+        Ok((schema_file, args))
+    }
+}
+
+fn parse_output(stdout: String, structured: bool) -> Result<Value, AppError> {
+    if !structured {
+        return Ok(Value::String(stdout.trim().to_string()));
+    }
+    serde_json::from_str(&stdout).map_err(|e| AppError::OutputParse {
+        message: format!("failed to parse output: {e}"),
+        stdout,
+    })
 }
 
 #[async_trait]
 impl Provider for CodexProvider {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "codex"
     }
 
+    fn backend(&self) -> Backend<'_> {
+        Backend::Program("codex")
+    }
+
+    fn signatures(&self) -> &'static [Signature] {
+        SIGNATURES
+    }
+
     async fn execute(
         &self,
-        prompt: &str,
-        schema: &Value,
+        messages: &[Message],
+        schema: Option<&Value>,
         model: Option<&str>,
         timeout_secs: Option<u64>,
-    ) -> Result<Value, AppError> {
-        let schema_file = tempfile::Builder::new()
-            .suffix(".json")
-            .tempfile()
-            .map_err(|e| AppError::ProviderExecution {
-                message: format!("failed to create temp file: {e}"),
-                stderr: String::new(),
-            })?;
-
-        std::fs::write(schema_file.path(), serde_json::to_string(schema).unwrap()).map_err(
-            |e| AppError::ProviderExecution {
-                message: format!("failed to write schema: {e}"),
-                stderr: String::new(),
-            },
-        )?;
-
-        let schema_path = schema_file.path().to_string_lossy().to_string();
-        let mut args: Vec<String> = vec!["exec".into()];
-        if let Some(m) = model {
-            args.extend(["--model".into(), m.into()]);
-        }
-        args.extend([
-            "--output-schema".into(),
-            schema_path,
-            "--skip-git-repo-check".into(),
-        ]);
+    ) -> Result<Completion, AppError> {
+        let (_schema_file, args) = Self::args(schema, model)?;
+
+        let output = self
+            .executor
+            .run("codex", &args, &transcript(messages), timeout_secs)
+            .await
+            .map_err(|e| classify("codex", SIGNATURES, e))?;
+
+        parse_output(output.stdout, schema.is_some()).map(Completion::from)
+    }
+
+    async fn execute_streaming(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+        chunks: mpsc::UnboundedSender<String>,
+    ) -> Result<Completion, AppError> {
+        let (_schema_file, args) = Self::args(schema, model)?;
 
         let output = self
             .executor
-            .run("codex", &args, prompt, timeout_secs)
-            .await?;
+            .run_streaming("codex", &args, &transcript(messages), timeout_secs, chunks)
+            .await
+            .map_err(|e| classify("codex", SIGNATURES, e))?;
 
-        serde_json::from_str(&output.stdout).map_err(|e| AppError::OutputParse {
-            message: format!("failed to parse output: {e}"),
-            stdout: output.stdout,
-        })
+        parse_output(output.stdout, schema.is_some()).map(Completion::from)
     }
 }
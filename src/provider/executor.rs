@@ -1,16 +1,18 @@
-use std::process::Stdio;
+use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use tokio::io::AsyncWriteExt;
-use tokio::process::Command;
+use dashmap::DashMap;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
 use tokio::time::timeout;
 use tracing::{debug, error, warn};
 
 use crate::error::AppError;
-
-const DEFAULT_TIMEOUT_SECS: u64 = 120;
+use crate::metrics::Metrics;
+use crate::provider::DEFAULT_TIMEOUT_SECS;
 
 #[derive(Clone)]
 pub struct CommandOutput {
@@ -29,22 +31,168 @@ pub trait Executor: Send + Sync {
         stdin_data: &str,
         timeout_secs: Option<u64>,
     ) -> Result<CommandOutput, AppError>;
+
+    /// Like [`Executor::run`], but forwards every stdout line to `stdout_tx`
+    /// as soon as the child writes it. The full output is still returned.
+    async fn run_streaming(
+        &self,
+        program: &str,
+        args: &[String],
+        stdin_data: &str,
+        timeout_secs: Option<u64>,
+        stdout_tx: mpsc::UnboundedSender<String>,
+    ) -> Result<CommandOutput, AppError>;
 }
 
-pub struct CliExecutor;
+#[derive(Default)]
+pub struct CliExecutor {
+    metrics: Metrics,
+    children: ChildProcesses,
+}
 
 impl CliExecutor {
-    pub fn new() -> Arc<dyn Executor> {
-        Arc::new(Self)
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(metrics: Metrics, children: ChildProcesses) -> Arc<dyn Executor> {
+        Arc::new(Self { metrics, children })
+    }
+}
+
+/// Running CLI children by PID, so shutdown can signal them.
+#[derive(Clone, Default)]
+pub struct ChildProcesses {
+    pids: Arc<DashMap<u32, String>>,
+}
+
+/// Removes the child from `ChildProcesses` when dropped.
+struct Tracked<'a> {
+    children: &'a ChildProcesses,
+    pid: Option<u32>,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            self.children.pids.remove(&pid);
+        }
+    }
+}
+
+impl ChildProcesses {
+    fn track(&self, program: &str, child: &Child) -> Tracked<'_> {
+        let pid = child.id();
+        if let Some(pid) = pid {
+            self.pids.insert(pid, program.to_string());
+        }
+        Tracked {
+            children: self,
+            pid,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pids.len()
+    }
+
+    /// Sends SIGTERM to every running child, then SIGKILL to those still
+    /// running after `grace`.
+    pub async fn terminate_all(&self, grace: Duration) {
+        if self.pids.is_empty() {
+            return;
+        }
+        self.signal_all(libc::SIGTERM);
+
+        let deadline = tokio::time::Instant::now() + grace;
+        while !self.pids.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        if !self.pids.is_empty() {
+            self.signal_all(libc::SIGKILL);
+        }
+    }
+
+    fn signal_all(&self, signal: libc::c_int) {
+        for entry in self.pids.iter() {
+            let (pid, program) = entry.pair();
+            warn!(pid, program = %program, signal, "signalling CLI process");
+            // SAFETY: kill(2) has no memory-safety preconditions. The PID is
+            // only tracked while its Child is alive and not yet reaped.
+            unsafe {
+                libc::kill(*pid as libc::pid_t, signal);
+            }
+        }
+    }
+}
+
+async fn spawn(program: &str, args: &[String], stdin_data: &str) -> Result<Child, AppError> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| AppError::ProviderExecution {
+            message: format!("failed to spawn {program}: {e}"),
+            stderr: String::new(),
+            stdout: String::new(),
+        })?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(stdin_data.as_bytes())
+            .await
+            .map_err(|e| AppError::ProviderExecution {
+                message: format!("failed to write to stdin: {e}"),
+                stderr: String::new(),
+                stdout: String::new(),
+            })?;
     }
+
+    Ok(child)
 }
 
-impl Default for CliExecutor {
-    fn default() -> Self {
-        Self
+fn timed_out(program: &str, timeout_secs: u64) -> AppError {
+    warn!(
+        provider = program,
+        timeout_secs, "process timed out, killing"
+    );
+    AppError::Timeout {
+        provider: program.to_string(),
+        timeout_secs,
     }
 }
 
+fn wait_failed(program: &str, e: std::io::Error) -> AppError {
+    AppError::ProviderExecution {
+        message: format!("failed to wait for {program}: {e}"),
+        stderr: String::new(),
+        stdout: String::new(),
+    }
+}
+
+fn finish(
+    program: &str,
+    status: ExitStatus,
+    stdout: String,
+    stderr: String,
+) -> Result<CommandOutput, AppError> {
+    if !stderr.is_empty() {
+        debug!(provider = program, stderr = %stderr, "stderr output");
+    }
+
+    if !status.success() {
+        error!(provider = program, stderr = %stderr, "{program} failed");
+        return Err(AppError::ProviderExecution {
+            message: format!("{program} exited with status: {status}"),
+            stderr,
+            stdout,
+        });
+    }
+
+    Ok(CommandOutput { stdout, stderr })
+}
+
 #[async_trait]
 impl Executor for CliExecutor {
     async fn run(
@@ -56,59 +204,66 @@ impl Executor for CliExecutor {
     ) -> Result<CommandOutput, AppError> {
         let timeout_secs = timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
 
-        let mut child = Command::new(program)
-            .args(args)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .kill_on_drop(true)
-            .spawn()
-            .map_err(|e| AppError::ProviderExecution {
-                message: format!("failed to spawn {program}: {e}"),
-                stderr: String::new(),
-            })?;
-
-        if let Some(mut stdin) = child.stdin.take() {
-            stdin.write_all(stdin_data.as_bytes()).await.map_err(|e| {
-                AppError::ProviderExecution {
-                    message: format!("failed to write to stdin: {e}"),
-                    stderr: String::new(),
-                }
-            })?;
-        }
+        let child = spawn(program, args, stdin_data).await?;
+        let _tracked = self.children.track(program, &child);
 
         let output = timeout(Duration::from_secs(timeout_secs), child.wait_with_output())
             .await
-            .map_err(|_| {
-                warn!(
-                    provider = program,
-                    timeout_secs, "process timed out, killing"
-                );
-                AppError::Timeout {
-                    provider: program.to_string(),
-                    timeout_secs,
-                }
-            })?
-            .map_err(|e| AppError::ProviderExecution {
-                message: format!("failed to wait for {program}: {e}"),
-                stderr: String::new(),
-            })?;
+            .map_err(|_| timed_out(program, timeout_secs))?
+            .map_err(|e| wait_failed(program, e))?;
 
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        self.metrics.record_exit(program, output.status.code());
+        finish(
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        )
+    }
 
-        if !stderr.is_empty() {
-            debug!(provider = program, stderr = %stderr, "stderr output");
-        }
+    async fn run_streaming(
+        &self,
+        program: &str,
+        args: &[String],
+        stdin_data: &str,
+        timeout_secs: Option<u64>,
+        stdout_tx: mpsc::UnboundedSender<String>,
+    ) -> Result<CommandOutput, AppError> {
+        let timeout_secs = timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
 
-        if !output.status.success() {
-            error!(provider = program, stderr = %stderr, "{program} failed");
-            return Err(AppError::ProviderExecution {
-                message: format!("{program} exited with status: {}", output.status),
-                stderr,
-            });
-        }
+        let mut child = spawn(program, args, stdin_data).await?;
+        let _tracked = self.children.track(program, &child);
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+
+        let read_stdout = async {
+            let mut lines = BufReader::new(stdout).lines();
+            let mut collected = String::new();
+            while let Some(line) = lines.next_line().await? {
+                collected.push_str(&line);
+                collected.push('\n');
+                // The receiver going away only means nobody is watching anymore.
+                let _ = stdout_tx.send(line);
+            }
+            Ok::<_, std::io::Error>(collected)
+        };
+
+        let read_stderr = async {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).await?;
+            Ok::<_, std::io::Error>(String::from_utf8_lossy(&buf).to_string())
+        };
+
+        let (status, stdout, stderr) = timeout(Duration::from_secs(timeout_secs), async {
+            let (stdout, stderr) = tokio::try_join!(read_stdout, read_stderr)?;
+            let status = child.wait().await?;
+            Ok::<_, std::io::Error>((status, stdout, stderr))
+        })
+        .await
+        .map_err(|_| timed_out(program, timeout_secs))?
+        .map_err(|e| wait_failed(program, e))?;
 
-        Ok(CommandOutput { stdout, stderr })
+        self.metrics.record_exit(program, status.code());
+        finish(program, status, stdout, stderr)
     }
 }
@@ -2,10 +2,31 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use serde_json::Value;
+use tokio::sync::mpsc;
 
 use crate::error::AppError;
+use crate::provider::classify::{classify, Failure, Signature};
 use crate::provider::executor::Executor;
-use crate::provider::Provider;
+use crate::provider::{transcript, Backend, Completion, Message, Provider};
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        failure: Failure::Authentication,
+        patterns: &["gemini_api_key", "please set an auth method"],
+    },
+    Signature {
+        failure: Failure::UnknownModel,
+        patterns: &["is not found for api version"],
+    },
+    Signature {
+        failure: Failure::ContextTooLong,
+        patterns: &["exceeds the maximum number of tokens"],
+    },
+    Signature {
+        failure: Failure::Refused,
+        patterns: &["prohibited_content"],
+    },
+];
 
 pub struct GeminiProvider {
     executor: Arc<dyn Executor>,
@@ -15,48 +36,102 @@ impl GeminiProvider {
     pub fn new(executor: Arc<dyn Executor>) -> Self {
         Self { executor }
     }
+
+    fn prepare(
+        prompt: &str,
+        schema: Option<&Value>,
+        model: Option<&str>,
+    ) -> Result<(String, Vec<String>), AppError> {
+        let combined_prompt = match schema {
+            Some(schema) => with_schema_instruction(prompt, schema)?,
+            None => prompt.to_string(),
+        };
+
+        let mut args: Vec<String> = Vec::new();
+        if let Some(m) = model {
+            args.extend(["--model".into(), m.into()]);
+        }
+
+        Ok((combined_prompt, args))
+    }
+}
+
+/// Appends an instruction to answer with JSON matching `schema`, for CLIs
+/// without native structured output.
+pub(super) fn with_schema_instruction(prompt: &str, schema: &Value) -> Result<String, AppError> {
+    let schema_str = serde_json::to_string_pretty(schema)
+        .map_err(|e| AppError::InvalidSchema(format!("{e}")))?;
+    Ok(format!(
+        "{prompt}\n\n---\nRespond with ONLY valid JSON matching this schema. No explanations, no markdown code blocks:\n{schema_str}"
+    ))
+}
+
+fn parse_output(stdout: String, structured: bool) -> Result<Value, AppError> {
+    if !structured {
+        return Ok(Value::String(stdout.trim().to_string()));
+    }
+
+    let json_str = extract_json(&stdout).unwrap_or(&stdout);
+
+    serde_json::from_str(json_str).map_err(|e| AppError::OutputParse {
+        message: format!("failed to parse output: {e}"),
+        stdout: stdout.clone(),
+    })
 }
 
 #[async_trait]
 impl Provider for GeminiProvider {
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "gemini"
     }
 
+    fn backend(&self) -> Backend<'_> {
+        Backend::Program("gemini")
+    }
+
+    fn signatures(&self) -> &'static [Signature] {
+        SIGNATURES
+    }
+
     async fn execute(
         &self,
-        prompt: &str,
-        schema: &Value,
+        messages: &[Message],
+        schema: Option<&Value>,
         model: Option<&str>,
         timeout_secs: Option<u64>,
-    ) -> Result<Value, AppError> {
-        let schema_str = serde_json::to_string_pretty(schema)
-            .map_err(|e| AppError::InvalidSchema(format!("{e}")))?;
-
-        let combined_prompt = format!(
-            "{prompt}\n\n---\nRespond with ONLY valid JSON matching this schema. No explanations, no markdown code blocks:\n{schema_str}"
-        );
-
-        let mut args: Vec<String> = Vec::new();
-        if let Some(m) = model {
-            args.extend(["--model".into(), m.into()]);
-        }
+    ) -> Result<Completion, AppError> {
+        let (combined_prompt, args) = Self::prepare(&transcript(messages), schema, model)?;
 
         let output = self
             .executor
             .run("gemini", &args, &combined_prompt, timeout_secs)
-            .await?;
+            .await
+            .map_err(|e| classify("gemini", SIGNATURES, e))?;
+
+        parse_output(output.stdout, schema.is_some()).map(Completion::from)
+    }
 
-        let json_str = extract_json(&output.stdout).unwrap_or(&output.stdout);
+    async fn execute_streaming(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+        chunks: mpsc::UnboundedSender<String>,
+    ) -> Result<Completion, AppError> {
+        let (combined_prompt, args) = Self::prepare(&transcript(messages), schema, model)?;
+
+        let output = self
+            .executor
+            .run_streaming("gemini", &args, &combined_prompt, timeout_secs, chunks)
+            .await
+            .map_err(|e| classify("gemini", SIGNATURES, e))?;
 
-        serde_json::from_str(json_str).map_err(|e| AppError::OutputParse {
-            message: format!("failed to parse output: {e}"),
-            stdout: output.stdout,
-        })
+        parse_output(output.stdout, schema.is_some()).map(Completion::from)
     }
 }
 
-fn extract_json(text: &str) -> Option<&str> {
+pub(super) fn extract_json(text: &str) -> Option<&str> {
     if let Some(start) = text.find("```json") {
         let content_start = start + 7;
         if let Some(end) = text[content_start..].find("```") {
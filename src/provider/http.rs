@@ -0,0 +1,802 @@
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::config::{HttpProviderConfig, ProviderKind};
+use crate::error::AppError;
+use crate::provider::classify::classify;
+use crate::provider::{
+    split_system, Backend, Completion, Message, Provider, Usage, DEFAULT_TIMEOUT_SECS,
+};
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Used when `max_tokens` is not configured, since the Messages API
+/// requires a limit.
+const DEFAULT_ANTHROPIC_MAX_TOKENS: u32 = 4096;
+
+/// Name of the tool Anthropic requests are forced to call with the output.
+const OUTPUT_TOOL: &str = "output";
+
+/// Shared by all HTTP providers so connections are reused across requests.
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(Client::new)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A provider that calls an OpenAI-compatible Chat Completions API, an
+/// Anthropic Messages API or an Ollama server directly, using the API's own
+/// structured output.
+pub struct HttpProvider {
+    name: String,
+    kind: ProviderKind,
+    spec: HttpProviderConfig,
+}
+
+impl HttpProvider {
+    pub fn new(name: &str, kind: ProviderKind, spec: HttpProviderConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            spec,
+        }
+    }
+
+    fn base_url(&self) -> &str {
+        let default = match self.kind {
+            ProviderKind::Anthropic => "https://api.anthropic.com/v1",
+            ProviderKind::Ollama => "http://localhost:11434",
+            _ => "https://api.openai.com/v1",
+        };
+        self.spec
+            .base_url
+            .as_deref()
+            .unwrap_or(default)
+            .trim_end_matches('/')
+    }
+
+    /// A request to `path` below the base URL, with the API key if one is
+    /// configured.
+    fn request(&self, method: reqwest::Method, path: &str) -> Result<RequestBuilder, AppError> {
+        let mut request = client().request(method, format!("{}{path}", self.base_url()));
+        if self.kind == ProviderKind::Anthropic {
+            request = request.header("anthropic-version", ANTHROPIC_VERSION);
+        }
+
+        let Some(var) = &self.spec.api_key_env else {
+            return Ok(request);
+        };
+        let key = std::env::var(var).map_err(|_| AppError::AuthenticationRequired {
+            provider: self.name.clone(),
+            message: format!("environment variable {var} is not set"),
+        })?;
+        Ok(match self.kind {
+            ProviderKind::Anthropic => request.header("x-api-key", key),
+            _ => request.bearer_auth(key),
+        })
+    }
+
+    /// Sends the request and returns the raw body of a successful response.
+    /// Without `timeout_secs` the request gets the same default as CLI runs.
+    async fn send(
+        &self,
+        request: RequestBuilder,
+        timeout_secs: Option<u64>,
+    ) -> Result<String, AppError> {
+        let timeout_secs = timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let request = request.timeout(Duration::from_secs(timeout_secs));
+        let response = request.send().await.map_err(|e| {
+            if e.is_timeout() {
+                AppError::Timeout {
+                    provider: self.name.clone(),
+                    timeout_secs,
+                }
+            } else {
+                AppError::ProviderExecution {
+                    message: format!("request to {} failed: {e}", self.base_url()),
+                    stderr: String::new(),
+                    stdout: String::new(),
+                }
+            }
+        })?;
+
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        let body = response
+            .text()
+            .await
+            .map_err(|e| AppError::ProviderExecution {
+                message: format!("failed to read response from {}: {e}", self.base_url()),
+                stderr: String::new(),
+                stdout: String::new(),
+            })?;
+        if status.is_success() {
+            return Ok(body);
+        }
+        Err(self.status_error(status, retry_after, body))
+    }
+
+    /// Maps an error response onto the errors CLI failures are classified
+    /// into: by status code first, then by the signatures in its message.
+    fn status_error(&self, status: StatusCode, retry_after: Option<u64>, body: String) -> AppError {
+        let message = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|v| {
+                ["/error/message", "/message", "/error"]
+                    .iter()
+                    .find_map(|pointer| v.pointer(pointer)?.as_str().map(str::to_string))
+            })
+            .unwrap_or_else(|| body.trim().chars().take(300).collect());
+        let provider = self.name.clone();
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                AppError::AuthenticationRequired { provider, message }
+            }
+            StatusCode::TOO_MANY_REQUESTS => AppError::QuotaExceeded {
+                provider,
+                message,
+                resets_at: retry_after.map(|secs| unix_now() + secs),
+            },
+            _ => classify(
+                &self.name,
+                &[],
+                AppError::ProviderExecution {
+                    message: format!("{} returned {status}: {message}", self.base_url()),
+                    stderr: message,
+                    stdout: String::new(),
+                },
+            ),
+        }
+    }
+
+    fn model<'a>(&'a self, model: Option<&'a str>) -> Result<&'a str, AppError> {
+        model
+            .or(self.spec.default_model.as_deref())
+            .ok_or_else(|| AppError::AutoModelNotSupported(self.name.clone()))
+    }
+
+    fn openai_body(&self, messages: &[Message], schema: Option<&Value>, model: &str) -> Value {
+        let mut body = json!({"model": model, "messages": messages});
+        if let Some(max_tokens) = self.spec.max_tokens {
+            body["max_tokens"] = max_tokens.into();
+        }
+        if let Some(schema) = schema {
+            body["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {"name": OUTPUT_TOOL, "schema": schema},
+            });
+        }
+        body
+    }
+
+    fn anthropic_body(&self, messages: &[Message], schema: Option<&Value>, model: &str) -> Value {
+        let (system, messages) = split_system(messages);
+        let mut body = json!({
+            "model": model,
+            "max_tokens": self.spec.max_tokens.unwrap_or(DEFAULT_ANTHROPIC_MAX_TOKENS),
+            "messages": messages,
+        });
+        if let Some(system) = system {
+            body["system"] = system.into();
+        }
+        // Forcing a tool call whose input schema is the request schema is
+        // how the Messages API returns structured output
+        if let Some(schema) = schema {
+            body["tools"] = json!([{
+                "name": OUTPUT_TOOL,
+                "description": "Respond by calling this tool with your answer.",
+                "input_schema": schema,
+            }]);
+            body["tool_choice"] = json!({"type": "tool", "name": OUTPUT_TOOL});
+        }
+        body
+    }
+
+    /// Ollama's native `/api/chat`, which constrains the reply to the schema
+    /// given as `format`.
+    fn ollama_body(&self, messages: &[Message], schema: Option<&Value>, model: &str) -> Value {
+        let mut body = json!({"model": model, "messages": messages, "stream": false});
+        if let Some(max_tokens) = self.spec.max_tokens {
+            body["options"] = json!({"num_predict": max_tokens});
+        }
+        if let Some(schema) = schema {
+            body["format"] = schema.clone();
+        }
+        body
+    }
+
+    /// Runs one completion and returns it together with the raw response.
+    async fn complete(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+    ) -> Result<(Completion, String), AppError> {
+        let model = self.model(model)?;
+        let (path, body) = match self.kind {
+            ProviderKind::Anthropic => ("/messages", self.anthropic_body(messages, schema, model)),
+            ProviderKind::Ollama => ("/api/chat", self.ollama_body(messages, schema, model)),
+            _ => (
+                "/chat/completions",
+                self.openai_body(messages, schema, model),
+            ),
+        };
+        let request = self.request(reqwest::Method::POST, path)?.json(&body);
+        let raw = self.send(request, timeout_secs).await?;
+
+        let response: Value = serde_json::from_str(&raw).map_err(|e| AppError::OutputParse {
+            message: format!("failed to parse response: {e}"),
+            stdout: raw.clone(),
+        })?;
+        let completion = match self.kind {
+            ProviderKind::Anthropic => self.parse_anthropic(&response, schema.is_some(), &raw),
+            ProviderKind::Ollama => self.parse_ollama(&response, schema.is_some(), &raw),
+            _ => self.parse_openai(&response, schema.is_some(), &raw),
+        }?;
+        Ok((completion, raw))
+    }
+
+    fn refused(&self, message: &str) -> AppError {
+        AppError::ContentRefused {
+            provider: self.name.clone(),
+            message: message.to_string(),
+        }
+    }
+
+    fn parse_openai(
+        &self,
+        response: &Value,
+        structured: bool,
+        raw: &str,
+    ) -> Result<Completion, AppError> {
+        let choice = &response["choices"][0];
+        if let Some(refusal) = choice["message"]["refusal"].as_str() {
+            return Err(self.refused(refusal));
+        }
+        if choice["finish_reason"] == "content_filter" {
+            return Err(self.refused("stopped by the content filter"));
+        }
+        let content =
+            choice["message"]["content"]
+                .as_str()
+                .ok_or_else(|| AppError::OutputParse {
+                    message: "missing 'choices[0].message.content'".to_string(),
+                    stdout: raw.to_string(),
+                })?;
+        let output = content_output(content, structured)?;
+
+        let usage = &response["usage"];
+        Ok(Completion {
+            output,
+            model: response["model"].as_str().map(str::to_string),
+            usage: usage.is_object().then(|| Usage {
+                input_tokens: usage["prompt_tokens"].as_u64(),
+                output_tokens: usage["completion_tokens"].as_u64(),
+                cache_read_input_tokens: usage["prompt_tokens_details"]["cached_tokens"].as_u64(),
+                cache_creation_input_tokens: None,
+                cost_usd: None,
+            }),
+        })
+    }
+
+    fn parse_anthropic(
+        &self,
+        response: &Value,
+        structured: bool,
+        raw: &str,
+    ) -> Result<Completion, AppError> {
+        if response["stop_reason"] == "refusal" {
+            return Err(self.refused("the model declined to answer"));
+        }
+        let blocks = response["content"].as_array().cloned().unwrap_or_default();
+        let output = if structured {
+            blocks
+                .iter()
+                .find(|b| b["type"] == "tool_use" && b["name"] == OUTPUT_TOOL)
+                .map(|b| b["input"].clone())
+                .ok_or_else(|| AppError::OutputParse {
+                    message: format!("missing '{OUTPUT_TOOL}' tool_use block"),
+                    stdout: raw.to_string(),
+                })?
+        } else {
+            let text: Vec<&str> = blocks
+                .iter()
+                .filter(|b| b["type"] == "text")
+                .filter_map(|b| b["text"].as_str())
+                .collect();
+            Value::String(text.join(""))
+        };
+
+        let usage = &response["usage"];
+        Ok(Completion {
+            output,
+            model: response["model"].as_str().map(str::to_string),
+            usage: usage.is_object().then(|| Usage {
+                input_tokens: usage["input_tokens"].as_u64(),
+                output_tokens: usage["output_tokens"].as_u64(),
+                cache_read_input_tokens: usage["cache_read_input_tokens"].as_u64(),
+                cache_creation_input_tokens: usage["cache_creation_input_tokens"].as_u64(),
+                cost_usd: None,
+            }),
+        })
+    }
+
+    fn parse_ollama(
+        &self,
+        response: &Value,
+        structured: bool,
+        raw: &str,
+    ) -> Result<Completion, AppError> {
+        let content =
+            response["message"]["content"]
+                .as_str()
+                .ok_or_else(|| AppError::OutputParse {
+                    message: "missing 'message.content'".to_string(),
+                    stdout: raw.to_string(),
+                })?;
+        let output = content_output(content, structured)?;
+
+        let input_tokens = response["prompt_eval_count"].as_u64();
+        let output_tokens = response["eval_count"].as_u64();
+        Ok(Completion {
+            output,
+            model: response["model"].as_str().map(str::to_string),
+            usage: (input_tokens.is_some() || output_tokens.is_some()).then_some(Usage {
+                input_tokens,
+                output_tokens,
+                cache_read_input_tokens: None,
+                cache_creation_input_tokens: None,
+                cost_usd: None,
+            }),
+        })
+    }
+}
+
+/// The output of a reply whose text holds the JSON document in structured
+/// mode.
+fn content_output(content: &str, structured: bool) -> Result<Value, AppError> {
+    if !structured {
+        return Ok(Value::String(content.to_string()));
+    }
+    serde_json::from_str(content).map_err(|e| AppError::OutputParse {
+        message: format!("content is not JSON: {e}"),
+        stdout: content.to_string(),
+    })
+}
+
+#[async_trait]
+impl Provider for HttpProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn backend(&self) -> Backend<'_> {
+        Backend::Url(self.base_url())
+    }
+
+    async fn ping(&self, timeout_secs: u64) -> Result<(), AppError> {
+        let path = match self.kind {
+            ProviderKind::Ollama => "/api/tags",
+            _ => "/models",
+        };
+        let request = self.request(reqwest::Method::GET, path)?;
+        self.send(request, Some(timeout_secs)).await.map(|_| ())
+    }
+
+    async fn execute(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+    ) -> Result<Completion, AppError> {
+        let (completion, _) = self.complete(messages, schema, model, timeout_secs).await?;
+        Ok(completion)
+    }
+
+    /// The response arrives as a whole, so it is sent as a single chunk.
+    async fn execute_streaming(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+        chunks: mpsc::UnboundedSender<String>,
+    ) -> Result<Completion, AppError> {
+        let (completion, raw) = self.complete(messages, schema, model, timeout_secs).await?;
+        let _ = chunks.send(raw);
+        Ok(completion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ModelSettings, ProviderSettings};
+    use crate::provider::Role;
+    use crate::tests::{mock_executor, test_state_with, valid_schema};
+    use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::Arc;
+
+    /// Answers like the real APIs would: the output echoes the last message
+    /// and the credentials it was sent with. Model "locked" is rejected and
+    /// model "busy" is rate limited.
+    async fn fake_api(req: HttpRequest, body: web::Json<Value>) -> HttpResponse {
+        match body["model"].as_str() {
+            Some("locked") => {
+                return HttpResponse::Unauthorized()
+                    .json(json!({"error": {"message": "invalid x-api-key"}}))
+            }
+            Some("busy") => {
+                return HttpResponse::TooManyRequests()
+                    .insert_header(("retry-after", "30"))
+                    .json(json!({"error": {"message": "slow down"}}))
+            }
+            _ => {}
+        }
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let last = body["messages"].as_array().unwrap().last().unwrap()["content"].clone();
+        if req.path().ends_with("/messages") {
+            let mut content = vec![json!({"type": "text", "text": last})];
+            if let Some(tool) = body["tools"][0]["name"].as_str() {
+                content = vec![json!({
+                    "type": "tool_use",
+                    "name": tool,
+                    "input": {
+                        "message": last,
+                        "system": body["system"],
+                        "key": header("x-api-key"),
+                    },
+                })];
+            }
+            HttpResponse::Ok().json(json!({
+                "model": body["model"],
+                "content": content,
+                "stop_reason": "end_turn",
+                "usage": {"input_tokens": 12, "output_tokens": 3},
+            }))
+        } else {
+            let content = if body["response_format"]["type"] == "json_schema" {
+                json!({"message": last, "authorization": header("authorization")}).to_string()
+            } else {
+                last.as_str().unwrap().to_string()
+            };
+            HttpResponse::Ok().json(json!({
+                "model": body["model"],
+                "choices": [{"message": {"content": content}, "finish_reason": "stop"}],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 2},
+            }))
+        }
+    }
+
+    /// Ollama's `/api/chat`: the output echoes the last message and the
+    /// request's `format` and `options`. Model "slow" takes three seconds.
+    async fn fake_ollama(body: web::Json<Value>) -> HttpResponse {
+        if body["model"] == "slow" {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+        }
+        if body["model"] == "missing" {
+            return HttpResponse::NotFound()
+                .json(json!({"error": "model \"missing\" not found, try pulling it first"}));
+        }
+        let last = body["messages"].as_array().unwrap().last().unwrap()["content"].clone();
+        let content = if body["format"].is_object() {
+            json!({
+                "message": last,
+                "format": body["format"]["type"],
+                "num_predict": body["options"]["num_predict"],
+            })
+            .to_string()
+        } else {
+            last.as_str().unwrap().to_string()
+        };
+        HttpResponse::Ok().json(json!({
+            "model": body["model"],
+            "message": {"role": "assistant", "content": content},
+            "done": body["stream"] == false,
+            "prompt_eval_count": 26,
+            "eval_count": 8,
+        }))
+    }
+
+    /// Starts the fake API on a free port and returns its base URL.
+    fn start_fake_api() -> String {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/v1/chat/completions", web::post().to(fake_api))
+                .route("/v1/messages", web::post().to(fake_api))
+                .route("/v1/models", web::get().to(HttpResponse::Ok))
+                .route("/v1/api/chat", web::post().to(fake_ollama))
+                .route("/v1/api/tags", web::get().to(HttpResponse::Ok))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{addr}/v1")
+    }
+
+    fn provider(kind: ProviderKind, base_url: &str, api_key_env: Option<&str>) -> HttpProvider {
+        HttpProvider::new(
+            "api",
+            kind,
+            HttpProviderConfig {
+                base_url: Some(base_url.to_string()),
+                api_key_env: api_key_env.map(str::to_string),
+                default_model: Some("default".to_string()),
+                max_tokens: None,
+            },
+        )
+    }
+
+    fn schema() -> Value {
+        json!({"type": "object", "properties": {"message": {"type": "string"}}})
+    }
+
+    #[actix_web::test]
+    async fn test_openai_compatible() {
+        std::env::set_var("LLM_MUX_TEST_OPENAI_KEY", "sk-test");
+        let base_url = start_fake_api();
+        let p = provider(
+            ProviderKind::Openai,
+            &base_url,
+            Some("LLM_MUX_TEST_OPENAI_KEY"),
+        );
+        assert_eq!(p.backend(), Backend::Url(base_url.as_str()));
+        p.ping(5).await.unwrap();
+
+        let completion = p
+            .execute(&[Message::user("hi")], Some(&schema()), Some("gpt"), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            completion.output,
+            json!({"message": "hi", "authorization": "Bearer sk-test"})
+        );
+        assert_eq!(completion.model.as_deref(), Some("gpt"));
+        let usage = completion.usage.unwrap();
+        assert_eq!(
+            (usage.input_tokens, usage.output_tokens),
+            (Some(10), Some(2))
+        );
+
+        let completion = p
+            .execute(&[Message::user("plain")], None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(completion.output, json!("plain"));
+        assert_eq!(completion.model.as_deref(), Some("default"));
+    }
+
+    #[actix_web::test]
+    async fn test_anthropic_messages() {
+        std::env::set_var("LLM_MUX_TEST_ANTHROPIC_KEY", "ak-test");
+        let base_url = start_fake_api();
+        let p = provider(
+            ProviderKind::Anthropic,
+            &base_url,
+            Some("LLM_MUX_TEST_ANTHROPIC_KEY"),
+        );
+        let messages = [
+            Message {
+                role: Role::System,
+                content: "be brief".into(),
+            },
+            Message::user("hi"),
+        ];
+
+        let completion = p
+            .execute(&messages, Some(&schema()), None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            completion.output,
+            json!({"message": "hi", "system": "be brief", "key": "ak-test"})
+        );
+        assert_eq!(completion.usage.unwrap().input_tokens, Some(12));
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let completion = p
+            .execute_streaming(&messages, None, None, None, tx)
+            .await
+            .unwrap();
+        assert_eq!(completion.output, json!("hi"));
+        assert!(rx.recv().await.unwrap().contains("\"content\""));
+    }
+
+    #[actix_web::test]
+    async fn test_error_statuses_are_classified() {
+        let base_url = start_fake_api();
+        let p = provider(ProviderKind::Openai, &base_url, None);
+        let hi = [Message::user("hi")];
+
+        let e = p
+            .execute(&hi, None, Some("locked"), None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&e, AppError::AuthenticationRequired { message, .. } if message == "invalid x-api-key")
+        );
+
+        let before = unix_now();
+        let e = p.execute(&hi, None, Some("busy"), None).await.unwrap_err();
+        let AppError::QuotaExceeded { resets_at, .. } = e else {
+            panic!("expected QuotaExceeded, got {e:?}");
+        };
+        assert!(resets_at.unwrap() >= before + 30);
+
+        let p = provider(
+            ProviderKind::Openai,
+            &base_url,
+            Some("LLM_MUX_TEST_UNSET_KEY"),
+        );
+        let e = p.execute(&hi, None, None, None).await.unwrap_err();
+        assert!(matches!(e, AppError::AuthenticationRequired { .. }));
+
+        // Nothing listens on the discard port
+        let p = provider(ProviderKind::Openai, "http://127.0.0.1:9/v1", None);
+        let e = p.execute(&hi, None, None, None).await.unwrap_err();
+        assert!(matches!(e, AppError::ProviderExecution { .. }));
+    }
+
+    /// An endpoint that accepts connections but never answers. The listener
+    /// must be kept alive.
+    fn start_silent_api() -> (std::net::TcpListener, String) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+        (listener, base_url)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unresponsive_api_times_out_by_default() {
+        let (_listener, base_url) = start_silent_api();
+        let p = provider(ProviderKind::Openai, &base_url, None);
+        let e = p
+            .execute(&[Message::user("hi")], None, None, None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(e, AppError::Timeout { timeout_secs, .. } if timeout_secs == DEFAULT_TIMEOUT_SECS),
+            "{e:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_ollama_times_out_by_default() {
+        let (_listener, base_url) = start_silent_api();
+        let p = provider(ProviderKind::Ollama, base_url.trim_end_matches("/v1"), None);
+        let e = p
+            .execute(&[Message::user("hi")], Some(&schema()), None, None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(e, AppError::Timeout { timeout_secs, .. } if timeout_secs == DEFAULT_TIMEOUT_SECS),
+            "{e:?}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_ollama_chat() {
+        let base_url = start_fake_api();
+        let mut p = provider(ProviderKind::Ollama, &base_url, None);
+        p.spec.max_tokens = Some(256);
+        p.ping(5).await.unwrap();
+
+        let completion = p
+            .execute(
+                &[Message::user("hi")],
+                Some(&schema()),
+                Some("llama3.2"),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            completion.output,
+            json!({"message": "hi", "format": "object", "num_predict": 256})
+        );
+        assert_eq!(completion.model.as_deref(), Some("llama3.2"));
+        let usage = completion.usage.unwrap();
+        assert_eq!(
+            (usage.input_tokens, usage.output_tokens),
+            (Some(26), Some(8))
+        );
+
+        let completion = p
+            .execute(&[Message::user("plain")], None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(completion.output, json!("plain"));
+
+        let e = p
+            .execute(&[Message::user("hi")], None, Some("missing"), None)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("try pulling it first"));
+    }
+
+    #[actix_web::test]
+    async fn test_ollama_through_generate() {
+        let base_url = start_fake_api();
+        let model = |timeout_secs| ModelSettings {
+            concurrent: Some(1),
+            timeout_secs: Some(timeout_secs),
+            ..Default::default()
+        };
+        let state = test_state_with(mock_executor(), |state| {
+            let current = Arc::make_mut(state.settings.get_mut().unwrap());
+            current.provider_settings.insert(
+                "local".into(),
+                ProviderSettings {
+                    kind: ProviderKind::Ollama,
+                    http: Some(HttpProviderConfig {
+                        base_url: Some(base_url),
+                        api_key_env: None,
+                        default_model: None,
+                        max_tokens: None,
+                    }),
+                    ..Default::default()
+                },
+            );
+            for (name, timeout_secs) in [("llama3.2", 60), ("slow", 1)] {
+                let key = ("local".to_string(), name.to_string());
+                current.model_settings.insert(key, model(timeout_secs));
+                state
+                    .rate_limiter
+                    .register("local".into(), name.into(), model(timeout_secs));
+            }
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+
+        let generate = |model: &str| {
+            test::TestRequest::post()
+                .uri("/generate")
+                .set_json(json!({
+                    "provider": "local",
+                    "model": model,
+                    "prompt": "hello",
+                    "schema": valid_schema(),
+                }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, generate("llama3.2")).await;
+        assert_eq!(resp.status(), 200);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["output"]["message"], "hello");
+
+        // The model's timeout_secs applies to the HTTP call
+        let resp = test::call_service(&app, generate("slow")).await;
+        assert_eq!(resp.status(), 504);
+
+        let resp = test::call_service(&app, generate("mistral")).await;
+        assert_eq!(resp.status(), 400);
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+        }
+    }
+}
+
+/// One turn of a conversation sent to a provider.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Message {
+    pub fn user(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::User,
+            content: content.into(),
+        }
+    }
+}
+
+/// Renders a conversation as the single prompt the CLIs read. A lone user
+/// message is passed through unchanged; anything longer becomes a transcript
+/// of `[role]` blocks separated by blank lines.
+pub fn transcript(messages: &[Message]) -> String {
+    if let [only] = messages {
+        if only.role == Role::User {
+            return only.content.clone();
+        }
+    }
+
+    messages
+        .iter()
+        .map(|m| format!("[{}]\n{}", m.role.as_str(), m.content))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Separates the system messages, joined by blank lines, from the rest of
+/// the conversation, for CLIs that take the system prompt as a flag.
+pub fn split_system(messages: &[Message]) -> (Option<String>, Vec<Message>) {
+    let (system, rest): (Vec<_>, Vec<_>) = messages
+        .iter()
+        .cloned()
+        .partition(|m| m.role == Role::System);
+    let system = (!system.is_empty()).then(|| {
+        system
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    });
+    (system, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    fn test_transcript() {
+        assert_eq!(transcript(&[Message::user("hi")]), "hi");
+
+        let messages = [
+            message(Role::System, "be brief"),
+            message(Role::User, "2+2?"),
+            message(Role::Assistant, "4"),
+            message(Role::User, "3+3?"),
+        ];
+        assert_eq!(
+            transcript(&messages),
+            "[system]\nbe brief\n\n[user]\n2+2?\n\n[assistant]\n4\n\n[user]\n3+3?"
+        );
+
+        let (system, rest) = split_system(&messages);
+        assert_eq!(system.as_deref(), Some("be brief"));
+        assert_eq!(rest, messages[1..]);
+        assert_eq!(split_system(&[Message::user("hi")]).0, None);
+    }
+}
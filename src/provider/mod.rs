@@ -1,38 +1,159 @@
+pub mod classify;
 mod claude;
+mod cli;
 mod codex;
 pub mod executor;
 mod gemini;
+mod http;
+mod message;
 
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use serde::Serialize;
 use serde_json::Value;
+use tokio::sync::mpsc;
 
+use crate::config::ProviderSettings;
 use crate::error::AppError;
+use classify::Signature;
 
 pub use claude::ClaudeProvider;
+pub use cli::CliProvider;
 pub use codex::CodexProvider;
-pub use executor::{CliExecutor, Executor};
+pub use executor::{ChildProcesses, CliExecutor, Executor};
 pub use gemini::GeminiProvider;
+pub use http::HttpProvider;
+pub use message::{split_system, transcript, Message, Role};
+
+/// How long a run may take when neither the model nor the provider sets
+/// `timeout_secs`, for CLIs and HTTP APIs alike.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 120;
+
+/// What a provider runs requests on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend<'a> {
+    /// An executable looked up on `PATH`.
+    Program(&'a str),
+    /// The base URL of an HTTP API.
+    Url(&'a str),
+}
+
+/// Token usage and cost of a run, as far as the CLI reports them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Usage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_read_input_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_creation_input_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+}
+
+impl Usage {
+    /// Adds `other` to this usage; fields missing on both sides stay missing.
+    pub fn add(&mut self, other: &Usage) {
+        fn sum<T: std::ops::Add<Output = T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            }
+        }
+        self.input_tokens = sum(self.input_tokens, other.input_tokens);
+        self.output_tokens = sum(self.output_tokens, other.output_tokens);
+        self.cache_read_input_tokens =
+            sum(self.cache_read_input_tokens, other.cache_read_input_tokens);
+        self.cache_creation_input_tokens = sum(
+            self.cache_creation_input_tokens,
+            other.cache_creation_input_tokens,
+        );
+        self.cost_usd = sum(self.cost_usd, other.cost_usd);
+    }
+}
+
+/// A provider's output together with what the CLI reported about the run.
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub output: Value,
+    /// The concrete model that answered, if the CLI reports it.
+    pub model: Option<String>,
+    pub usage: Option<Usage>,
+}
+
+impl From<Value> for Completion {
+    fn from(output: Value) -> Self {
+        Self {
+            output,
+            model: None,
+            usage: None,
+        }
+    }
+}
 
 #[async_trait]
 pub trait Provider: Send + Sync {
     #[allow(dead_code)]
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
 
+    /// What requests run on.
+    fn backend(&self) -> Backend<'_>;
+
+    /// Provider-specific failure signatures, checked before the common ones.
+    fn signatures(&self) -> &'static [Signature] {
+        &[]
+    }
+
+    /// Checks that an HTTP API is reachable and accepts the configured key,
+    /// without running a prompt. CLIs are checked through their binary.
+    async fn ping(&self, _timeout_secs: u64) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    /// Runs the CLI on the conversation and returns its structured output.
+    /// Without a schema the CLI runs in free-text mode and the reply is
+    /// returned as a JSON string.
     async fn execute(
         &self,
-        prompt: &str,
-        schema: &Value,
+        messages: &[Message],
+        schema: Option<&Value>,
         model: Option<&str>,
         timeout_secs: Option<u64>,
-    ) -> Result<Value, AppError>;
+    ) -> Result<Completion, AppError>;
+
+    /// Same contract as [`Provider::execute`], but raw stdout lines are sent
+    /// to `chunks` while the CLI is still running.
+    async fn execute_streaming(
+        &self,
+        messages: &[Message],
+        schema: Option<&Value>,
+        model: Option<&str>,
+        timeout_secs: Option<u64>,
+        chunks: mpsc::UnboundedSender<String>,
+    ) -> Result<Completion, AppError>;
 }
 
+/// Names of the providers compiled into llm-mux.
+pub const BUILTIN_PROVIDERS: &[&str] = &["claude", "codex", "gemini"];
+
+/// Builds the provider called `name`. Providers configured with
+/// `kind = "cli"` take precedence over the built-in ones.
 pub fn get_provider_with_executor(
     name: &str,
+    settings: Option<&ProviderSettings>,
     executor: Arc<dyn Executor>,
 ) -> Option<Box<dyn Provider>> {
+    if let Some(spec) = settings.and_then(|s| s.cli.as_ref()) {
+        return Some(Box::new(CliProvider::new(name, spec.clone(), executor)));
+    }
+    if let Some(settings) = settings.filter(|s| s.kind.is_http()) {
+        let spec = settings.http.clone()?;
+        return Some(Box::new(HttpProvider::new(name, settings.kind, spec)));
+    }
+
     match name {
         "codex" => Some(Box::new(CodexProvider::new(executor))),
         "claude" => Some(Box::new(ClaudeProvider::new(executor))),
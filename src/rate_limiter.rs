@@ -1,20 +1,50 @@
 use dashmap::DashMap;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{debug, info};
 
 use crate::config::ModelSettings;
 
+/// How long [`RateLimiter::acquire`] may wait for a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wait {
+    /// Up to the model's `max_queue_wait_secs`, and never past the deadline.
+    Queue(Option<Instant>),
+    /// Until a slot frees up, ignoring `max_queue_wait_secs` and
+    /// `max_queue_depth`. For callers that cap their own number of waiters.
+    Unbounded,
+}
+
 #[derive(Clone)]
 pub struct RateLimiter {
     limiters: Arc<DashMap<(String, String), Arc<ModelLimiter>>>,
+    in_flight: Arc<InFlight>,
+}
+
+/// Guards handed out by `RateLimiter::acquire` that are still alive,
+/// registered or not.
+#[derive(Default)]
+struct InFlight {
+    count: AtomicU32,
+    idle: Notify,
 }
 
 struct ModelLimiter {
+    settings: ModelSettings,
     rps: Option<SlidingWindow>,
     rpm: Option<SlidingWindow>,
-    concurrent: Option<ConcurrentLimiter>,
+    /// Maximum number of requests in flight.
+    concurrent: Option<u32>,
+    slots: Arc<Slots>,
+    max_queue_wait: Duration,
+    max_queue_depth: Option<u32>,
+    /// Waiters take this lock in FIFO order; only the holder may claim a slot.
+    turnstile: tokio::sync::Mutex<()>,
+    queued: AtomicU32,
 }
 
 struct SlidingWindow {
@@ -23,25 +53,56 @@ struct SlidingWindow {
     timestamps: Mutex<VecDeque<Instant>>,
 }
 
-struct ConcurrentLimiter {
-    max: u32,
-    current: AtomicU32,
+/// Requests in flight for a model, counted whether or not it has a
+/// concurrency limit. Handed over to the new limiter when the model's limits
+/// change, so requests admitted before still count.
+#[derive(Default)]
+struct Slots {
+    taken: AtomicU32,
+    released: Notify,
 }
 
 pub struct ConcurrentGuard {
     limiter: Option<Arc<ModelLimiter>>,
+    in_flight: Option<Arc<InFlight>>,
 }
 
 impl Drop for ConcurrentGuard {
     fn drop(&mut self) {
         if let Some(ref limiter) = self.limiter {
-            if let Some(ref concurrent) = limiter.concurrent {
-                concurrent.current.fetch_sub(1, Ordering::SeqCst);
+            limiter.slots.taken.fetch_sub(1, Ordering::SeqCst);
+            limiter.slots.released.notify_one();
+        }
+        if let Some(ref in_flight) = self.in_flight {
+            if in_flight.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+                in_flight.idle.notify_waiters();
             }
         }
     }
 }
 
+/// Point-in-time usage of one registered limiter.
+pub struct LimiterSnapshot {
+    pub provider: String,
+    pub model: String,
+    /// `(in flight, limit)` when a concurrency limit is configured.
+    pub concurrent: Option<(u32, u32)>,
+    /// `(requests in the last second, limit)`.
+    pub rps: Option<(usize, u32)>,
+    /// `(requests in the last minute, limit)`.
+    pub rpm: Option<(usize, u32)>,
+    pub queued: u32,
+}
+
+/// Keeps `ModelLimiter::queued` accurate even when a waiter is cancelled.
+struct QueueTicket<'a>(&'a AtomicU32);
+
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 impl SlidingWindow {
     fn new(window: Duration, max_requests: u32) -> Self {
         Self {
@@ -51,82 +112,134 @@ impl SlidingWindow {
         }
     }
 
-    fn try_acquire(&self) -> bool {
-        let now = Instant::now();
-        let cutoff = now - self.window;
-
-        let mut timestamps = self.timestamps.lock().unwrap();
-
-        while timestamps.front().is_some_and(|&t| t < cutoff) {
+    /// Drops expired entries and returns `None` if a request fits right now,
+    /// otherwise the instant at which the oldest entry leaves the window.
+    fn next_free(
+        timestamps: &mut VecDeque<Instant>,
+        window: Duration,
+        max: u32,
+        now: Instant,
+    ) -> Option<Instant> {
+        while timestamps.front().is_some_and(|&t| t + window <= now) {
             timestamps.pop_front();
         }
 
-        if timestamps.len() < self.max_requests as usize {
-            timestamps.push_back(now);
-            true
+        if timestamps.len() < max as usize {
+            None
         } else {
-            false
+            timestamps.front().map(|&t| t + window)
         }
     }
 }
 
-impl ConcurrentLimiter {
-    fn new(max: u32) -> Self {
-        Self {
-            max,
-            current: AtomicU32::new(0),
-        }
+impl SlidingWindow {
+    fn occupancy(&self) -> (usize, u32) {
+        let mut timestamps = self.timestamps.lock().unwrap();
+        Self::next_free(
+            &mut timestamps,
+            self.window,
+            self.max_requests,
+            Instant::now(),
+        );
+        (timestamps.len(), self.max_requests)
     }
+}
 
-    fn try_acquire(&self) -> bool {
-        loop {
-            let current = self.current.load(Ordering::SeqCst);
-            if current >= self.max {
-                return false;
-            }
-            if self
-                .current
-                .compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst)
-                .is_ok()
-            {
-                return true;
-            }
-        }
-    }
+/// Why a slot could not be claimed right now.
+enum Blocked {
+    /// Wait for a running request to finish.
+    Concurrency,
+    /// Wait until the given instant for the rate windows to slide.
+    Rate(Instant),
 }
 
 impl ModelLimiter {
-    fn new(config: &ModelSettings) -> Self {
+    fn new(config: &ModelSettings, slots: Arc<Slots>) -> Self {
         Self {
+            settings: config.clone(),
             rps: config
                 .rps
                 .map(|limit| SlidingWindow::new(Duration::from_secs(1), limit)),
             rpm: config
                 .rpm
                 .map(|limit| SlidingWindow::new(Duration::from_secs(60), limit)),
-            concurrent: config.concurrent.map(ConcurrentLimiter::new),
+            concurrent: config.concurrent,
+            slots,
+            max_queue_wait: Duration::from_secs(config.max_queue_wait_secs.unwrap_or(0)),
+            max_queue_depth: config.max_queue_depth,
+            turnstile: tokio::sync::Mutex::new(()),
+            queued: AtomicU32::new(0),
         }
     }
 
-    fn try_acquire_rate(&self) -> bool {
-        if let Some(ref rps) = self.rps {
-            if !rps.try_acquire() {
-                return false;
+    /// Records a request in both windows, or in neither if either is full.
+    fn try_acquire_rate(&self) -> Result<(), Instant> {
+        let now = Instant::now();
+        let mut rps = self.rps.as_ref().map(|w| (w, w.timestamps.lock().unwrap()));
+        let mut rpm = self.rpm.as_ref().map(|w| (w, w.timestamps.lock().unwrap()));
+
+        let blocked_until = [&mut rps, &mut rpm]
+            .into_iter()
+            .flatten()
+            .filter_map(|(w, ts)| SlidingWindow::next_free(ts, w.window, w.max_requests, now))
+            .max();
+        if let Some(until) = blocked_until {
+            return Err(until);
+        }
+
+        for (_, ts) in [&mut rps, &mut rpm].into_iter().flatten() {
+            ts.push_back(now);
+        }
+        Ok(())
+    }
+
+    /// When a waiter given `wait` has to give up, `None` for never.
+    fn deadline(&self, wait: Wait) -> Option<Instant> {
+        match wait {
+            Wait::Queue(deadline) => {
+                let queue_deadline = Instant::now() + self.max_queue_wait;
+                Some(deadline.map_or(queue_deadline, |d| d.min(queue_deadline)))
             }
+            Wait::Unbounded => None,
         }
-        if let Some(ref rpm) = self.rpm {
-            if !rpm.try_acquire() {
+    }
+
+    fn try_acquire_concurrent(&self) -> bool {
+        let taken = &self.slots.taken;
+        let Some(max) = self.concurrent else {
+            taken.fetch_add(1, Ordering::SeqCst);
+            return true;
+        };
+        loop {
+            let current = taken.load(Ordering::SeqCst);
+            if current >= max {
                 return false;
             }
+            if taken
+                .compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return true;
+            }
         }
-        true
     }
 
-    fn try_acquire_concurrent(&self) -> bool {
-        match &self.concurrent {
-            Some(c) => c.try_acquire(),
-            None => true,
+    fn try_claim(self: &Arc<Self>) -> Result<ConcurrentGuard, Blocked> {
+        // 先檢查 concurrent（不消耗 quota），再檢查 rate
+        if !self.try_acquire_concurrent() {
+            return Err(Blocked::Concurrency);
+        }
+
+        if let Err(until) = self.try_acquire_rate() {
+            // rate 失敗，釋放 concurrent
+            self.slots.taken.fetch_sub(1, Ordering::SeqCst);
+            return Err(Blocked::Rate(until));
         }
+
+        Ok(ConcurrentGuard {
+            limiter: Some(Arc::clone(self)),
+            in_flight: None,
+        })
     }
 }
 
@@ -134,38 +247,239 @@ impl RateLimiter {
     pub fn new() -> Self {
         Self {
             limiters: Arc::new(DashMap::new()),
+            in_flight: Arc::default(),
         }
     }
 
     pub fn register(&self, provider: String, model: String, config: ModelSettings) {
+        self.limiters.insert(
+            (provider, model),
+            Arc::new(ModelLimiter::new(&config, Arc::default())),
+        );
+    }
+
+    /// Replaces the registered limits with `limits`. Entries whose settings
+    /// did not change keep their limiter, including its window history;
+    /// changed ones start new windows but keep counting the requests in
+    /// flight against the new concurrency limit. Entries missing from
+    /// `limits` are removed unless `keep` returns true for their provider.
+    /// Guards already handed out stay valid either way.
+    pub fn sync(
+        &self,
+        limits: HashMap<(String, String), ModelSettings>,
+        keep: impl Fn(&str) -> bool,
+    ) {
+        self.limiters.retain(|(provider, model), _| {
+            let retained =
+                keep(provider) || limits.contains_key(&(provider.clone(), model.clone()));
+            if !retained {
+                info!(provider = %provider, model = %model, "removing rate limits");
+            }
+            retained
+        });
+        for (key, settings) in limits {
+            let slots = match self.limiters.get(&key) {
+                Some(limiter) if limiter.settings == settings => continue,
+                Some(limiter) => Arc::clone(&limiter.slots),
+                None => Arc::default(),
+            };
+            info!(provider = %key.0, model = %key.1, "registering rate limits");
+            self.limiters
+                .insert(key, Arc::new(ModelLimiter::new(&settings, slots)));
+        }
+    }
+
+    fn limiter(&self, provider: &str, model: &str) -> Option<Arc<ModelLimiter>> {
+        let key = (provider.to_string(), model.to_string());
+        self.limiters.get(&key).map(|l| Arc::clone(&l))
+    }
+
+    pub fn snapshot(&self) -> Vec<LimiterSnapshot> {
         self.limiters
-            .insert((provider, model), Arc::new(ModelLimiter::new(&config)));
+            .iter()
+            .map(|entry| {
+                let ((provider, model), limiter) = entry.pair();
+                LimiterSnapshot {
+                    provider: provider.clone(),
+                    model: model.clone(),
+                    concurrent: limiter
+                        .concurrent
+                        .map(|max| (limiter.slots.taken.load(Ordering::SeqCst), max)),
+                    rps: limiter.rps.as_ref().map(SlidingWindow::occupancy),
+                    rpm: limiter.rpm.as_ref().map(SlidingWindow::occupancy),
+                    queued: limiter.queued.load(Ordering::SeqCst),
+                }
+            })
+            .collect()
     }
 
+    /// The share of the tightest limit of `provider/model` that is free right
+    /// now, from `0.0` (a request would have to wait) to `1.0` (idle, or no
+    /// limits registered).
+    pub fn headroom(&self, provider: &str, model: &str) -> f64 {
+        let Some(limiter) = self.limiter(provider, model) else {
+            return 1.0;
+        };
+        if limiter.queued.load(Ordering::SeqCst) > 0 {
+            return 0.0;
+        }
+        let free = |used: usize, max: u32| 1.0 - (used as f64 / f64::from(max.max(1))).min(1.0);
+        let concurrent = limiter
+            .concurrent
+            .map(|max| free(limiter.slots.taken.load(Ordering::SeqCst) as usize, max));
+        let windows = [&limiter.rps, &limiter.rpm].into_iter().flatten().map(|w| {
+            let (used, max) = w.occupancy();
+            free(used, max)
+        });
+        concurrent.into_iter().chain(windows).fold(1.0, f64::min)
+    }
+
+    /// Claims a slot without waiting.
     pub fn try_acquire(&self, provider: &str, model: &str) -> Result<ConcurrentGuard, ()> {
-        let key = (provider.to_string(), model.to_string());
+        match self.limiter(provider, model) {
+            Some(limiter) => limiter.try_claim().map_err(|_| ()),
+            None => Ok(ConcurrentGuard {
+                limiter: None,
+                in_flight: None,
+            }),
+        }
+    }
 
-        let limiter = match self.limiters.get(&key) {
-            Some(l) => Arc::clone(&l),
-            None => return Ok(ConcurrentGuard { limiter: None }),
+    /// Claims a slot, waiting in a FIFO queue while the rate windows or the
+    /// concurrency limit are full.
+    ///
+    /// With [`Wait::Queue`], gives up when the queue already holds
+    /// `max_queue_depth` waiters, or once the deadline or the model's
+    /// `max_queue_wait_secs` (whichever comes first) has passed. Without
+    /// `max_queue_wait_secs` this never waits.
+    ///
+    /// Every guard returned, including those for unregistered models, counts
+    /// towards `in_flight` until dropped.
+    pub async fn acquire(
+        &self,
+        provider: &str,
+        model: &str,
+        wait: Wait,
+    ) -> Result<ConcurrentGuard, ()> {
+        let mut guard = self.claim(provider, model, wait).await?;
+        self.in_flight.count.fetch_add(1, Ordering::SeqCst);
+        guard.in_flight = Some(Arc::clone(&self.in_flight));
+        Ok(guard)
+    }
+
+    /// Counts one more request of an admitted caller against the rps/rpm
+    /// windows, waiting for them to slide as long as `wait` allows. The
+    /// caller keeps its concurrency slot.
+    pub async fn charge(&self, provider: &str, model: &str, wait: Wait) -> Result<(), ()> {
+        let Some(limiter) = self.limiter(provider, model) else {
+            return Ok(());
         };
+        let deadline = limiter.deadline(wait);
+        loop {
+            let until = match limiter.try_acquire_rate() {
+                Ok(()) => return Ok(()),
+                Err(until) => until,
+            };
+            if deadline.is_some_and(|d| until > d) {
+                debug!(
+                    provider,
+                    model, "rate limit wait for repeated request expired"
+                );
+                return Err(());
+            }
+            tokio::time::sleep_until(until).await;
+        }
+    }
 
-        // 先檢查 concurrent（不消耗 quota），再檢查 rate
-        if !limiter.try_acquire_concurrent() {
-            return Err(());
+    /// A guard that claims no slot but counts towards `in_flight`, for
+    /// requests let through past their limits.
+    pub fn unlimited(&self) -> ConcurrentGuard {
+        self.in_flight.count.fetch_add(1, Ordering::SeqCst);
+        ConcurrentGuard {
+            limiter: None,
+            in_flight: Some(Arc::clone(&self.in_flight)),
         }
+    }
 
-        if !limiter.try_acquire_rate() {
-            // rate 失敗，釋放 concurrent
-            if let Some(ref c) = limiter.concurrent {
-                c.current.fetch_sub(1, Ordering::SeqCst);
+    /// Number of guards from `acquire` that are still held.
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight.count.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once no guard from `acquire` is held.
+    pub async fn idle(&self) {
+        loop {
+            let idle = self.in_flight.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.in_flight() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    async fn claim(&self, provider: &str, model: &str, wait: Wait) -> Result<ConcurrentGuard, ()> {
+        let limiter = match self.limiter(provider, model) {
+            Some(l) => l,
+            None => {
+                return Ok(ConcurrentGuard {
+                    limiter: None,
+                    in_flight: None,
+                })
+            }
+        };
+
+        // Nobody waiting: don't pay for the queue
+        if limiter.queued.load(Ordering::SeqCst) == 0 {
+            if let Ok(guard) = limiter.try_claim() {
+                return Ok(guard);
             }
+        }
+
+        let queued = limiter.queued.fetch_add(1, Ordering::SeqCst);
+        let _ticket = QueueTicket(&limiter.queued);
+        if matches!(wait, Wait::Queue(_))
+            && limiter.max_queue_depth.is_some_and(|depth| queued >= depth)
+        {
+            debug!(provider, model, queued, "rate limit queue full");
             return Err(());
         }
+        let deadline = limiter.deadline(wait);
 
-        Ok(ConcurrentGuard {
-            limiter: Some(limiter),
-        })
+        let _turn = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, limiter.turnstile.lock())
+                .await
+                .map_err(|_| debug!(provider, model, "rate limit queue wait expired"))?,
+            None => limiter.turnstile.lock().await,
+        };
+
+        loop {
+            let released = limiter.slots.released.notified();
+            let wake_at = match limiter.try_claim() {
+                Ok(guard) => return Ok(guard),
+                Err(Blocked::Concurrency) => deadline,
+                Err(Blocked::Rate(until)) => Some(deadline.map_or(until, |d| until.min(d))),
+            };
+
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                debug!(provider, model, "rate limit queue wait expired");
+                return Err(());
+            }
+
+            tokio::select! {
+                _ = released => {}
+                _ = sleep_until(wake_at) => {}
+            }
+        }
+    }
+}
+
+/// Sleeps until `at`, or forever without it.
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
     }
 }
 
@@ -190,6 +504,7 @@ mod tests {
                 rpm: None,
                 concurrent: Some(2),
                 timeout_secs: None,
+                ..Default::default()
             },
         );
 
@@ -220,6 +535,7 @@ mod tests {
                 rpm: None,
                 concurrent: None,
                 timeout_secs: None,
+                ..Default::default()
             },
         );
 
@@ -251,6 +567,7 @@ mod tests {
                 rpm: Some(10),
                 concurrent: Some(2),
                 timeout_secs: None,
+                ..Default::default()
             },
         );
 
@@ -270,4 +587,365 @@ mod tests {
         // rps = 5，已經用了 5 個，應該被拒絕
         assert!(limiter.try_acquire("test", "model").is_err());
     }
+
+    fn queued_settings(concurrent: u32, wait_secs: u64, depth: Option<u32>) -> ModelSettings {
+        ModelSettings {
+            concurrent: Some(concurrent),
+            max_queue_wait_secs: Some(wait_secs),
+            max_queue_depth: depth,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acquire_without_queue_config_rejects_immediately() {
+        let limiter = RateLimiter::new();
+        limiter.register(
+            "test".into(),
+            "model".into(),
+            ModelSettings {
+                concurrent: Some(1),
+                ..Default::default()
+            },
+        );
+
+        let _g1 = limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .unwrap();
+        assert!(limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_release() {
+        let limiter = RateLimiter::new();
+        limiter.register("test".into(), "model".into(), queued_settings(1, 5, None));
+
+        let g1 = limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .unwrap();
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .acquire("test", "model", Wait::Queue(None))
+                    .await
+                    .is_ok()
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(g1);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_wait_ignores_queue_limits() {
+        let limiter = RateLimiter::new();
+        limiter.register(
+            "test".into(),
+            "model".into(),
+            queued_settings(1, 0, Some(0)),
+        );
+
+        let g1 = limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .unwrap();
+        assert!(limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .is_err());
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .acquire("test", "model", Wait::Unbounded)
+                    .await
+                    .is_ok()
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        drop(g1);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_deadline_expires() {
+        let limiter = RateLimiter::new();
+        limiter.register("test".into(), "model".into(), queued_settings(1, 5, None));
+
+        let _g1 = limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(limiter
+            .acquire("test", "model", Wait::Queue(Some(deadline)))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_queue_full() {
+        let limiter = RateLimiter::new();
+        limiter.register(
+            "test".into(),
+            "model".into(),
+            queued_settings(1, 5, Some(1)),
+        );
+
+        let _g1 = limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .unwrap();
+        let _waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .acquire("test", "model", Wait::Queue(None))
+                    .await
+                    .is_ok()
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // 佇列已有一個等待者，第二個應該直接被拒絕
+        let start = Instant::now();
+        assert!(limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_acquire_is_fifo() {
+        let limiter = RateLimiter::new();
+        limiter.register("test".into(), "model".into(), queued_settings(1, 5, None));
+
+        let g1 = limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let mut waiters = Vec::new();
+        for i in 0..3 {
+            let limiter = limiter.clone();
+            let order = order.clone();
+            waiters.push(tokio::spawn(async move {
+                let _g = limiter
+                    .acquire("test", "model", Wait::Queue(None))
+                    .await
+                    .unwrap();
+                order.lock().unwrap().push(i);
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        drop(g1);
+        for w in waiters {
+            w.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_rate_window() {
+        tokio::time::pause();
+        let limiter = RateLimiter::new();
+        limiter.register(
+            "test".into(),
+            "model".into(),
+            ModelSettings {
+                rps: Some(1),
+                max_queue_wait_secs: Some(5),
+                ..Default::default()
+            },
+        );
+
+        drop(
+            limiter
+                .acquire("test", "model", Wait::Queue(None))
+                .await
+                .unwrap(),
+        );
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter
+                    .acquire("test", "model", Wait::Queue(None))
+                    .await
+                    .is_ok()
+            }
+        });
+
+        tokio::time::advance(Duration::from_millis(900)).await;
+        assert!(!waiter.is_finished());
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_charge_counts_against_rate_windows() {
+        tokio::time::pause();
+        let limiter = RateLimiter::new();
+        limiter.register(
+            "test".into(),
+            "model".into(),
+            ModelSettings {
+                rps: Some(1),
+                concurrent: Some(1),
+                ..Default::default()
+            },
+        );
+
+        let _guard = limiter
+            .acquire("test", "model", Wait::Queue(None))
+            .await
+            .unwrap();
+        // No queue wait configured
+        assert!(limiter
+            .charge("test", "model", Wait::Queue(None))
+            .await
+            .is_err());
+
+        let charge = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.charge("test", "model", Wait::Unbounded).await }
+        });
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(!charge.is_finished());
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(charge.await.unwrap().is_ok());
+        assert!(limiter
+            .charge("unregistered", "model", Wait::Queue(None))
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let limiter = RateLimiter::new();
+        limiter.register(
+            "test".into(),
+            "model".into(),
+            ModelSettings {
+                rps: Some(5),
+                concurrent: Some(2),
+                ..Default::default()
+            },
+        );
+
+        let _g = limiter.try_acquire("test", "model").unwrap();
+        let snapshot = limiter.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].concurrent, Some((1, 2)));
+        assert_eq!(snapshot[0].rps, Some((1, 5)));
+        assert_eq!(snapshot[0].rpm, None);
+    }
+
+    #[test]
+    fn test_sync_keeps_unchanged_limiters() {
+        let limiter = RateLimiter::new();
+        let settings = ModelSettings {
+            concurrent: Some(1),
+            ..Default::default()
+        };
+        limiter.register("key".into(), "ci".into(), settings.clone());
+        let limits = HashMap::from([
+            (("test".into(), "same".into()), settings.clone()),
+            (("test".into(), "changed".into()), settings.clone()),
+            (("test".into(), "removed".into()), settings.clone()),
+        ]);
+        limiter.sync(limits, |provider| provider == "key");
+        let _same = limiter.try_acquire("test", "same").unwrap();
+        let changed = limiter.try_acquire("test", "changed").unwrap();
+
+        let limits = HashMap::from([
+            (("test".into(), "same".into()), settings.clone()),
+            (
+                ("test".into(), "changed".into()),
+                ModelSettings {
+                    concurrent: Some(2),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        limiter.sync(limits, |provider| provider == "key");
+
+        // Held slots still count, whether or not the limits changed
+        assert!(limiter.try_acquire("test", "same").is_err());
+        let mut snapshot = limiter.snapshot();
+        snapshot.sort_by(|a, b| a.model.cmp(&b.model));
+        let models: Vec<_> = snapshot.iter().map(|s| s.model.as_str()).collect();
+        assert_eq!(models, ["changed", "ci", "same"]);
+        assert_eq!(snapshot[0].concurrent, Some((1, 2)));
+        assert_eq!(snapshot[2].concurrent, Some((1, 1)));
+
+        let _second = limiter.try_acquire("test", "changed").unwrap();
+        assert!(limiter.try_acquire("test", "changed").is_err());
+        drop(changed);
+        assert!(limiter.try_acquire("test", "changed").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_idle_waits_for_all_guards() {
+        let limiter = RateLimiter::new();
+        // Unregistered models are tracked too
+        let guard = limiter
+            .acquire("unregistered", "model", Wait::Queue(None))
+            .await
+            .unwrap();
+        assert_eq!(limiter.in_flight(), 1);
+
+        let idle = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.idle().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!idle.is_finished());
+
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(1), idle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_headroom() {
+        let limiter = RateLimiter::new();
+        limiter.register(
+            "test".into(),
+            "model".into(),
+            ModelSettings {
+                concurrent: Some(2),
+                rpm: Some(4),
+                ..Default::default()
+            },
+        );
+        assert_eq!(limiter.headroom("test", "model"), 1.0);
+        assert_eq!(limiter.headroom("test", "unregistered"), 1.0);
+
+        let first = limiter.try_acquire("test", "model").unwrap();
+        assert_eq!(limiter.headroom("test", "model"), 0.5);
+        let _second = limiter.try_acquire("test", "model").unwrap();
+        assert_eq!(limiter.headroom("test", "model"), 0.0);
+
+        // The released slot frees concurrency, but the requests still count
+        // towards rpm
+        drop(first);
+        assert_eq!(limiter.headroom("test", "model"), 0.5);
+    }
 }
@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+use crate::auth::{sha256_hex, KEY_LIMITER};
+use crate::config::Config;
+use crate::error::AppError;
+use crate::AppState;
+
+/// Loads the config at `path` and swaps in its providers, models, routes
+/// and rate limits. An invalid config is rejected and the current one kept.
+///
+/// Requests in flight keep counting against their model's concurrency
+/// limit, and models whose limits did not change keep their window history.
+/// Other sections only take effect on restart.
+pub fn reload(state: &AppState, path: &Path) -> Result<(), AppError> {
+    let settings = Config::load(path)?.settings();
+    // Concurrent reloads must not leave the limits of one config with the
+    // settings of another
+    let mut current = state.settings.write().unwrap();
+    state
+        .rate_limiter
+        .sync(settings.limits(), |provider| provider == KEY_LIMITER);
+    *current = Arc::new(settings);
+    Ok(())
+}
+
+fn fingerprint(path: &Path) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    Some(sha256_hex(&content))
+}
+
+/// Reloads on SIGHUP, and whenever the content of `path` changes when
+/// checked every `poll`. Content is compared rather than the modification
+/// time, so updates to a Kubernetes ConfigMap mounted as a directory (a
+/// symlink swap) are noticed too; `subPath` mounts are never updated.
+pub fn spawn_watcher(state: Arc<AppState>, path: PathBuf, poll: Option<Duration>) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    info!(path = %path.display(), poll_secs = ?poll.map(|p| p.as_secs()), "watching config for changes");
+
+    tokio::spawn(async move {
+        let mut loaded = fingerprint(&path);
+        let mut interval = poll.map(tokio::time::interval);
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("received SIGHUP, reloading config"),
+                _ = async { interval.as_mut().unwrap().tick().await }, if interval.is_some() => {
+                    let current = fingerprint(&path);
+                    if current.is_none() || current == loaded {
+                        continue;
+                    }
+                    info!(path = %path.display(), "config changed, reloading");
+                }
+            }
+
+            // Remember the attempt even if it fails, so a broken file is
+            // reported once rather than on every poll
+            loaded = fingerprint(&path);
+            match reload(&state, &path) {
+                Ok(()) => info!("config reloaded"),
+                Err(e) => error!(error = %e, "rejected new config, keeping the current one"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{mock_executor, test_state};
+
+    const CONFIG: &str = r#"
+[server]
+
+[[providers]]
+name = "claude"
+rps = 10
+concurrent = 2
+
+[[providers.models]]
+name = "sonnet"
+rps = 10
+rpm = 100
+concurrent = 2
+timeout_secs = 60
+
+[[providers.models]]
+name = "opus"
+concurrent = 1
+
+[[routes]]
+name = "fallback"
+targets = ["claude/opus"]
+"#;
+
+    #[actix_web::test]
+    async fn test_reload_swaps_settings_and_keeps_unchanged_limiters() {
+        let state = test_state(mock_executor());
+        let _guard = state.rate_limiter.try_acquire("claude", "sonnet").unwrap();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), CONFIG).unwrap();
+        reload(&state, file.path()).unwrap();
+
+        let settings = state.settings();
+        assert!(settings
+            .model_settings
+            .contains_key(&("claude".into(), "opus".into())));
+        assert!(!settings.provider_settings.contains_key("gemini"));
+        assert_eq!(settings.routes["fallback"].targets.len(), 1);
+
+        // claude/sonnet is unchanged, so the held slot still counts
+        let sonnet = state
+            .rate_limiter
+            .snapshot()
+            .into_iter()
+            .find(|s| s.provider == "claude" && s.model == "sonnet")
+            .unwrap();
+        assert_eq!(sonnet.concurrent, Some((1, 2)));
+        assert!(!state
+            .rate_limiter
+            .snapshot()
+            .iter()
+            .any(|s| s.provider == "gemini"));
+
+        std::fs::write(file.path(), "[[providers]]\nname = ").unwrap();
+        assert!(reload(&state, file.path()).is_err());
+        assert!(state.settings().routes.contains_key("fallback"));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::auth::ApiKey;
+use crate::config::{RoutingRule, Target, AUTO_MODEL};
+use crate::error::AppError;
+use crate::rate_limiter::RateLimiter;
+use crate::schema;
+use crate::GenerateRequest;
+
+/// What `[[routing.rules]]` look at in a request.
+pub struct Features<'a> {
+    /// Characters over all messages.
+    pub prompt_chars: usize,
+    /// `(properties, depth)` of the schema; `None` in free-text mode.
+    pub schema: Option<(usize, usize)>,
+    pub tags: &'a [String],
+}
+
+impl<'a> Features<'a> {
+    pub fn of(req: &'a GenerateRequest) -> Self {
+        Self {
+            prompt_chars: req
+                .conversation()
+                .iter()
+                .map(|m| m.content.chars().count())
+                .sum(),
+            schema: req.schema.as_ref().map(schema::shape),
+            tags: &req.tags,
+        }
+    }
+}
+
+/// The targets picked for a `provider: "auto"` request.
+pub struct Routed {
+    pub rule: String,
+    pub targets: Vec<Target>,
+}
+
+fn matches(rule: &RoutingRule, features: &Features) -> bool {
+    let (properties, depth) = features.schema.unwrap_or_default();
+    rule.tags.iter().all(|tag| features.tags.contains(tag))
+        && rule
+            .min_prompt_chars
+            .is_none_or(|min| features.prompt_chars >= min)
+        && rule
+            .max_prompt_chars
+            .is_none_or(|max| features.prompt_chars <= max)
+        && rule
+            .structured
+            .is_none_or(|structured| structured == features.schema.is_some())
+        && rule
+            .max_schema_properties
+            .is_none_or(|max| properties <= max)
+        && rule.max_schema_depth.is_none_or(|max| depth <= max)
+}
+
+/// Picks the targets of the first rule that matches `features`, leaving out
+/// those `caller` may not use. Fails with `403` if that leaves none.
+///
+/// Targets keep their configured order, except that those without enough
+/// rate limiter headroom to start right away are moved to the end, where
+/// they remain as fallbacks.
+pub fn route(
+    rules: &[RoutingRule],
+    limiter: &RateLimiter,
+    features: &Features,
+    caller: Option<&ApiKey>,
+) -> Result<Routed, AppError> {
+    for rule in rules.iter().filter(|rule| matches(rule, features)) {
+        let min_headroom = rule.min_headroom.unwrap_or(0.0);
+        let targets: Vec<Target> = rule.targets.iter().map(|t| Target::parse(t)).collect();
+        if let (Some(key), Some(first)) = (caller, targets.first()) {
+            if !targets.iter().any(|t| key.allows(t)) {
+                key.check(first)?;
+            }
+        }
+        let mut targets: Vec<(Target, bool)> = targets
+            .into_iter()
+            .filter(|t| caller.is_none_or(|key| key.allows(t)))
+            .map(|target| {
+                let headroom = limiter.headroom(
+                    &target.provider,
+                    target.model.as_deref().unwrap_or(AUTO_MODEL),
+                );
+                (target, headroom > 0.0 && headroom >= min_headroom)
+            })
+            .collect();
+        if rule.min_headroom.is_some() && !targets.iter().any(|(_, free)| *free) {
+            continue;
+        }
+        targets.sort_by_key(|(_, free)| !free);
+        return Ok(Routed {
+            rule: rule.name.clone(),
+            targets: targets.into_iter().map(|(target, _)| target).collect(),
+        });
+    }
+    Err(AppError::NoMatchingRule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ModelSettings};
+
+    fn rules() -> Vec<RoutingRule> {
+        let config: Config = toml::from_str(
+            r#"
+[server]
+
+[[routing.rules]]
+name = "fast"
+tags = ["fast"]
+max_prompt_chars = 100
+targets = ["claude/haiku", "gemini/gemini-2.5-flash"]
+
+[[routing.rules]]
+name = "simple"
+structured = true
+max_schema_properties = 2
+max_schema_depth = 1
+min_headroom = 0.5
+targets = ["gemini/gemini-2.5-flash"]
+
+[[routing.rules]]
+name = "default"
+targets = ["claude/sonnet"]
+"#,
+        )
+        .unwrap();
+        config.routing.rules
+    }
+
+    fn pick(
+        limiter: &RateLimiter,
+        chars: usize,
+        schema: Option<(usize, usize)>,
+        tags: &[&str],
+    ) -> (String, Vec<String>) {
+        let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+        let features = Features {
+            prompt_chars: chars,
+            schema,
+            tags: &tags,
+        };
+        let routed = route(&rules(), limiter, &features, None).unwrap();
+        let targets = routed.targets.iter().map(Target::to_string).collect();
+        (routed.rule, targets)
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let limiter = RateLimiter::new();
+        assert_eq!(pick(&limiter, 50, None, &["fast", "cheap"]).0, "fast");
+        // Too long for the fast rule
+        assert_eq!(pick(&limiter, 500, None, &["fast"]).0, "default");
+        assert_eq!(pick(&limiter, 500, Some((2, 1)), &[]).0, "simple");
+        assert_eq!(pick(&limiter, 500, Some((3, 1)), &[]).0, "default");
+        assert_eq!(pick(&limiter, 500, Some((2, 2)), &[]).0, "default");
+
+        let e = route(
+            &rules()[..1],
+            &limiter,
+            &Features {
+                prompt_chars: 1,
+                schema: None,
+                tags: &[],
+            },
+            None,
+        );
+        assert!(matches!(e, Err(AppError::NoMatchingRule)));
+    }
+
+    #[test]
+    fn test_headroom_orders_targets_and_skips_rules() {
+        let limiter = RateLimiter::new();
+        for (provider, model) in [("claude", "haiku"), ("gemini", "gemini-2.5-flash")] {
+            limiter.register(
+                provider.into(),
+                model.into(),
+                ModelSettings {
+                    concurrent: Some(2),
+                    ..Default::default()
+                },
+            );
+        }
+        let _a = limiter.try_acquire("claude", "haiku").unwrap();
+        let _b = limiter.try_acquire("claude", "haiku").unwrap();
+        // Saturated haiku stays as a fallback behind flash
+        assert_eq!(
+            pick(&limiter, 10, None, &["fast"]).1,
+            ["gemini/gemini-2.5-flash", "claude/haiku"]
+        );
+
+        // Flash at half capacity still meets min_headroom = 0.5
+        let _c = limiter.try_acquire("gemini", "gemini-2.5-flash").unwrap();
+        assert_eq!(pick(&limiter, 500, Some((1, 1)), &[]).0, "simple");
+        let _d = limiter.try_acquire("gemini", "gemini-2.5-flash").unwrap();
+        assert_eq!(pick(&limiter, 500, Some((1, 1)), &[]).0, "default");
+    }
+}
@@ -69,6 +69,53 @@ pub fn validate_output(schema: &Value, output: &Value) -> Result<(), AppError> {
     }
 }
 
+/// Size of a schema as `(properties, depth)`: the number of properties
+/// declared at any level, and how deeply objects and arrays nest. A flat
+/// object has depth 1.
+pub fn shape(schema: &Value) -> (usize, usize) {
+    let mut properties = 0;
+    let mut depth = 0;
+    let mut children = Vec::new();
+    if let Some(props) = schema.get("properties").and_then(Value::as_object) {
+        properties += props.len();
+        children.extend(props.values());
+    }
+    for key in ["items", "additionalProperties"] {
+        children.extend(schema.get(key).filter(|v| v.is_object()));
+    }
+    for key in ["anyOf", "oneOf", "allOf"] {
+        children.extend(
+            schema
+                .get(key)
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten(),
+        );
+    }
+    for child in children {
+        let (child_properties, child_depth) = shape(child);
+        properties += child_properties;
+        depth = depth.max(child_depth);
+    }
+
+    let nests = ["object", "array"]
+        .iter()
+        .any(|t| schema.get("type").and_then(Value::as_str) == Some(t));
+    (properties, depth + usize::from(nests))
+}
+
+/// Builds the follow-up prompt used to ask a provider to fix its output.
+pub fn repair_prompt(prompt: &str, invalid_output: &str, errors: &[String]) -> String {
+    let errors = errors
+        .iter()
+        .map(|e| format!("- {e}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "{prompt}\n\n---\nYour previous response did not satisfy the required JSON schema.\nPrevious response:\n{invalid_output}\n\nErrors:\n{errors}\n\nRespond again with output that satisfies the schema."
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -154,4 +201,35 @@ mod tests {
         let output = json!({});
         assert!(validate_output(&schema, &output).is_err());
     }
+
+    #[test]
+    fn test_shape() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": { "label": { "type": "string" } }
+                    }
+                }
+            }
+        });
+        assert_eq!(shape(&schema), (3, 3));
+        assert_eq!(shape(&json!({"type": "string"})), (0, 0));
+    }
+
+    #[test]
+    fn test_repair_prompt_includes_errors() {
+        let prompt = repair_prompt(
+            "classify this",
+            r#"{"name":123}"#,
+            &["123 is not of type \"string\" at /name".to_string()],
+        );
+        assert!(prompt.starts_with("classify this"));
+        assert!(prompt.contains(r#"{"name":123}"#));
+        assert!(prompt.contains("- 123 is not of type"));
+    }
 }
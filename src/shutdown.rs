@@ -0,0 +1,163 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+
+use crate::provider::ChildProcesses;
+use crate::AppState;
+
+/// Resolves on the first SIGTERM or SIGINT.
+pub async fn signalled() {
+    let mut term = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    let mut int = signal(SignalKind::interrupt()).expect("failed to install SIGINT handler");
+    tokio::select! {
+        _ = term.recv() => info!("received SIGTERM"),
+        _ = int.recv() => info!("received SIGINT"),
+    }
+}
+
+/// Stops admitting new executions, which also fails readiness, then waits
+/// up to `drain_timeout` for the running ones. CLI processes still running
+/// after that get SIGTERM, and SIGKILL after `kill_grace`.
+pub async fn drain(
+    state: &AppState,
+    children: &ChildProcesses,
+    drain_timeout: Duration,
+    kill_grace: Duration,
+) {
+    state.draining.store(true, Ordering::SeqCst);
+    info!(
+        in_flight = state.rate_limiter.in_flight(),
+        drain_timeout_secs = drain_timeout.as_secs(),
+        "draining in-flight requests"
+    );
+
+    if tokio::time::timeout(drain_timeout, state.rate_limiter.idle())
+        .await
+        .is_ok()
+    {
+        info!("all in-flight requests finished");
+        return;
+    }
+
+    warn!(
+        in_flight = state.rate_limiter.in_flight(),
+        processes = children.len(),
+        "drain period over, terminating CLI processes"
+    );
+    children.terminate_all(kill_grace).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::ResponseCache;
+    use crate::config::ModelSettings;
+    use crate::rate_limiter::Wait;
+    use crate::tests::{mock_executor, test_state, test_state_with, valid_schema};
+    use actix_web::{test, web, App};
+    use serde_json::json;
+
+    #[actix_web::test]
+    async fn test_drain_waits_for_in_flight_and_rejects_new_requests() {
+        let state = test_state(mock_executor());
+        let guard = state
+            .rate_limiter
+            .acquire("claude", "sonnet", Wait::Queue(None))
+            .await
+            .unwrap();
+
+        let drain = tokio::spawn({
+            let state = state.clone();
+            async move {
+                drain(
+                    &state,
+                    &ChildProcesses::default(),
+                    Duration::from_secs(5),
+                    Duration::from_secs(1),
+                )
+                .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!drain.is_finished());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .route("/ready", web::get().to(crate::health::ready))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 503);
+        let req = test::TestRequest::post()
+            .uri("/generate")
+            .set_json(json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 503);
+
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(1), drain)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_requests_waiting_or_cached_are_rejected_once_draining() {
+        let state = test_state_with(mock_executor(), |state| {
+            state.rate_limiter.register(
+                "claude".into(),
+                "sonnet".into(),
+                ModelSettings {
+                    concurrent: Some(1),
+                    max_queue_wait_secs: Some(5),
+                    ..Default::default()
+                },
+            );
+            let cache = toml::from_str(r#"backend = "memory""#).unwrap();
+            state.cache = Some(ResponseCache::new(&cache).unwrap());
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .route("/generate", web::post().to(crate::generate)),
+        )
+        .await;
+        let request = |model: Option<&str>| {
+            test::TestRequest::post()
+                .uri("/generate")
+                .set_json(json!({
+                    "provider": "claude",
+                    "model": model,
+                    "prompt": "hello",
+                    "schema": valid_schema()
+                }))
+                .to_request()
+        };
+        // Cache an answer for claude with auto model selection
+        assert_eq!(test::call_service(&app, request(None)).await.status(), 200);
+
+        let guard = state
+            .rate_limiter
+            .acquire("claude", "sonnet", Wait::Queue(None))
+            .await
+            .unwrap();
+        let (queued, ()) = tokio::join!(test::call_service(&app, request(Some("sonnet"))), async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            state.draining.store(true, Ordering::SeqCst);
+            drop(guard);
+        });
+        assert_eq!(queued.status(), 503);
+
+        let cached = test::call_service(&app, request(None)).await;
+        assert_eq!(cached.status(), 503);
+    }
+}
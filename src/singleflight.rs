@@ -0,0 +1,197 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use tokio::sync::{watch, OnceCell};
+
+/// Collapses concurrent calls with the same key into one.
+///
+/// The first caller runs its future; callers arriving while it is in flight
+/// wait for and share its result. If the running caller is cancelled, one of
+/// the waiters runs its own future instead.
+pub struct Singleflight<T> {
+    calls: DashMap<String, Arc<Call<T>>>,
+}
+
+struct Call<T> {
+    result: OnceCell<T>,
+    started: Arc<watch::Sender<bool>>,
+}
+
+impl<T> Default for Call<T> {
+    fn default() -> Self {
+        Self {
+            result: OnceCell::new(),
+            started: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+/// Handed to the running future, to tell every caller sharing it that the
+/// call got past its preliminaries.
+pub struct Started(Arc<watch::Sender<bool>>);
+
+impl Started {
+    pub fn signal(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+impl<T> Default for Singleflight<T> {
+    fn default() -> Self {
+        Self {
+            calls: DashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone> Singleflight<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `f`, or waits for the call already in flight for `key`. Every
+    /// caller's `on_started` is called once the running future signals
+    /// [`Started`], or not at all if it never does.
+    pub async fn run<F, Fut>(&self, key: &str, f: F, on_started: impl FnOnce()) -> T
+    where
+        F: FnOnce(Started) -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let call = self.calls.entry(key.to_string()).or_default().clone();
+        let mut started = call.started.subscribe();
+        let mut on_started = Some(on_started);
+        let run = call
+            .result
+            .get_or_init(|| f(Started(Arc::clone(&call.started))));
+        tokio::pin!(run);
+        let result = loop {
+            tokio::select! {
+                result = &mut run => break result.clone(),
+                Ok(_) = started.wait_for(|started| *started), if on_started.is_some() => {
+                    if let Some(on_started) = on_started.take() {
+                        on_started();
+                    }
+                }
+            }
+        };
+        // Later callers start a fresh call instead of reusing this result
+        self.calls.remove_if(key, |_, c| Arc::ptr_eq(c, &call));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_calls_share_one_execution() {
+        let flight = Arc::new(Singleflight::new());
+        let runs = Arc::new(AtomicU32::new(0));
+
+        let calls: Vec<_> = (0..5)
+            .map(|_| {
+                let flight = flight.clone();
+                let runs = runs.clone();
+                tokio::spawn(async move {
+                    flight
+                        .run(
+                            "key",
+                            |_| async {
+                                runs.fetch_add(1, Ordering::SeqCst);
+                                tokio::time::sleep(Duration::from_millis(50)).await;
+                                42
+                            },
+                            || {},
+                        )
+                        .await
+                })
+            })
+            .collect();
+
+        for call in calls {
+            assert_eq!(call.await.unwrap(), 42);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // Finished calls are not reused
+        assert_eq!(flight.run("key", |_| async { 7 }, || {}).await, 7);
+    }
+
+    #[tokio::test]
+    async fn test_waiter_takes_over_cancelled_call() {
+        let flight = Arc::new(Singleflight::new());
+
+        let leader = tokio::spawn({
+            let flight = flight.clone();
+            async move {
+                flight
+                    .run("key", |_| std::future::pending::<u32>(), || {})
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let follower = tokio::spawn({
+            let flight = flight.clone();
+            async move { flight.run("key", |_| async { 1 }, || {}).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        leader.abort();
+
+        assert_eq!(follower.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_waiters_see_the_call_start() {
+        let flight = Arc::new(Singleflight::new());
+        let (go, wait) = tokio::sync::oneshot::channel::<()>();
+        let started = Arc::new(AtomicU32::new(0));
+
+        let leader = tokio::spawn({
+            let flight = flight.clone();
+            let started = started.clone();
+            async move {
+                flight
+                    .run(
+                        "key",
+                        |s| async move {
+                            s.signal();
+                            wait.await.unwrap();
+                            1
+                        },
+                        || {
+                            started.fetch_add(1, Ordering::SeqCst);
+                        },
+                    )
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let follower = tokio::spawn({
+            let flight = flight.clone();
+            let started = started.clone();
+            async move {
+                flight
+                    .run(
+                        "key",
+                        |_| async { 2 },
+                        || {
+                            started.fetch_add(1, Ordering::SeqCst);
+                        },
+                    )
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // Both know the shared call is running before it finishes
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+
+        go.send(()).unwrap();
+        assert_eq!(leader.await.unwrap(), 1);
+        assert_eq!(follower.await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+
+use actix_web::http::header;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{debug, info};
+
+use crate::auth::ApiKey;
+use crate::dispatch::admit_any;
+use crate::error::AppError;
+use crate::{AppState, GenerateRequest, Output};
+
+fn sse_event(name: &str, data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).unwrap_or_else(|_| "null".to_string());
+    Bytes::from(format!("event: {name}\ndata: {data}\n\n"))
+}
+
+/// `POST /generate/stream`: same request body as `/generate`, answered as
+/// Server-Sent Events.
+///
+/// Events, in order:
+/// - `progress` — `{"stage": "running"}` once the request is admitted
+/// - `chunk` — `{"data": "..."}` for every stdout line of the CLI
+/// - `output` — `{"output": ...}` after schema validation (`{"text": ...}`
+///   without a schema), or
+/// - `error` — the usual `ErrorResponse` body plus its `status`
+///
+/// Requests rejected before execution (bad schema, rate limit, ...) get a
+/// plain HTTP error instead of a stream.
+pub async fn generate_stream(
+    state: web::Data<Arc<AppState>>,
+    caller: Option<web::ReqData<Arc<ApiKey>>>,
+    req: web::Json<GenerateRequest>,
+) -> Result<HttpResponse, AppError> {
+    req.validate()?;
+
+    // Routes only fall back on rate limits here: once output has been
+    // streamed to the client, switching targets is no longer possible.
+    let caller = caller.as_deref().map(Arc::as_ref);
+    let targets = req.plan(&state, caller)?.targets;
+    let admission = admit_any(&state, caller, &targets).await?;
+    let req = req.into_inner();
+    let target = admission.target.clone();
+    let metrics = state.metrics.clone();
+
+    info!(
+        provider = %target.provider,
+        model = ?target.model,
+        timeout_secs = ?admission.timeout_secs,
+        "executing streaming request"
+    );
+
+    let (tx, rx) = mpsc::unbounded_channel::<Bytes>();
+
+    tokio::spawn(async move {
+        let _ = tx.send(sse_event("progress", &json!({"stage": "running"})));
+
+        let started = Instant::now();
+        let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel::<String>();
+        let messages = req.conversation();
+        let execution = admission.provider.execute_streaming(
+            &messages,
+            req.schema.as_ref(),
+            target.model.as_deref(),
+            admission.timeout_secs,
+            chunk_tx,
+        );
+        tokio::pin!(execution);
+
+        let result = loop {
+            tokio::select! {
+                Some(line) = chunk_rx.recv() => {
+                    let _ = tx.send(sse_event("chunk", &json!({"data": line})));
+                }
+                result = &mut execution => break result,
+                // Dropping the execution future kills the child process.
+                _ = tx.closed() => {
+                    debug!(provider = %target.provider, "stream client disconnected");
+                    return;
+                }
+            }
+        };
+
+        while let Ok(line) = chunk_rx.try_recv() {
+            let _ = tx.send(sse_event("chunk", &json!({"data": line})));
+        }
+
+        metrics.observe_execution(&target, started.elapsed());
+        let result =
+            result.and_then(|completion| Output::validate(req.schema.as_ref(), completion.output));
+        if let Err(AppError::OutputValidation { .. }) = &result {
+            metrics.record_validation_failure(&target);
+        }
+        metrics.record_request(&target, crate::metrics::outcome(&result));
+
+        let event = match result {
+            Ok(output) => sse_event("output", &output),
+            Err(e) => {
+                let (status, body) = e.payload();
+                let mut body = serde_json::to_value(body).unwrap_or_default();
+                body["status"] = json!(status.as_u16());
+                sse_event("error", &body)
+            }
+        };
+        let _ = tx.send(event);
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(UnboundedReceiverStream::new(rx).map(Ok::<_, Infallible>)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::executor::{CommandOutput, MockExecutor};
+    use crate::tests::{test_state, valid_schema};
+    use actix_web::{test, App};
+
+    fn streaming_executor(lines: &'static [&'static str]) -> Arc<dyn crate::provider::Executor> {
+        let mut mock = MockExecutor::new();
+        mock.expect_run_streaming()
+            .returning(move |_, _, _, _, tx| {
+                for line in lines {
+                    tx.send(line.to_string()).unwrap();
+                }
+                Ok(CommandOutput {
+                    stdout: lines.join("\n"),
+                    stderr: String::new(),
+                })
+            });
+        Arc::new(mock)
+    }
+
+    async fn post_stream(
+        executor: Arc<dyn crate::provider::Executor>,
+        body: serde_json::Value,
+    ) -> (u16, String) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state(executor)))
+                .route("/generate/stream", web::post().to(generate_stream)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/generate/stream")
+            .set_json(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let status = resp.status().as_u16();
+        let body = test::read_body(resp).await;
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn test_stream_emits_chunks_and_output() {
+        let (status, body) = post_stream(
+            streaming_executor(&[
+                r#"{"type":"system"}"#,
+                r#"{"type":"result","structured_output":{"message":"hi"}}"#,
+            ]),
+            json!({
+                "provider": "claude",
+                "model": "sonnet",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+
+        assert_eq!(status, 200);
+        assert!(body.starts_with("event: progress\n"));
+        assert_eq!(body.matches("event: chunk\n").count(), 2);
+        assert!(body.contains("event: output\ndata: {\"output\":{\"message\":\"hi\"}}"));
+    }
+
+    #[actix_web::test]
+    async fn test_stream_reports_validation_error_as_event() {
+        let (status, body) = post_stream(
+            streaming_executor(&[r#"{"type":"result","structured_output":{"message":1}}"#]),
+            json!({
+                "provider": "claude",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+
+        assert_eq!(status, 200);
+        assert!(body.contains("event: error\n"));
+        assert!(body.contains("\"status\":422"));
+    }
+
+    #[actix_web::test]
+    async fn test_stream_rejects_before_streaming() {
+        let (status, _) = post_stream(
+            streaming_executor(&[]),
+            json!({
+                "provider": "unknown",
+                "prompt": "hello",
+                "schema": valid_schema()
+            }),
+        )
+        .await;
+
+        assert_eq!(status, 400);
+    }
+}